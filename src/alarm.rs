@@ -0,0 +1,667 @@
+//! Threshold alarms for low SpO2, abnormal pulse rate, probe-off and signal loss.
+//!
+//! The [`AlarmEngine`] consumes readings and emits an [`AlarmEvent`] on each alarm
+//! state change. Conditions must persist for [`AlarmOptions::alarm_delay`] before an
+//! alarm is raised, with threshold conditions confirmed by valid readings throughout,
+//! and clear only once values recover past the hysteresis band.
+//! Latched alarms remain latched after the condition clears until acknowledged.
+//!
+//! Time is taken from reading timestamps, so recorded sessions can be evaluated
+//! faster than real time.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use serde::{Serialize, Deserialize};
+use structopt::StructOpt;
+use tokio::sync::mpsc;
+
+use crate::Reading;
+use crate::reading::unix_time;
+
+
+/// Alarm condition
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// SpO2 below [`AlarmOptions::spo2_low`]
+    LowSpo2,
+    /// Pulse rate above [`AlarmOptions::pulse_high`]
+    HighPulse,
+    /// Pulse rate below [`AlarmOptions::pulse_low`]
+    LowPulse,
+    /// Sensor reports the probe is off the finger
+    ProbeOff,
+    /// No valid, good quality readings for [`AlarmOptions::signal_lost_after`]
+    SignalLost,
+}
+
+impl Condition {
+    /// All conditions
+    pub const ALL: &'static [Condition] = &[Condition::LowSpo2, Condition::HighPulse, Condition::LowPulse, Condition::ProbeOff, Condition::SignalLost];
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::LowSpo2 => write!(f, "low SpO2"),
+            Condition::HighPulse => write!(f, "high pulse rate"),
+            Condition::LowPulse => write!(f, "low pulse rate"),
+            Condition::ProbeOff => write!(f, "probe off"),
+            Condition::SignalLost => write!(f, "signal lost"),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low_spo2" => Ok(Condition::LowSpo2),
+            "high_pulse" => Ok(Condition::HighPulse),
+            "low_pulse" => Ok(Condition::LowPulse),
+            "probe_off" => Ok(Condition::ProbeOff),
+            "signal_lost" => Ok(Condition::SignalLost),
+            _ => Err(format!("Unrecognised condition '{}' (expected low_spo2, high_pulse, low_pulse, probe_off or signal_lost)", s)),
+        }
+    }
+}
+
+/// Alarm priority
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    Medium,
+    High,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::Low => write!(f, "low"),
+            Priority::Medium => write!(f, "medium"),
+            Priority::High => write!(f, "high"),
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "medium" => Ok(Priority::Medium),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("Unrecognised priority '{}' (expected low, medium or high)", s)),
+        }
+    }
+}
+
+/// Condition priorities, parsed from a comma separated list of `condition=priority` pairs
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Priorities(pub BTreeMap<Condition, Priority>);
+
+impl Priorities {
+    /// Fetch the priority for a condition, conditions not listed are medium priority
+    pub fn get(&self, c: Condition) -> Priority {
+        self.0.get(&c).cloned().unwrap_or(Priority::Medium)
+    }
+}
+
+impl Default for Priorities {
+    fn default() -> Self {
+        Priorities([
+            (Condition::LowSpo2, Priority::High),
+            (Condition::HighPulse, Priority::Medium),
+            (Condition::LowPulse, Priority::Medium),
+            (Condition::ProbeOff, Priority::Low),
+            (Condition::SignalLost, Priority::Medium),
+        ].into_iter().collect())
+    }
+}
+
+impl FromStr for Priorities {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut p = Priorities::default();
+
+        for pair in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (c, v) = pair.split_once('=')
+                .ok_or_else(|| format!("Invalid priority '{}' (expected condition=priority)", pair))?;
+            p.0.insert(c.trim().parse()?, v.trim().parse()?);
+        }
+
+        Ok(p)
+    }
+}
+
+/// Alarm options
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct AlarmOptions {
+    /// Enable alarms
+    #[structopt(long)]
+    pub alarms: bool,
+
+    /// Raise an alarm when SpO2 falls below this level (%)
+    #[structopt(long, default_value="90")]
+    pub spo2_low: f32,
+
+    /// SpO2 must recover this far above the alarm level to clear (%)
+    #[structopt(long, default_value="2")]
+    pub spo2_hysteresis: f32,
+
+    /// Raise an alarm when the pulse rate rises above this level (bpm)
+    #[structopt(long, default_value="120")]
+    pub pulse_high: f32,
+
+    /// Raise an alarm when the pulse rate falls below this level (bpm)
+    #[structopt(long, default_value="50")]
+    pub pulse_low: f32,
+
+    /// Pulse rate must recover this far inside the alarm levels to clear (bpm)
+    #[structopt(long, default_value="5")]
+    pub pulse_hysteresis: f32,
+
+    /// Period a condition must persist before an alarm is raised
+    #[structopt(long, default_value="10s")]
+    pub alarm_delay: humantime::Duration,
+
+    /// Raise a signal lost alarm if no valid, good quality readings are received for this period
+    #[structopt(long, default_value="15s")]
+    pub signal_lost_after: humantime::Duration,
+
+    /// Keep alarms active after the condition clears, until acknowledged
+    #[structopt(long)]
+    pub latching: bool,
+
+    /// Alarm priorities (comma separated condition=priority, eg. low_spo2=high,probe_off=low)
+    #[structopt(long, default_value="low_spo2=high,high_pulse=medium,low_pulse=medium,probe_off=low,signal_lost=medium")]
+    pub alarm_priorities: Priorities,
+}
+
+impl Default for AlarmOptions {
+    fn default() -> Self {
+        Self{
+            alarms: false,
+            spo2_low: 90.0,
+            spo2_hysteresis: 2.0,
+            pulse_high: 120.0,
+            pulse_low: 50.0,
+            pulse_hysteresis: 5.0,
+            alarm_delay: Duration::from_secs(10).into(),
+            signal_lost_after: Duration::from_secs(15).into(),
+            latching: false,
+            alarm_priorities: Priorities::default(),
+        }
+    }
+}
+
+/// Alarm state
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmState {
+    /// Condition present and unacknowledged
+    Active,
+    /// Condition present, acknowledged
+    Acknowledged,
+    /// Condition cleared, awaiting acknowledgement of a latched alarm
+    Latched,
+    /// Alarm cleared
+    Cleared,
+}
+
+impl fmt::Display for AlarmState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlarmState::Active => write!(f, "active"),
+            AlarmState::Acknowledged => write!(f, "acknowledged"),
+            AlarmState::Latched => write!(f, "latched"),
+            AlarmState::Cleared => write!(f, "cleared"),
+        }
+    }
+}
+
+/// Alarm state change
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AlarmEvent {
+    #[serde(with = "unix_time")]
+    pub timestamp: SystemTime,
+    pub condition: Condition,
+    pub priority: Priority,
+    /// New alarm state
+    pub state: AlarmState,
+    /// Triggering SpO2 or pulse rate value, where applicable
+    #[serde(default)]
+    pub value: Option<f32>,
+}
+
+impl fmt::Display for AlarmEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} priority {} alarm {}", self.priority, self.condition, self.state)?;
+        if let Some(v) = self.value {
+            write!(f, " ({:.0})", v)?;
+        }
+        Ok(())
+    }
+}
+
+/// Per-condition alarm tracking
+#[derive(Debug, Default, Clone)]
+struct Alarm {
+    /// Time the condition was first present, if currently present
+    since: Option<SystemTime>,
+    /// Current alarm state, `None` where no alarm is raised
+    state: Option<AlarmState>,
+    /// Latest value while the condition is present
+    value: Option<f32>,
+}
+
+/// Evaluates readings against alarm thresholds
+#[derive(Debug, Clone)]
+pub struct AlarmEngine {
+    opts: AlarmOptions,
+    alarms: BTreeMap<Condition, Alarm>,
+    last_valid: Option<SystemTime>,
+    probe_off: bool,
+}
+
+impl AlarmEngine {
+    /// Create a new alarm engine
+    pub fn new(opts: AlarmOptions) -> Self {
+        let alarms = Condition::ALL.iter().map(|c| (*c, Alarm::default())).collect();
+        Self{ opts, alarms, last_valid: None, probe_off: false }
+    }
+
+    /// Fetch the current state of each raised alarm
+    pub fn active(&self) -> Vec<(Condition, AlarmState)> {
+        self.alarms.iter()
+            .filter_map(|(c, a)| a.state.map(|s| (*c, s)))
+            .collect()
+    }
+
+    /// Evaluate a reading, returning any alarm state changes
+    pub fn update(&mut self, r: &Reading) -> Vec<AlarmEvent> {
+        let t = r.timestamp;
+        let mut events = vec![];
+
+        self.probe_off = r.status.probe_off;
+        if r.is_valid() && !r.status.low_quality {
+            self.last_valid = Some(t);
+        }
+
+        // Threshold conditions use hysteresis. Readings without a value, taken with the
+        // probe off or flagged by signal quality assessment cannot confirm a condition,
+        // so pending onsets restart rather than raising from earlier readings
+        let usable = !r.status.probe_off && !r.status.low_quality;
+
+        match r.spo2.filter(|_| usable) {
+            Some(v) => {
+                let present = self.present(Condition::LowSpo2, v < self.opts.spo2_low, v >= self.opts.spo2_low + self.opts.spo2_hysteresis);
+                self.evaluate(Condition::LowSpo2, present, t, Some(v), &mut events);
+            },
+            None => self.suspend(Condition::LowSpo2),
+        }
+
+        match r.pulse_rate.filter(|_| usable) {
+            Some(v) => {
+                let present = self.present(Condition::HighPulse, v > self.opts.pulse_high, v <= self.opts.pulse_high - self.opts.pulse_hysteresis);
+                self.evaluate(Condition::HighPulse, present, t, Some(v), &mut events);
+
+                let present = self.present(Condition::LowPulse, v < self.opts.pulse_low, v >= self.opts.pulse_low + self.opts.pulse_hysteresis);
+                self.evaluate(Condition::LowPulse, present, t, Some(v), &mut events);
+            },
+            None => {
+                self.suspend(Condition::HighPulse);
+                self.suspend(Condition::LowPulse);
+            },
+        }
+
+        self.evaluate(Condition::ProbeOff, r.status.probe_off, t, None, &mut events);
+        events.extend(self.tick(t));
+
+        events
+    }
+
+    /// Evaluate time based conditions, call periodically when readings may stop
+    pub fn tick(&mut self, now: SystemTime) -> Vec<AlarmEvent> {
+        let mut events = vec![];
+
+        // Signal loss is measured from the last valid reading (or the first tick),
+        // while the probe is off the probe-off alarm applies instead
+        let last = *self.last_valid.get_or_insert(now);
+        let lost = !self.probe_off && now.duration_since(last).map(|d| d >= *self.opts.signal_lost_after).unwrap_or(false);
+
+        let since = lost.then(|| last + *self.opts.signal_lost_after);
+        self.evaluate_since(Condition::SignalLost, since, now, None, &mut events);
+
+        // Raise a probe-off alarm whose onset delay has elapsed without further readings,
+        // threshold conditions are only raised by readings confirming them
+        if let Some(since) = self.alarms[&Condition::ProbeOff].since {
+            self.evaluate_since(Condition::ProbeOff, Some(since), now, None, &mut events);
+        }
+
+        events
+    }
+
+    /// Acknowledge an alarm (or all alarms where no condition is provided)
+    pub fn acknowledge(&mut self, condition: Option<Condition>, now: SystemTime) -> Vec<AlarmEvent> {
+        let mut events = vec![];
+
+        for (c, a) in self.alarms.iter_mut().filter(|(c, _)| condition.map(|v| v == **c).unwrap_or(true)) {
+            let state = match a.state {
+                Some(AlarmState::Active) => AlarmState::Acknowledged,
+                Some(AlarmState::Latched) => AlarmState::Cleared,
+                _ => continue,
+            };
+
+            a.state = Some(state).filter(|s| *s != AlarmState::Cleared);
+            events.push(AlarmEvent{ timestamp: now, condition: *c, priority: self.opts.alarm_priorities.get(*c), state, value: None });
+        }
+
+        events
+    }
+
+    /// Restart the onset delay of a condition without a raised alarm, raised alarms are
+    /// left unchanged until valid readings clear them
+    fn suspend(&mut self, c: Condition) {
+        let a = self.alarms.get_mut(&c).unwrap();
+        if a.state.is_none() {
+            a.since = None;
+            a.value = None;
+        }
+    }
+
+    /// Apply hysteresis, conditions start when `start` is met and end only when `end` is met
+    fn present(&self, c: Condition, start: bool, end: bool) -> bool {
+        match self.alarms[&c].since.is_some() {
+            true => !end,
+            false => start,
+        }
+    }
+
+    fn evaluate(&mut self, c: Condition, present: bool, t: SystemTime, value: Option<f32>, events: &mut Vec<AlarmEvent>) {
+        let since = match present {
+            true => Some(self.alarms[&c].since.unwrap_or(t)),
+            false => None,
+        };
+        self.evaluate_since(c, since, t, value, events);
+    }
+
+    fn evaluate_since(&mut self, c: Condition, since: Option<SystemTime>, t: SystemTime, value: Option<f32>, events: &mut Vec<AlarmEvent>) {
+        // Signal loss has its own timeout in place of the onset delay
+        let delay = match c {
+            Condition::SignalLost => Duration::from_secs(0),
+            _ => *self.opts.alarm_delay,
+        };
+        let latching = self.opts.latching;
+
+        let a = self.alarms.get_mut(&c).unwrap();
+        a.since = since;
+        a.value = value.or(a.value).filter(|_| since.is_some());
+        let value = a.value;
+
+        let state = match (since, a.state) {
+            // Raise once the condition has persisted for the onset delay
+            (Some(s), None) if t.duration_since(s).map(|d| d >= delay).unwrap_or(false) => AlarmState::Active,
+            // Condition returned while latched
+            (Some(_), Some(AlarmState::Latched)) => AlarmState::Active,
+            (None, Some(AlarmState::Active)) if latching => AlarmState::Latched,
+            (None, Some(AlarmState::Active)) | (None, Some(AlarmState::Acknowledged)) => AlarmState::Cleared,
+            _ => return,
+        };
+
+        a.state = Some(state).filter(|s| *s != AlarmState::Cleared);
+        events.push(AlarmEvent{ timestamp: t, condition: c, priority: self.opts.alarm_priorities.get(c), state, value });
+    }
+
+    /// Evaluate a stream of readings, returning a handle for acknowledging alarms and
+    /// a stream of alarm state changes.
+    ///
+    /// Time based conditions are evaluated each second, advancing from the last
+    /// reading timestamp by the wall-clock time elapsed since it was received.
+    pub fn events<S>(self, readings: S) -> (AlarmHandle, BoxStream<'static, AlarmEvent>)
+    where
+        S: Stream<Item=Reading> + Send + 'static,
+    {
+        let (ack_tx, ack_rx) = mpsc::unbounded_channel();
+        let (tx, rx) = mpsc::channel(64);
+
+        tokio::spawn(self.run(Box::pin(readings), ack_rx, tx));
+
+        let events = stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|e| (e, rx))
+        });
+
+        (AlarmHandle{ tx: ack_tx }, Box::pin(events))
+    }
+
+    async fn run(mut self, mut readings: BoxStream<'static, Reading>, mut ack: mpsc::UnboundedReceiver<Option<Condition>>, tx: mpsc::Sender<AlarmEvent>) {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        let mut last = (SystemTime::now(), Instant::now());
+
+        loop {
+            let events = tokio::select!{
+                r = readings.next() => match r {
+                    Some(r) => {
+                        last = (r.timestamp, Instant::now());
+                        self.update(&r)
+                    },
+                    None => break,
+                },
+                Some(c) = ack.recv() => self.acknowledge(c, last.0 + last.1.elapsed()),
+                _ = tick.tick() => self.tick(last.0 + last.1.elapsed()),
+                _ = tx.closed() => break,
+            };
+
+            for e in events {
+                if tx.send(e).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Handle for acknowledging alarms evaluated by [`AlarmEngine::events`]
+#[derive(Debug, Clone)]
+pub struct AlarmHandle {
+    tx: mpsc::UnboundedSender<Option<Condition>>,
+}
+
+impl AlarmHandle {
+    /// Acknowledge an alarm (or all alarms where no condition is provided)
+    pub fn acknowledge(&self, condition: Option<Condition>) {
+        let _ = self.tx.send(condition);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Status;
+    use super::*;
+
+    fn options() -> AlarmOptions {
+        AlarmOptions{ alarms: true, ..Default::default() }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn reading(secs: u64, spo2: Option<f32>, pulse_rate: Option<f32>) -> Reading {
+        Reading{ timestamp: at(secs), spo2, pulse_rate, perfusion_index: None, status: Status::default() }
+    }
+
+    fn probe_off(secs: u64) -> Reading {
+        Reading{ status: Status{ probe_off: true, ..Default::default() }, ..reading(secs, Some(85.0), Some(72.0)) }
+    }
+
+    /// Events for a condition, as (seconds, state) pairs
+    fn states(events: &[AlarmEvent], c: Condition) -> Vec<(u64, AlarmState)> {
+        events.iter()
+            .filter(|e| e.condition == c)
+            .map(|e| (e.timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(), e.state))
+            .collect()
+    }
+
+    #[test]
+    fn onset_delay() {
+        let mut a = AlarmEngine::new(options());
+
+        let events: Vec<_> = (0..=10).flat_map(|t| a.update(&reading(t, Some(85.0), Some(72.0)))).collect();
+
+        assert_eq!(states(&events, Condition::LowSpo2), vec![(10, AlarmState::Active)]);
+        assert_eq!(events[0].priority, Priority::High);
+        assert_eq!(events[0].value, Some(85.0));
+        assert_eq!(a.active(), vec![(Condition::LowSpo2, AlarmState::Active)]);
+
+        // A brief recovery restarts the onset delay
+        let mut a = AlarmEngine::new(options());
+        let events: Vec<_> = (0..=20)
+            .map(|t| reading(t, Some(if t == 5 { 95.0 } else { 85.0 }), Some(72.0)))
+            .flat_map(|r| a.update(&r))
+            .collect();
+
+        assert_eq!(states(&events, Condition::LowSpo2), vec![(16, AlarmState::Active)]);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut a = AlarmEngine::new(options());
+
+        let mut events: Vec<_> = (0..=10).flat_map(|t| a.update(&reading(t, Some(72.0), Some(130.0)))).collect();
+
+        // Pulse rate must fall to 115 bpm to clear
+        events.extend(a.update(&reading(11, Some(97.0), Some(118.0))));
+        events.extend(a.update(&reading(12, Some(97.0), Some(125.0))));
+        events.extend(a.update(&reading(13, Some(97.0), Some(115.0))));
+
+        assert_eq!(states(&events, Condition::HighPulse), vec![(10, AlarmState::Active), (13, AlarmState::Cleared)]);
+        assert_eq!(states(&events, Condition::LowSpo2), vec![(10, AlarmState::Active), (11, AlarmState::Cleared)]);
+
+        // SpO2 must recover to 92 % to clear
+        let mut a = AlarmEngine::new(options());
+        let mut events: Vec<_> = (0..=10).flat_map(|t| a.update(&reading(t, Some(85.0), Some(72.0)))).collect();
+        events.extend(a.update(&reading(11, Some(91.0), Some(72.0))));
+        events.extend(a.update(&reading(12, Some(92.0), Some(72.0))));
+
+        assert_eq!(states(&events, Condition::LowSpo2), vec![(10, AlarmState::Active), (12, AlarmState::Cleared)]);
+    }
+
+    #[test]
+    fn latching() {
+        let mut a = AlarmEngine::new(AlarmOptions{ latching: true, ..options() });
+
+        let mut events: Vec<_> = (0..=10).flat_map(|t| a.update(&reading(t, Some(85.0), Some(72.0)))).collect();
+
+        // Remains latched after recovering until acknowledged
+        events.extend(a.update(&reading(11, Some(97.0), Some(72.0))));
+        events.extend(a.update(&reading(12, Some(97.0), Some(72.0))));
+        assert_eq!(a.active(), vec![(Condition::LowSpo2, AlarmState::Latched)]);
+
+        events.extend(a.acknowledge(Some(Condition::LowSpo2), at(13)));
+        assert_eq!(a.active(), vec![]);
+
+        assert_eq!(states(&events, Condition::LowSpo2), vec![
+            (10, AlarmState::Active), (11, AlarmState::Latched), (13, AlarmState::Cleared),
+        ]);
+    }
+
+    #[test]
+    fn acknowledge() {
+        let mut a = AlarmEngine::new(options());
+
+        let mut events: Vec<_> = (0..=10).flat_map(|t| a.update(&reading(t, Some(85.0), Some(72.0)))).collect();
+
+        // Acknowledged alarms clear with the condition
+        events.extend(a.acknowledge(None, at(11)));
+        events.extend(a.update(&reading(12, Some(85.0), Some(72.0))));
+        assert_eq!(a.active(), vec![(Condition::LowSpo2, AlarmState::Acknowledged)]);
+
+        events.extend(a.update(&reading(13, Some(97.0), Some(72.0))));
+        events.extend(a.acknowledge(None, at(14)));
+
+        assert_eq!(states(&events, Condition::LowSpo2), vec![
+            (10, AlarmState::Active), (11, AlarmState::Acknowledged), (13, AlarmState::Cleared),
+        ]);
+    }
+
+    #[test]
+    fn probe_off_restarts_onset() {
+        let mut a = AlarmEngine::new(options());
+
+        // A single low reading, followed by probe-off readings and ticks
+        let mut events = a.update(&reading(0, Some(85.0), Some(72.0)));
+        events.extend((1..=5).flat_map(|t| a.update(&probe_off(t))));
+        events.extend((6..=20).flat_map(|t| a.tick(at(t))));
+
+        assert_eq!(states(&events, Condition::LowSpo2), vec![]);
+        assert_eq!(states(&events, Condition::ProbeOff), vec![(11, AlarmState::Active)]);
+
+        // The onset delay restarts from the next valid reading
+        events.clear();
+        events.extend((21..=31).flat_map(|t| a.update(&reading(t, Some(85.0), Some(72.0)))));
+
+        assert_eq!(states(&events, Condition::ProbeOff), vec![(21, AlarmState::Cleared)]);
+        assert_eq!(states(&events, Condition::LowSpo2), vec![(31, AlarmState::Active)]);
+    }
+
+    #[test]
+    fn low_quality_restarts_onset() {
+        let mut a = AlarmEngine::new(options());
+
+        let low_quality = |t| Reading{ status: Status{ low_quality: true, ..Default::default() }, ..reading(t, Some(80.0), Some(130.0)) };
+
+        // Values flagged by signal quality assessment neither raise nor sustain a pending onset
+        let mut events = a.update(&reading(0, Some(85.0), Some(72.0)));
+        events.extend((1..=20).flat_map(|t| a.update(&low_quality(t))));
+        events.extend((21..=25).flat_map(|t| a.tick(at(t))));
+
+        assert_eq!(states(&events, Condition::LowSpo2), vec![]);
+        assert_eq!(states(&events, Condition::HighPulse), vec![]);
+
+        // Nor do they count as valid readings for signal loss
+        assert_eq!(states(&events, Condition::SignalLost), vec![(15, AlarmState::Active)]);
+
+        // A raised alarm is unaffected by low quality readings until valid readings clear it
+        let mut a = AlarmEngine::new(options());
+        let mut events: Vec<_> = (0..=10).flat_map(|t| a.update(&reading(t, Some(85.0), Some(72.0)))).collect();
+        events.extend(a.update(&Reading{ spo2: Some(97.0), ..low_quality(11) }));
+        events.extend(a.update(&reading(12, Some(97.0), Some(72.0))));
+
+        assert_eq!(states(&events, Condition::LowSpo2), vec![(10, AlarmState::Active), (12, AlarmState::Cleared)]);
+    }
+
+    #[test]
+    fn missing_values_restart_onset() {
+        let mut a = AlarmEngine::new(options());
+
+        // SpO2 missing before the alarm is raised, pulse rate missing after
+        let events: Vec<_> = (0..=20)
+            .map(|t| reading(t, Some(85.0).filter(|_| t != 5), Some(40.0).filter(|_| t != 12)))
+            .flat_map(|r| a.update(&r))
+            .collect();
+
+        assert_eq!(states(&events, Condition::LowSpo2), vec![(16, AlarmState::Active)]);
+        assert_eq!(states(&events, Condition::LowPulse), vec![(10, AlarmState::Active)]);
+    }
+
+    #[test]
+    fn signal_lost() {
+        let mut a = AlarmEngine::new(options());
+
+        let mut events = a.update(&reading(0, Some(97.0), Some(72.0)));
+        events.extend((1..=20).flat_map(|t| a.tick(at(t))));
+        events.extend(a.update(&reading(21, Some(97.0), Some(72.0))));
+
+        assert_eq!(states(&events, Condition::SignalLost), vec![(15, AlarmState::Active), (21, AlarmState::Cleared)]);
+    }
+}
+
@@ -0,0 +1,326 @@
+//! Hypoxic burden, the area under the desaturation curve per hour.
+//!
+//! The ensemble-averaged method follows Azarbarzin et al. (Eur Heart J, 2019).
+//! SpO2 curves around each event are averaged to find a common search window,
+//! spanning the peaks either side of the averaged nadir. The area below each event's
+//! pre-event baseline (the maximum SpO2 in the preceding 100 s) is then summed
+//! within that window. Oximetry-only recordings have no scored respiratory events,
+//! so desaturation nadirs are used as the event anchors.
+//!
+//! The baseline-area variant sums the area below each desaturation's rolling
+//! baseline between the event start and end.
+//!
+//! Both are reported in %·min/h of valid recording time, and each point in time is
+//! counted at most once where event windows overlap.
+
+use std::time::{Duration, SystemTime};
+
+use serde::{Serialize, Deserialize};
+
+use crate::reading::unix_time;
+use super::{Sample, Desaturation, duration_secs};
+
+
+/// Period either side of each anchor included in the ensemble average
+const ENSEMBLE_WINDOW: i64 = 120;
+
+/// Period before each anchor used to find the pre-event baseline
+const BASELINE_WINDOW: i64 = 100;
+
+/// Hypoxic burden method
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    /// Ensemble-averaged search window with pre-event baselines
+    EnsembleAveraged,
+    /// Area below the rolling baseline for each desaturation
+    BaselineArea,
+}
+
+/// Event window contributing to the hypoxic burden
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BurdenWindow {
+    #[serde(with = "unix_time")]
+    pub start: SystemTime,
+    #[serde(with = "unix_time")]
+    pub end: SystemTime,
+    /// Baseline SpO2 the area is measured from (%)
+    pub baseline: f32,
+    /// Area below baseline (%·min)
+    pub area: f64,
+}
+
+/// Hypoxic burden for a session
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct HypoxicBurden {
+    pub method: Method,
+    /// Hypoxic burden (%·min/h)
+    pub burden: f64,
+    /// Total area below baseline (%·min)
+    pub area: f64,
+    /// Valid recording time the burden is normalised by
+    #[serde(with = "duration_secs")]
+    pub valid_time: Duration,
+    /// Ensemble search window relative to each anchor (s), for the ensemble-averaged method
+    #[serde(default)]
+    pub search_window: Option<(i64, i64)>,
+    /// Windows used for each event
+    pub windows: Vec<BurdenWindow>,
+}
+
+/// Hypoxic burden by both methods
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BurdenReport {
+    pub ensemble_averaged: HypoxicBurden,
+    pub baseline_area: HypoxicBurden,
+}
+
+impl BurdenReport {
+    /// Calculate the hypoxic burden of 1 Hz samples with the provided desaturation events
+    pub fn new(samples: &[Sample], events: &[Desaturation]) -> Self {
+        Self{
+            ensemble_averaged: ensemble_averaged(samples, events),
+            baseline_area: baseline_area(samples, events),
+        }
+    }
+}
+
+/// SpO2 series indexed by seconds from the first sample
+struct Series {
+    start: SystemTime,
+    values: Vec<Option<f32>>,
+    valid_time: Duration,
+}
+
+impl Series {
+    fn new(samples: &[Sample]) -> Self {
+        let start = samples.first().map(|s| s.0).unwrap_or(SystemTime::UNIX_EPOCH);
+        let values: Vec<_> = samples.iter().map(super::spo2).collect();
+        let valid_time = Duration::from_secs(values.iter().filter(|v| v.is_some()).count() as u64);
+
+        Self{ start, values, valid_time }
+    }
+
+    fn index(&self, t: SystemTime) -> i64 {
+        match t.duration_since(self.start) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        }
+    }
+
+    fn time(&self, i: i64) -> SystemTime {
+        match i >= 0 {
+            true => self.start + Duration::from_secs(i as u64),
+            false => self.start - Duration::from_secs(-i as u64),
+        }
+    }
+
+    fn get(&self, i: i64) -> Option<f32> {
+        usize::try_from(i).ok().and_then(|i| self.values.get(i).cloned().flatten())
+    }
+
+    /// Sum the area below baseline over a window, skipping points already counted
+    fn area(&self, from: i64, to: i64, baseline: f32, counted: &mut [bool]) -> f64 {
+        let mut area = 0.0;
+
+        for i in from.max(0)..=to.min(self.values.len() as i64 - 1) {
+            let (i, c) = (i as usize, &mut counted[i as usize]);
+            if let (Some(v), false) = (self.values[i], *c) {
+                area += (baseline - v).max(0.0) as f64;
+                *c = true;
+            }
+        }
+
+        // Samples are one second apart, convert %·s to %·min
+        area / 60.0
+    }
+
+    fn burden(&self, method: Method, search_window: Option<(i64, i64)>, windows: Vec<BurdenWindow>) -> HypoxicBurden {
+        // Folded from zero, as an empty float sum is negative zero
+        let area = windows.iter().map(|w| w.area).fold(0.0, |a, v| a + v);
+        let hours = self.valid_time.as_secs_f64() / 3600.0;
+
+        HypoxicBurden{
+            method,
+            burden: if hours > 0.0 { area / hours } else { 0.0 },
+            area,
+            valid_time: self.valid_time,
+            search_window,
+            windows,
+        }
+    }
+}
+
+/// Calculate the ensemble-averaged hypoxic burden, anchored at each desaturation nadir
+pub fn ensemble_averaged(samples: &[Sample], events: &[Desaturation]) -> HypoxicBurden {
+    let series = Series::new(samples);
+    let anchors: Vec<_> = events.iter().map(|e| series.index(e.nadir_time)).collect();
+
+    let search_window = match search_window(&series, &anchors) {
+        Some(w) => w,
+        None => return series.burden(Method::EnsembleAveraged, None, vec![]),
+    };
+
+    let mut counted = vec![false; series.values.len()];
+    let windows = anchors.iter().filter_map(|a| {
+        // Pre-event baseline, the maximum SpO2 in the period before the anchor
+        let baseline = (a - BASELINE_WINDOW..*a)
+            .filter_map(|i| series.get(i))
+            .fold(None, |m: Option<f32>, v| Some(m.map_or(v, |m| m.max(v))))?;
+
+        let (from, to) = (a + search_window.0, a + search_window.1);
+        let area = series.area(from, to, baseline, &mut counted);
+
+        Some(BurdenWindow{ start: series.time(from), end: series.time(to), baseline, area })
+    }).collect();
+
+    series.burden(Method::EnsembleAveraged, Some(search_window), windows)
+}
+
+/// Find the search window from the ensemble average of SpO2 around each anchor,
+/// spanning the peaks either side of the averaged nadir
+fn search_window(series: &Series, anchors: &[i64]) -> Option<(i64, i64)> {
+    let average: Vec<Option<f32>> = (-ENSEMBLE_WINDOW..=ENSEMBLE_WINDOW).map(|o| {
+        let values: Vec<_> = anchors.iter().filter_map(|a| series.get(a + o)).collect();
+        match values.is_empty() {
+            true => None,
+            false => Some(values.iter().sum::<f32>() / values.len() as f32),
+        }
+    }).collect();
+
+    let nadir = average.iter().enumerate()
+        .filter_map(|(i, v)| v.map(|v| (i, v)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))?
+        .0;
+
+    // Walk out from the nadir to the peak either side, crossing plateaus, and ending
+    // where the averaged curve last rose
+    let rising = |from: usize, step: isize| {
+        let (mut i, mut peak) = (from, from);
+        loop {
+            let next = i as isize + step;
+            match (average[i], usize::try_from(next).ok().and_then(|n| average.get(n).cloned().flatten())) {
+                (Some(v), Some(n)) if n >= v => {
+                    i = next as usize;
+                    if n > v {
+                        peak = i;
+                    }
+                },
+                _ => return peak,
+            }
+        }
+    };
+
+    let (start, end) = (rising(nadir, -1), rising(nadir, 1));
+
+    Some((start as i64 - ENSEMBLE_WINDOW, end as i64 - ENSEMBLE_WINDOW))
+}
+
+/// Calculate the baseline-area hypoxic burden, between each desaturation's start and end
+pub fn baseline_area(samples: &[Sample], events: &[Desaturation]) -> HypoxicBurden {
+    let series = Series::new(samples);
+    let mut counted = vec![false; series.values.len()];
+
+    let windows = events.iter().map(|e| {
+        let area = series.area(series.index(e.start), series.index(e.end), e.baseline, &mut counted);
+        BurdenWindow{ start: e.start, end: e.end, baseline: e.baseline, area }
+    }).collect();
+
+    series.burden(Method::BaselineArea, None, windows)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Reading, Status};
+    use super::super::{DesaturationOptions, desaturation};
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn sample(t: u64, spo2: f32, status: Status) -> Sample {
+        (at(t), Some(Reading{ timestamp: at(t), spo2: Some(spo2), pulse_rate: Some(72.0), perfusion_index: None, status }))
+    }
+
+    /// One hour at 96 %, with 28 drops to 88 % for 20 s every 120 s
+    fn drops() -> Vec<Sample> {
+        (0..3600).map(|t| {
+            let spo2 = if t >= 120 && t % 120 >= 60 && t % 120 < 80 && t < 3480 { 88.0 } else { 96.0 };
+            sample(t, spo2, Status::default())
+        }).collect()
+    }
+
+    /// Each event contributes 8 % for 20 s, 28 * 8 * 20 / 60 %·min over one hour
+    const DROPS_BURDEN: f64 = 28.0 * 8.0 * 20.0 / 60.0;
+
+    #[test]
+    fn ensemble_averaged_drops() {
+        let samples = drops();
+        let events = desaturation::detect(&samples, &DesaturationOptions::default());
+        assert_eq!(events.len(), 28);
+
+        let b = ensemble_averaged(&samples, &events);
+
+        // Anchored at the first nadir sample, spanning the averaged fall and recovery
+        assert_eq!(b.search_window, Some((-1, 20)));
+        assert_eq!(b.windows.len(), 28);
+        assert!(b.windows.iter().all(|w| w.baseline == 96.0 && (w.area - 160.0 / 60.0).abs() < 1e-9));
+
+        assert_eq!(b.valid_time, Duration::from_secs(3600));
+        assert!((b.burden - DROPS_BURDEN).abs() < 1e-9, "burden {}", b.burden);
+        assert!((b.burden - 74.67).abs() < 0.01);
+    }
+
+    #[test]
+    fn baseline_area_drops() {
+        let samples = drops();
+        let events = desaturation::detect(&samples, &DesaturationOptions::default());
+
+        let b = baseline_area(&samples, &events);
+
+        assert_eq!(b.search_window, None);
+        assert_eq!(b.windows[0], BurdenWindow{ start: at(180), end: at(200), baseline: 96.0, area: 160.0 / 60.0 });
+        assert!((b.burden - DROPS_BURDEN).abs() < 1e-9, "burden {}", b.burden);
+    }
+
+    #[test]
+    fn artifacts_excluded() {
+        // Ten minutes of artifact, including drops, adds neither area nor valid time
+        let mut samples = drops();
+        samples.extend((3600..4200).map(|t| sample(t, if t % 120 < 20 { 80.0 } else { 96.0 }, Status{ motion: true, ..Default::default() })));
+
+        let events = desaturation::detect(&samples, &DesaturationOptions::default());
+        let r = BurdenReport::new(&samples, &events);
+
+        assert_eq!(r.ensemble_averaged.valid_time, Duration::from_secs(3600));
+        assert!((r.ensemble_averaged.burden - DROPS_BURDEN).abs() < 1e-9);
+        assert!((r.baseline_area.burden - DROPS_BURDEN).abs() < 1e-9);
+    }
+
+    #[test]
+    fn overlapping_windows_counted_once() {
+        let samples = drops();
+        let events = desaturation::detect(&samples, &DesaturationOptions::default());
+
+        let doubled: Vec<_> = events.iter().flat_map(|e| [e.clone(), e.clone()]).collect();
+        let r = BurdenReport::new(&samples, &doubled);
+
+        assert!((r.ensemble_averaged.area - DROPS_BURDEN).abs() < 1e-9);
+        assert!((r.baseline_area.area - DROPS_BURDEN).abs() < 1e-9);
+    }
+
+    #[test]
+    fn no_events() {
+        let samples: Vec<_> = (0..600).map(|t| sample(t, 96.0, Status::default())).collect();
+
+        let r = BurdenReport::new(&samples, &[]);
+
+        for b in [&r.ensemble_averaged, &r.baseline_area] {
+            assert_eq!(b.burden, 0.0);
+            assert!(b.burden.is_sign_positive() && b.area.is_sign_positive());
+            assert_eq!(b.windows, vec![]);
+        }
+        assert_eq!(r.ensemble_averaged.search_window, None);
+    }
+}
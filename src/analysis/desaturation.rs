@@ -0,0 +1,390 @@
+//! Oxygen desaturation event detection and oxygen desaturation index (ODI).
+//!
+//! A desaturation starts when SpO2 falls by at least [`DesaturationOptions::drop`]
+//! below a rolling baseline (the mean SpO2 over the preceding
+//! [`DesaturationOptions::baseline_window`], excluding earlier events) and ends when
+//! SpO2 recovers to within the drop of the baseline. Events shorter than
+//! [`DesaturationOptions::min_duration`] are discarded, and an event ends early at
+//! the last valid sample if readings are lost or flagged as artifacts.
+
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use serde::{Serialize, Deserialize};
+use structopt::StructOpt;
+
+use crate::Reading;
+use crate::reading::unix_time;
+use crate::recording::Record;
+use super::{Sample, Resampler, duration_secs};
+
+
+/// Minimum valid data within the baseline window for a baseline to be established
+const MIN_BASELINE: Duration = Duration::from_secs(10);
+
+/// Desaturation detection options
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct DesaturationOptions {
+    /// SpO2 drop from baseline for a desaturation event (%), typically 3 or 4
+    #[structopt(long="desat-drop", default_value="3")]
+    pub drop: f32,
+
+    /// Rolling baseline window
+    #[structopt(long="desat-baseline", default_value="120s")]
+    pub baseline_window: humantime::Duration,
+
+    /// Minimum desaturation event duration
+    #[structopt(long="desat-min-duration", default_value="10s")]
+    pub min_duration: humantime::Duration,
+
+    /// Maximum period from the start of an event to search for the nadir
+    #[structopt(long="desat-nadir-window", default_value="120s")]
+    pub nadir_window: humantime::Duration,
+}
+
+impl Default for DesaturationOptions {
+    fn default() -> Self {
+        Self{
+            drop: 3.0,
+            baseline_window: Duration::from_secs(120).into(),
+            min_duration: Duration::from_secs(10).into(),
+            nadir_window: Duration::from_secs(120).into(),
+        }
+    }
+}
+
+/// Desaturation event
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Desaturation {
+    #[serde(with = "unix_time")]
+    pub start: SystemTime,
+    #[serde(with = "unix_time")]
+    pub nadir_time: SystemTime,
+    #[serde(with = "unix_time")]
+    pub end: SystemTime,
+    /// Baseline SpO2 (%)
+    pub baseline: f32,
+    /// Lowest SpO2 during the event (%)
+    pub nadir: f32,
+}
+
+impl Desaturation {
+    /// Event duration
+    pub fn duration(&self) -> Duration {
+        self.end.duration_since(self.start).unwrap_or_default()
+    }
+
+    /// Depth of the desaturation below baseline (%)
+    pub fn depth(&self) -> f32 {
+        self.baseline - self.nadir
+    }
+}
+
+/// Desaturation events and indices for a session
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DesaturationReport {
+    /// Events detected with the configured criteria
+    pub events: Vec<Desaturation>,
+    /// Recording time with valid, artifact free SpO2
+    #[serde(with = "duration_secs")]
+    pub valid_time: Duration,
+    /// Desaturations of 3% or more per hour of valid time
+    pub odi3: f64,
+    /// Desaturations of 4% or more per hour of valid time
+    pub odi4: f64,
+}
+
+impl DesaturationReport {
+    /// Analyse 1 Hz samples
+    pub fn new(samples: &[Sample], opts: &DesaturationOptions) -> Self {
+        let events = detect(samples, opts);
+
+        let valid_time = Duration::from_secs(samples.iter().filter(|s| super::spo2(s).is_some()).count() as u64);
+        let hours = valid_time.as_secs_f64() / 3600.0;
+
+        // Indices always use the standard criteria, reusing the configured events where they match
+        let index = |drop: f32| {
+            let n = match opts.drop == drop {
+                true => events.len(),
+                false => detect(samples, &DesaturationOptions{ drop, ..opts.clone() }).len(),
+            };
+            match hours > 0.0 {
+                true => n as f64 / hours,
+                false => 0.0,
+            }
+        };
+
+        Self{ odi3: index(3.0), odi4: index(4.0), events, valid_time }
+    }
+
+    /// Analyse a recorded session
+    pub fn from_records(records: &[Record], opts: &DesaturationOptions) -> Self {
+        Self::new(&super::resample(records), opts)
+    }
+}
+
+/// Detect desaturation events in 1 Hz samples
+pub fn detect(samples: &[Sample], opts: &DesaturationOptions) -> Vec<Desaturation> {
+    let mut d = Detector::new(opts.clone());
+
+    let mut events: Vec<_> = samples.iter().filter_map(|s| d.push(s)).collect();
+    events.extend(d.finish());
+
+    events
+}
+
+/// Detect desaturation events from a stream of readings, events are emitted as they end
+pub fn events<S>(readings: S, opts: DesaturationOptions) -> BoxStream<'static, Desaturation>
+where
+    S: Stream<Item=Reading> + Send + 'static,
+{
+    let state = (Box::pin(readings), Resampler::new(), Detector::new(opts));
+
+    let events = stream::unfold(Some(state), |state| async move {
+        let (mut readings, mut resampler, mut detector) = state?;
+
+        let events: Vec<_> = match readings.next().await {
+            Some(r) => {
+                let events = resampler.push_reading(&r).iter().filter_map(|s| detector.push(s)).collect();
+                return Some((events, Some((readings, resampler, detector))));
+            },
+            None => {
+                let mut events: Vec<_> = resampler.finish().iter().filter_map(|s| detector.push(s)).collect();
+                events.extend(detector.finish());
+                events
+            },
+        };
+
+        Some((events, None))
+    });
+
+    Box::pin(events.flat_map(stream::iter))
+}
+
+/// Event in progress
+#[derive(Debug, Clone)]
+struct Pending {
+    start: SystemTime,
+    baseline: f32,
+    nadir: (SystemTime, f32),
+    last: SystemTime,
+}
+
+/// Incremental desaturation detector, operating on 1 Hz samples
+#[derive(Debug, Clone)]
+pub struct Detector {
+    opts: DesaturationOptions,
+    /// Samples within the baseline window, flagged where part of an event
+    history: VecDeque<(SystemTime, Option<f32>, bool)>,
+    event: Option<Pending>,
+}
+
+impl Detector {
+    /// Create a new detector
+    pub fn new(opts: DesaturationOptions) -> Self {
+        Self{ opts, history: VecDeque::new(), event: None }
+    }
+
+    /// Add a sample, returning an event if one has ended
+    pub fn push(&mut self, s: &Sample) -> Option<Desaturation> {
+        let (t, v) = (s.0, super::spo2(s));
+        let mut ended = None;
+
+        match (self.event.as_mut(), v) {
+            // Still desaturated, tracking the nadir within the search window
+            (Some(e), Some(v)) if v <= e.baseline - self.opts.drop => {
+                let in_window = t.duration_since(e.start).map(|d| d <= *self.opts.nadir_window).unwrap_or(false);
+                if in_window && v < e.nadir.1 {
+                    e.nadir = (t, v);
+                }
+                e.last = t;
+            },
+            // Recovered, or readings lost
+            (Some(_), v) => {
+                let e = self.event.take().unwrap();
+                let end = match v {
+                    Some(_) => t,
+                    None => e.last,
+                };
+                ended = self.complete(e, end);
+            },
+            (None, Some(v)) => {
+                if let Some(b) = self.baseline().filter(|b| v <= b - self.opts.drop) {
+                    self.event = Some(self.begin(t, v, b));
+                }
+            },
+            (None, None) => (),
+        }
+
+        self.history.push_back((t, v, self.event.is_some()));
+        while let Some((h, _, _)) = self.history.front() {
+            match t.duration_since(*h).map(|d| d >= *self.opts.baseline_window) {
+                Ok(true) => self.history.pop_front(),
+                _ => break,
+            };
+        }
+
+        ended
+    }
+
+    /// Complete detection, returning any event in progress
+    pub fn finish(&mut self) -> Option<Desaturation> {
+        let e = self.event.take()?;
+        let end = e.last;
+        self.complete(e, end)
+    }
+
+    /// Rolling baseline, the mean of valid samples outside events within the window
+    fn baseline(&self) -> Option<f32> {
+        let values: Vec<_> = self.history.iter()
+            .filter(|(_, _, event)| !event)
+            .filter_map(|(_, v, _)| *v)
+            .collect();
+
+        match values.len() as u64 >= MIN_BASELINE.as_secs() {
+            true => Some(values.iter().sum::<f32>() / values.len() as f32),
+            false => None,
+        }
+    }
+
+    /// Start an event, backtracking the start to where SpO2 began falling from baseline
+    fn begin(&self, t: SystemTime, v: f32, baseline: f32) -> Pending {
+        let mut start = t;
+
+        for (h, hv, _) in self.history.iter().rev() {
+            let within = t.duration_since(*h).map(|d| d <= *self.opts.nadir_window).unwrap_or(false);
+            match hv {
+                Some(hv) if within && *hv < baseline - 1.0 => start = *h,
+                _ => break,
+            }
+        }
+
+        Pending{ start, baseline, nadir: (t, v), last: t }
+    }
+
+    fn complete(&self, e: Pending, end: SystemTime) -> Option<Desaturation> {
+        let d = Desaturation{ start: e.start, nadir_time: e.nadir.0, end, baseline: e.baseline, nadir: e.nadir.1 };
+
+        match d.duration() >= *self.opts.min_duration {
+            true => Some(d),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Status;
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// 1 Hz samples from SpO2 values, `None` for missing readings
+    fn samples(values: &[Option<f32>]) -> Vec<Sample> {
+        values.iter().enumerate().map(|(i, v)| {
+            let r = v.map(|v| Reading{ timestamp: at(i as u64), spo2: Some(v), pulse_rate: Some(72.0), perfusion_index: None, status: Status::default() });
+            (at(i as u64), r)
+        }).collect()
+    }
+
+    /// Baseline at 96 %, falling 1 %/s to an 88 % nadir held for 5 s, then recovering at 1 %/s
+    fn v_shaped() -> Vec<Option<f32>> {
+        let mut v = vec![96.0; 121];
+        v.extend((1..=8).map(|i| 96.0 - i as f32));
+        v.extend([88.0; 4]);
+        v.extend((1..=8).map(|i| 88.0 + i as f32));
+        v.extend([96.0; 60]);
+        v.into_iter().map(Some).collect()
+    }
+
+    #[test]
+    fn event_timing() {
+        let events = detect(&samples(&v_shaped()), &DesaturationOptions::default());
+
+        // The baseline includes the start of the fall, (117 * 96 + 95 + 94) / 119 %
+        assert_eq!(events.len(), 1);
+        let e = &events[0];
+        assert!((e.baseline - 95.95).abs() < 0.01, "baseline {}", e.baseline);
+
+        // Detected at 92 % (t = 124), backtracked to the first sample more than 1 % below
+        // baseline, with the nadir at the first 88 % sample, ending once SpO2 recovers
+        // above 92.95 %
+        assert_eq!((e.start, e.nadir_time, e.end, e.nadir), (at(122), at(128), at(137), 88.0));
+        assert_eq!(e.duration(), Duration::from_secs(15));
+        assert!((e.depth() - 7.95).abs() < 0.01);
+    }
+
+    #[test]
+    fn minimum_duration() {
+        let mut v = vec![Some(96.0); 120];
+        v.extend([Some(90.0); 9]);
+        v.extend([Some(96.0); 30]);
+        assert_eq!(detect(&samples(&v), &DesaturationOptions::default()), vec![]);
+
+        v[129] = Some(90.0);
+        assert_eq!(detect(&samples(&v), &DesaturationOptions::default()).len(), 1);
+    }
+
+    #[test]
+    fn lost_readings_end_event() {
+        let mut v = v_shaped();
+        for s in &mut v[133..143] {
+            *s = None;
+        }
+
+        let events = detect(&samples(&v), &DesaturationOptions::default());
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].start, events[0].end), (at(122), at(132)));
+
+        // Events cut below the minimum duration are discarded
+        v[131] = None;
+        assert_eq!(detect(&samples(&v), &DesaturationOptions::default()), vec![]);
+    }
+
+    #[test]
+    fn odi() {
+        // One hour at 96 %, with 20 s drops to 88 % every 120 s
+        let mut v = vec![Some(96.0); 3600];
+        for k in 1..=28 {
+            for s in &mut v[120 * k + 60..120 * k + 80] {
+                *s = Some(88.0);
+            }
+        }
+        let mut s = samples(&v);
+
+        let report = DesaturationReport::new(&s, &DesaturationOptions::default());
+        assert_eq!(report.events.len(), 28);
+        assert!(report.events.iter().all(|e| e.duration() == Duration::from_secs(20) && e.nadir == 88.0));
+        assert_eq!(report.valid_time, Duration::from_secs(3600));
+        assert_eq!((report.odi3, report.odi4), (28.0, 28.0));
+
+        // A further 10 minutes of artifact, including a drop, is excluded from both
+        // the events and the valid time
+        s.extend((3600..4200).map(|t| {
+            let spo2 = if t % 120 < 20 { 80.0 } else { 96.0 };
+            let r = Reading{ timestamp: at(t), spo2: Some(spo2), pulse_rate: Some(72.0), perfusion_index: None, status: Status{ low_quality: true, ..Default::default() } };
+            (at(t), Some(r))
+        }));
+
+        let with_artifact = DesaturationReport::new(&s, &DesaturationOptions::default());
+        assert_eq!(with_artifact, report);
+    }
+
+    #[test]
+    fn odi_criteria() {
+        // Drops of 3.5 % count towards ODI3 only
+        let mut v = vec![Some(96.0); 3600];
+        for k in 1..=10 {
+            for s in &mut v[300 * k..300 * k + 20] {
+                *s = Some(92.5);
+            }
+        }
+
+        let report = DesaturationReport::new(&samples(&v), &DesaturationOptions{ drop: 4.0, ..Default::default() });
+        assert_eq!(report.events, vec![]);
+        assert_eq!((report.odi3, report.odi4), (10.0, 0.0));
+    }
+}
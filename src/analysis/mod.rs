@@ -0,0 +1,161 @@
+//! Offline and streaming analysis of SpO2 / pulse rate series.
+//!
+//! Analyses operate on readings resampled to 1 Hz, with each sample holding the
+//! latest valid reading or `None` where the sensor was disconnected or readings
+//! were invalid or stale. Samples flagged as artifacts are excluded from analysis.
+
+use std::time::{Duration, SystemTime};
+
+use crate::Reading;
+use crate::recording::Record;
+
+pub mod desaturation;
+pub use desaturation::{Desaturation, DesaturationOptions, DesaturationReport};
+
+pub mod burden;
+pub use burden::{BurdenReport, HypoxicBurden};
+
+pub mod summary;
+pub use summary::Summary;
+
+
+/// Readings older than this are treated as missing
+pub(crate) const STALE_AFTER: Duration = Duration::from_secs(5);
+
+/// 1 Hz sample, holding the reading for the second starting at the timestamp
+pub type Sample = (SystemTime, Option<Reading>);
+
+/// Incrementally resamples readings to 1 Hz.
+///
+/// Sampling starts at the first valid reading (truncated to the second), each sample
+/// holds the latest valid reading received by the end of that second.
+#[derive(Debug, Clone, Default)]
+pub struct Resampler {
+    /// Start of the next sample to be emitted
+    next: Option<SystemTime>,
+    /// Timestamp of the latest reading
+    last: Option<SystemTime>,
+    current: Option<Reading>,
+}
+
+impl Resampler {
+    /// Create a new resampler
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a record, returning samples completed before it. Readings update the
+    /// held value and disconnections clear it, other records are ignored.
+    pub fn push(&mut self, r: &Record) -> Vec<Sample> {
+        // Header and device records carry their own creation times so are excluded from ordering
+        if !matches!(r, Record::Reading(_) | Record::Disconnected{ .. }) {
+            return vec![];
+        }
+
+        let t = r.timestamp();
+
+        let next = match (self.next, r) {
+            (Some(n), _) => n,
+            (None, Record::Reading(r)) if r.is_valid() => {
+                let since_epoch = t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                SystemTime::UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())
+            },
+            _ => return vec![],
+        };
+        self.next = Some(next);
+
+        let samples = self.until(t);
+
+        match r {
+            Record::Reading(r) => {
+                self.current = Some(r.clone());
+                self.last = Some(t);
+            },
+            Record::Disconnected{ .. } => self.current = None,
+            _ => (),
+        }
+
+        samples
+    }
+
+    /// Add a reading, returning samples completed before it
+    pub fn push_reading(&mut self, r: &Reading) -> Vec<Sample> {
+        self.push(&Record::Reading(r.clone()))
+    }
+
+    /// Complete resampling, returning the sample containing the final reading
+    pub fn finish(mut self) -> Vec<Sample> {
+        match self.last {
+            Some(l) => self.until(l + Duration::from_secs(1)),
+            None => vec![],
+        }
+    }
+
+    /// Emit samples for each second ending at or before the provided time
+    fn until(&mut self, t: SystemTime) -> Vec<Sample> {
+        let mut samples = vec![];
+
+        while let Some(n) = self.next {
+            let end = n + Duration::from_secs(1);
+            if end > t {
+                break;
+            }
+
+            let r = self.current.as_ref()
+                .filter(|r| r.is_valid())
+                .filter(|r| end.duration_since(r.timestamp).map(|d| d <= STALE_AFTER).unwrap_or(true));
+
+            samples.push((n, r.cloned()));
+            self.next = Some(end);
+        }
+
+        samples
+    }
+}
+
+/// Resample recorded readings to 1 Hz, see [`Resampler`]
+pub fn resample(records: &[Record]) -> Vec<Sample> {
+    let mut r = Resampler::new();
+
+    let mut samples: Vec<_> = records.iter().flat_map(|v| r.push(v)).collect();
+    samples.extend(r.finish());
+
+    samples
+}
+
+/// Check whether a reading is an artifact, where the sensor reports motion, low
+/// signal or a fault, signal quality assessment flags it, or the SpO2 value is implausible
+pub fn is_artifact(r: &Reading) -> bool {
+    let s = &r.status;
+    !r.is_valid() || s.motion || s.low_signal || s.sensor_fault || s.searching || s.low_quality
+        || r.spo2.map(|v| !(50.0..=100.0).contains(&v)).unwrap_or(false)
+}
+
+/// Fetch the SpO2 value of a sample for analysis, `None` for missing or artifact samples
+pub fn spo2(s: &Sample) -> Option<f32> {
+    s.1.as_ref().filter(|r| !is_artifact(r)).and_then(|r| r.spo2)
+}
+
+/// Fetch the pulse rate of a sample for analysis, `None` for missing or artifact samples
+pub fn pulse_rate(s: &Sample) -> Option<f32> {
+    s.1.as_ref().filter(|r| !is_artifact(r)).and_then(|r| r.pulse_rate)
+}
+
+/// Serialise durations as fractional seconds
+pub mod duration_secs {
+    use std::time::Duration;
+
+    use serde::{Serializer, Deserializer, Deserialize};
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_f64(d.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let v = f64::deserialize(d)?;
+        if !v.is_finite() || v < 0.0 {
+            return Err(serde::de::Error::custom("invalid duration"));
+        }
+        Ok(Duration::from_secs_f64(v))
+    }
+}
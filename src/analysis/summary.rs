@@ -0,0 +1,237 @@
+//! Overnight summary statistics
+
+use std::time::{Duration, SystemTime};
+
+use serde::{Serialize, Deserialize};
+
+use crate::recording::Record;
+use crate::reading::unix_time;
+use super::{Sample, DesaturationOptions, DesaturationReport, BurdenReport, duration_secs};
+
+
+/// Time spent at an SpO2 level
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct HistogramBin {
+    /// SpO2 (%), rounded to the nearest percent
+    pub spo2: u8,
+    #[serde(with = "duration_secs")]
+    pub time: Duration,
+}
+
+/// SpO2 statistics over valid time
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Spo2Summary {
+    pub mean: f32,
+    pub median: f32,
+    /// Lowest SpO2 (%)
+    pub nadir: f32,
+    #[serde(with = "unix_time")]
+    pub nadir_time: SystemTime,
+    /// Time below 90%
+    #[serde(with = "duration_secs")]
+    pub t90: Duration,
+    /// Time below 88%
+    #[serde(with = "duration_secs")]
+    pub t88: Duration,
+    /// Percentage of valid time below 90%
+    pub ct90: f32,
+    /// Time at each SpO2 level, in ascending order
+    pub histogram: Vec<HistogramBin>,
+}
+
+/// Pulse rate statistics over valid time
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PulseSummary {
+    pub min: f32,
+    pub mean: f32,
+    pub max: f32,
+}
+
+/// Summary of a recorded session
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Summary {
+    /// Time of the first sample
+    #[serde(with = "unix_time")]
+    pub start: SystemTime,
+    /// Time of the end of the final sample
+    #[serde(with = "unix_time")]
+    pub end: SystemTime,
+    /// Recording time with valid, artifact free SpO2
+    #[serde(with = "duration_secs")]
+    pub valid_time: Duration,
+    /// SpO2 statistics, `None` where there is no valid data
+    pub spo2: Option<Spo2Summary>,
+    /// Pulse rate statistics, `None` where there is no valid data
+    pub pulse_rate: Option<PulseSummary>,
+    /// Number of sensor disconnections
+    pub disconnects: usize,
+    /// Number of periods with the probe off the finger
+    pub probe_off: usize,
+    /// Desaturation events and indices
+    pub desaturations: DesaturationReport,
+    /// Hypoxic burden of the desaturation events
+    pub hypoxic_burden: BurdenReport,
+}
+
+impl Summary {
+    /// Summarise a recorded session
+    pub fn from_records(records: &[Record], opts: &DesaturationOptions) -> Self {
+        let samples = super::resample(records);
+
+        let disconnects = records.iter().filter(|r| matches!(r, Record::Disconnected{ .. })).count();
+
+        // Count transitions into probe-off
+        let mut probe_off = 0;
+        let mut off = false;
+        for r in records {
+            if let Record::Reading(r) = r {
+                if r.status.probe_off && !off {
+                    probe_off += 1;
+                }
+                off = r.status.probe_off;
+            }
+        }
+
+        Self::new(&samples, opts, disconnects, probe_off)
+    }
+
+    /// Summarise 1 Hz samples, with disconnection and probe-off counts from the source data
+    pub fn new(samples: &[Sample], opts: &DesaturationOptions, disconnects: usize, probe_off: usize) -> Self {
+        let start = samples.first().map(|s| s.0).unwrap_or(SystemTime::UNIX_EPOCH);
+        let end = samples.last().map(|s| s.0 + Duration::from_secs(1)).unwrap_or(start);
+
+        let spo2: Vec<_> = samples.iter().filter_map(|s| super::spo2(s).map(|v| (s.0, v))).collect();
+        let pulse: Vec<_> = samples.iter().filter_map(super::pulse_rate).collect();
+
+        let desaturations = DesaturationReport::new(samples, opts);
+        let hypoxic_burden = BurdenReport::new(samples, &desaturations.events);
+
+        Self{
+            start,
+            end,
+            valid_time: Duration::from_secs(spo2.len() as u64),
+            spo2: spo2_summary(&spo2),
+            pulse_rate: pulse_summary(&pulse),
+            disconnects,
+            probe_off,
+            desaturations,
+            hypoxic_burden,
+        }
+    }
+}
+
+fn spo2_summary(values: &[(SystemTime, f32)]) -> Option<Spo2Summary> {
+    let (nadir_time, nadir) = values.iter()
+        .cloned()
+        .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+    let mut sorted: Vec<_> = values.iter().map(|(_, v)| *v).collect();
+    sorted.sort_by(f32::total_cmp);
+
+    let n = sorted.len();
+    let median = match n % 2 {
+        0 => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0,
+        _ => sorted[n / 2],
+    };
+
+    // Each sample represents one second
+    let below = |level: f32| Duration::from_secs(sorted.iter().filter(|v| **v < level).count() as u64);
+    let t90 = below(90.0);
+
+    let mut histogram: Vec<HistogramBin> = vec![];
+    for v in &sorted {
+        let spo2 = v.round() as u8;
+        match histogram.last_mut() {
+            Some(b) if b.spo2 == spo2 => b.time += Duration::from_secs(1),
+            _ => histogram.push(HistogramBin{ spo2, time: Duration::from_secs(1) }),
+        }
+    }
+
+    Some(Spo2Summary{
+        mean: sorted.iter().sum::<f32>() / n as f32,
+        median,
+        nadir,
+        nadir_time,
+        t90,
+        t88: below(88.0),
+        ct90: t90.as_secs() as f32 * 100.0 / n as f32,
+        histogram,
+    })
+}
+
+fn pulse_summary(values: &[f32]) -> Option<PulseSummary> {
+    if values.is_empty() {
+        return None;
+    }
+
+    Some(PulseSummary{
+        min: values.iter().cloned().fold(f32::INFINITY, f32::min),
+        mean: values.iter().sum::<f32>() / values.len() as f32,
+        max: values.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Address, Reading, Status};
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn reading(t: u64, spo2: f32, pulse_rate: f32, probe_off: bool) -> Record {
+        Record::Reading(Reading{
+            timestamp: at(t),
+            spo2: Some(spo2),
+            pulse_rate: Some(pulse_rate),
+            perfusion_index: None,
+            status: Status{ probe_off, ..Default::default() },
+        })
+    }
+
+    /// 14 s with a 5 s disconnection and 2 s with the probe off, leaving 7 valid samples
+    fn records() -> Vec<Record> {
+        vec![
+            reading(0, 95.0, 60.0, false),
+            reading(1, 96.0, 62.0, false),
+            reading(2, 89.0, 70.0, false),
+            reading(3, 87.0, 80.0, false),
+            reading(4, 97.0, 64.0, false),
+            Record::Disconnected{ timestamp: at(5), address: Address([1, 2, 3, 4, 5, 6]) },
+            reading(10, 94.0, 66.0, true),
+            reading(11, 93.0, 66.0, true),
+            reading(12, 95.4, 68.0, false),
+            reading(13, 98.0, 90.0, false),
+        ]
+    }
+
+    #[test]
+    fn from_records() {
+        let s = Summary::from_records(&records(), &DesaturationOptions::default());
+
+        assert_eq!(s.start, at(0));
+        assert_eq!(s.end, at(14));
+        assert_eq!(s.valid_time, Duration::from_secs(7));
+        assert_eq!(s.disconnects, 1);
+        assert_eq!(s.probe_off, 1);
+
+        // 87, 89, 95, 95.4, 96, 97, 98
+        let spo2 = s.spo2.unwrap();
+        assert!((spo2.mean - 657.4 / 7.0).abs() < 1e-4, "mean {}", spo2.mean);
+        assert_eq!(spo2.median, 95.4);
+        assert_eq!(spo2.nadir, 87.0);
+        assert_eq!(spo2.nadir_time, at(3));
+        assert_eq!(spo2.t90, Duration::from_secs(2));
+        assert_eq!(spo2.t88, Duration::from_secs(1));
+        assert!((spo2.ct90 - 200.0 / 7.0).abs() < 1e-4, "ct90 {}", spo2.ct90);
+
+        let histogram: Vec<_> = spo2.histogram.iter().map(|b| (b.spo2, b.time.as_secs())).collect();
+        assert_eq!(histogram, [(87, 1), (89, 1), (95, 2), (96, 1), (97, 1), (98, 1)]);
+
+        // 60, 62, 70, 80, 64, 68, 90
+        let pulse = s.pulse_rate.unwrap();
+        assert_eq!((pulse.min, pulse.max), (60.0, 90.0));
+        assert!((pulse.mean - 494.0 / 7.0).abs() < 1e-4, "mean {}", pulse.mean);
+    }
+}
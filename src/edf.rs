@@ -0,0 +1,566 @@
+//! EDF+ export, for sleep study tools such as EDFbrowser and OSCAR.
+//!
+//! Files are written as continuous EDF+ (`EDF+C`) with one second data records
+//! containing SpO2 and pulse rate at 1 Hz, an optional pleth waveform at
+//! [`EdfOptions::pleth_rate`] and an `EDF Annotations` signal carrying
+//! disconnections, alarms and other events. Periods without a valid reading are
+//! written as zero.
+//!
+//! The record count in the header is updated as each record is written, so files
+//! remain readable if recording is interrupted.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
+use log::{debug, warn};
+use structopt::StructOpt;
+
+use crate::{Error, Measurement, Reading, PlethSample};
+use crate::recording::{self, Record};
+use crate::analysis::STALE_AFTER;
+
+
+/// Data record duration
+const RECORD_DURATION: Duration = Duration::from_secs(1);
+
+/// Annotation signal samples (two bytes each) per data record
+const ANNOTATION_SAMPLES: usize = 64;
+
+/// Offset of the record count header field
+const RECORD_COUNT_OFFSET: u64 = 236;
+
+/// EDF+ export options
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct EdfOptions {
+    /// Patient code (EDF+ patient identification)
+    #[structopt(long, default_value="X")]
+    pub patient_code: String,
+
+    /// Patient name
+    #[structopt(long, default_value="X")]
+    pub patient_name: String,
+
+    /// Patient sex (M, F or X)
+    #[structopt(long, default_value="X")]
+    pub patient_sex: String,
+
+    /// Patient birth date (dd-MMM-yyyy, eg. 02-AUG-1951)
+    #[structopt(long, default_value="X")]
+    pub patient_birthdate: String,
+
+    /// Hospital administration code (EDF+ recording identification)
+    #[structopt(long, default_value="X")]
+    pub admin_code: String,
+
+    /// Technician
+    #[structopt(long, default_value="X")]
+    pub technician: String,
+
+    /// Pleth waveform sample rate (Hz), 0 to omit the waveform
+    #[structopt(long, default_value="100")]
+    pub pleth_rate: usize,
+}
+
+impl Default for EdfOptions {
+    fn default() -> Self {
+        Self{
+            patient_code: "X".to_string(),
+            patient_name: "X".to_string(),
+            patient_sex: "X".to_string(),
+            patient_birthdate: "X".to_string(),
+            admin_code: "X".to_string(),
+            technician: "X".to_string(),
+            pleth_rate: 100,
+        }
+    }
+}
+
+/// Signal definition
+struct Signal {
+    label: &'static str,
+    dimension: &'static str,
+    physical: (f32, f32),
+    digital: (i16, i16),
+    samples: usize,
+}
+
+impl Signal {
+    /// Convert a physical value to the digital range
+    fn digital(&self, v: f32) -> i16 {
+        let (pmin, pmax) = self.physical;
+        let (dmin, dmax) = (self.digital.0 as f32, self.digital.1 as f32);
+
+        let d = dmin + (v.clamp(pmin, pmax) - pmin) * (dmax - dmin) / (pmax - pmin);
+        d.round() as i16
+    }
+}
+
+/// Streaming EDF+ writer.
+///
+/// Measurements must be pushed in time order, data records are written as each
+/// second completes. Call [`EdfWriter::finish`] to write the final partial record.
+pub struct EdfWriter {
+    w: BufWriter<File>,
+    start: SystemTime,
+    signals: Vec<Signal>,
+    pleth_rate: usize,
+
+    /// Index of the record currently being filled
+    record: u64,
+
+    last_reading: Option<Reading>,
+    pleth: Vec<Option<f32>>,
+    last_pleth: Option<f32>,
+    annotations: VecDeque<Vec<u8>>,
+}
+
+impl std::fmt::Debug for EdfWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EdfWriter")
+            .field("start", &self.start)
+            .field("record", &self.record)
+            .finish()
+    }
+}
+
+impl EdfWriter {
+    /// Create a new EDF+ file starting at the provided time (truncated to the second)
+    pub fn create(path: impl AsRef<Path>, start: SystemTime, opts: &EdfOptions) -> Result<Self, Error> {
+        // Align the start to a whole second, EDF start times have one second resolution
+        let since_epoch = start.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs());
+
+        let mut signals = vec![
+            Signal{ label: "SpO2", dimension: "%", physical: (0.0, 100.0), digital: (0, 1000), samples: 1 },
+            Signal{ label: "Pulse", dimension: "bpm", physical: (0.0, 300.0), digital: (0, 3000), samples: 1 },
+        ];
+        if opts.pleth_rate > 0 {
+            signals.push(Signal{ label: "Pleth", dimension: "", physical: (0.0, 127.0), digital: (0, 1270), samples: opts.pleth_rate });
+        }
+        signals.push(Signal{ label: "EDF Annotations", dimension: "", physical: (-1.0, 1.0), digital: (-32768, 32767), samples: ANNOTATION_SAMPLES });
+
+        let f = File::create(path.as_ref())?;
+        debug!("Writing EDF+ to {}", path.as_ref().display());
+
+        let mut w = Self{
+            w: BufWriter::new(f),
+            start,
+            signals,
+            pleth_rate: opts.pleth_rate,
+            record: 0,
+            last_reading: None,
+            pleth: vec![None; opts.pleth_rate],
+            last_pleth: None,
+            annotations: VecDeque::new(),
+        };
+
+        w.write_header(opts)?;
+
+        Ok(w)
+    }
+
+    /// Fetch the recording start time
+    pub fn start(&self) -> SystemTime {
+        self.start
+    }
+
+    /// Add a measurement, writing any data records completed before it
+    pub fn push(&mut self, m: &Measurement) -> Result<(), Error> {
+        let offset = match m.timestamp().duration_since(self.start) {
+            Ok(o) => o,
+            Err(_) => {
+                warn!("Dropping measurement before recording start");
+                return Ok(());
+            }
+        };
+
+        self.advance(offset.as_secs())?;
+
+        match m {
+            Measurement::Reading(r) => self.last_reading = Some(r.clone()),
+            Measurement::Pleth(PlethSample{ value, .. }) if self.pleth_rate > 0 => {
+                let i = (offset.subsec_nanos() as u64 * self.pleth_rate as u64 / 1_000_000_000) as usize;
+                self.pleth[i.min(self.pleth_rate - 1)] = Some(*value);
+            },
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Add an annotation at the provided time, with an optional duration
+    pub fn annotate(&mut self, timestamp: SystemTime, duration: Option<Duration>, text: &str) -> Result<(), Error> {
+        let onset = match timestamp.duration_since(self.start) {
+            Ok(o) => format!("+{}", seconds(o)),
+            Err(e) => format!("-{}", seconds(e.duration())),
+        };
+
+        // TAL: +onset[\x15duration]\x14text\x14\0
+        let mut tal = onset.into_bytes();
+        if let Some(d) = duration {
+            tal.push(0x15);
+            tal.extend(seconds(d).bytes());
+        }
+        tal.push(0x14);
+
+        // Limit annotation text to the space available alongside the time-keeping TAL
+        let max = ANNOTATION_SAMPLES * 2 - tal.len() - 2 - 16;
+        tal.extend(text.bytes().filter(|b| (0x20..0x7F).contains(b)).take(max));
+        tal.extend([0x14, 0x00]);
+
+        self.annotations.push_back(tal);
+
+        Ok(())
+    }
+
+    /// Mark the sensor as disconnected, clearing held readings and annotating the file
+    pub fn disconnected(&mut self, timestamp: SystemTime) -> Result<(), Error> {
+        if let Ok(o) = timestamp.duration_since(self.start) {
+            self.advance(o.as_secs())?;
+        }
+
+        self.last_reading = None;
+        self.last_pleth = None;
+
+        self.annotate(timestamp, None, "Sensor disconnected")
+    }
+
+    /// Add a recording record, measurements are written and connection and alarm events annotated
+    pub fn record(&mut self, r: &Record) -> Result<(), Error> {
+        match r {
+            Record::Reading(v) => self.push(&Measurement::Reading(v.clone())),
+            Record::Pleth(p) => self.push(&Measurement::Pleth(p.clone())),
+            Record::Disconnected{ timestamp, .. } => self.disconnected(*timestamp),
+            Record::Connected{ timestamp, .. } => self.annotate(*timestamp, None, "Sensor connected"),
+            Record::Reconnected{ timestamp, .. } => self.annotate(*timestamp, None, "Sensor reconnected"),
+            Record::Alarm(a) => self.annotate(a.timestamp, None, &format!("Alarm: {}", a)),
+            Record::Device{ timestamp, name, info, .. } => {
+                let mut text = name.clone().unwrap_or_else(|| "Unknown device".to_string());
+                if let Some(fw) = &info.firmware_revision {
+                    text += &format!(" fw {}", fw);
+                }
+                self.annotate(*timestamp, None, &text)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Flush written records to disk
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.w.flush()?;
+        self.w.get_ref().sync_data()?;
+
+        Ok(())
+    }
+
+    /// Write the final record and any pending annotations, then flush
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.write_record()?;
+
+        while !self.annotations.is_empty() {
+            self.write_record()?;
+        }
+
+        self.w.flush()?;
+
+        Ok(())
+    }
+
+    /// Write records until the record with the provided index is current
+    fn advance(&mut self, index: u64) -> Result<(), Error> {
+        while self.record < index {
+            self.write_record()?;
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self) -> Result<(), Error> {
+        let end = self.start + RECORD_DURATION * (self.record as u32 + 1);
+        let mut data = vec![];
+
+        // Hold the latest valid reading until it becomes stale
+        let reading = self.last_reading.as_ref()
+            .filter(|r| r.is_valid())
+            .filter(|r| end.duration_since(r.timestamp).map(|d| d <= STALE_AFTER).unwrap_or(true));
+
+        let spo2 = reading.and_then(|r| r.spo2).unwrap_or(0.0);
+        let pulse = reading.and_then(|r| r.pulse_rate).unwrap_or(0.0);
+
+        for s in &self.signals {
+            match s.label {
+                "SpO2" => data.push(s.digital(spo2)),
+                "Pulse" => data.push(s.digital(pulse)),
+                "Pleth" => {
+                    // Hold the previous sample across missing samples
+                    for v in self.pleth.iter_mut() {
+                        if v.is_none() {
+                            *v = self.last_pleth;
+                        }
+                        self.last_pleth = *v;
+                        data.push(s.digital(v.unwrap_or(0.0)));
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        let mut bytes: Vec<u8> = data.iter().flat_map(|v| v.to_le_bytes()).collect();
+
+        // Annotations, starting with the time-keeping TAL for this record
+        let mut tals = format!("+{}\x14\x14\0", self.record).into_bytes();
+        while let Some(a) = self.annotations.front() {
+            if tals.len() + a.len() > ANNOTATION_SAMPLES * 2 {
+                break;
+            }
+            tals.extend(self.annotations.pop_front().unwrap());
+        }
+        tals.resize(ANNOTATION_SAMPLES * 2, 0);
+        bytes.extend(tals);
+
+        self.w.write_all(&bytes)?;
+
+        self.record += 1;
+        self.pleth.iter_mut().for_each(|v| *v = None);
+
+        // Keep the header record count current so interrupted files remain readable
+        let pos = self.w.stream_position()?;
+        self.w.seek(SeekFrom::Start(RECORD_COUNT_OFFSET))?;
+        self.w.write_all(field(&self.record.to_string(), 8).as_bytes())?;
+        self.w.seek(SeekFrom::Start(pos))?;
+
+        Ok(())
+    }
+
+    fn write_header(&mut self, opts: &EdfOptions) -> Result<(), Error> {
+        let start: DateTime<Local> = self.start.into();
+        let ns = self.signals.len();
+
+        let patient = format!("{} {} {} {}", id(&opts.patient_code), id(&opts.patient_sex),
+            id(&opts.patient_birthdate), id(&opts.patient_name));
+        let recording = format!("Startdate {} {} {} {}", start.format("%d-%b-%Y").to_string().to_uppercase(),
+            id(&opts.admin_code), id(&opts.technician), id(concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION"))));
+
+        let mut h = String::new();
+        h += &field("0", 8);
+        h += &field(&patient, 80);
+        h += &field(&recording, 80);
+        h += &field(&start.format("%d.%m.%y").to_string(), 8);
+        h += &field(&start.format("%H.%M.%S").to_string(), 8);
+        h += &field(&(256 * (ns + 1)).to_string(), 8);
+        h += &field("EDF+C", 44);
+        h += &field("-1", 8);
+        h += &field(&RECORD_DURATION.as_secs().to_string(), 8);
+        h += &field(&ns.to_string(), 4);
+
+        for s in &self.signals { h += &field(s.label, 16); }
+        for s in &self.signals { h += &field(if s.label == "Pleth" { "Pulse oximeter" } else { "" }, 80); }
+        for s in &self.signals { h += &field(s.dimension, 8); }
+        for s in &self.signals { h += &field(&s.physical.0.to_string(), 8); }
+        for s in &self.signals { h += &field(&s.physical.1.to_string(), 8); }
+        for s in &self.signals { h += &field(&s.digital.0.to_string(), 8); }
+        for s in &self.signals { h += &field(&s.digital.1.to_string(), 8); }
+        for _ in &self.signals { h += &field("", 80); }
+        for s in &self.signals { h += &field(&s.samples.to_string(), 8); }
+        for _ in &self.signals { h += &field("", 32); }
+
+        self.w.write_all(h.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// Export a recorded session to EDF+
+pub fn export(records: &[Record], path: impl AsRef<Path>, opts: &EdfOptions) -> Result<(), Error> {
+    // Start at the first measurement, falling back to the first record
+    let start = records.iter()
+        .find(|r| matches!(r, Record::Reading(_) | Record::Pleth(_)))
+        .or_else(|| records.first())
+        .map(|r| r.timestamp())
+        .ok_or_else(|| Error::InvalidRecording("empty recording".to_string()))?;
+
+    recording::check_dated(start)?;
+
+    // Omit the waveform signal for recordings without pleth samples
+    let mut opts = opts.clone();
+    if !records.iter().any(|r| matches!(r, Record::Pleth(_))) {
+        opts.pleth_rate = 0;
+    }
+
+    let mut w = EdfWriter::create(path, start, &opts)?;
+
+    for r in records {
+        w.record(r)?;
+    }
+
+    w.finish()
+}
+
+/// Format a duration as decimal seconds, trimming trailing zeros
+fn seconds(d: Duration) -> String {
+    let s = format!("{:.3}", d.as_secs_f64());
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Format an EDF+ identification subfield, spaces are replaced and empty fields marked unknown
+fn id(s: &str) -> String {
+    match s.trim() {
+        "" => "X".to_string(),
+        s => s.replace(' ', "_"),
+    }
+}
+
+/// Pad or truncate an ASCII header field to the provided width
+fn field(s: &str, width: usize) -> String {
+    let s: String = s.chars()
+        .map(|c| if (' '..='~').contains(&c) { c } else { '_' })
+        .take(width)
+        .collect();
+    format!("{:<width$}", s, width = width)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Address;
+    use crate::alarm::{AlarmEvent, Condition, Priority, AlarmState};
+    use crate::tests::temp_path;
+    use super::*;
+
+    const START: u64 = 1_760_745_600;
+
+    fn at(ms: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(START * 1000 + ms)
+    }
+
+    fn reading(ms: u64, spo2: f32, pulse_rate: f32) -> Record {
+        Record::Reading(Reading{
+            timestamp: at(ms),
+            spo2: Some(spo2),
+            pulse_rate: Some(pulse_rate),
+            perfusion_index: None,
+            status: Default::default(),
+        })
+    }
+
+    /// Fetch a trimmed ASCII header field
+    fn text(data: &[u8], offset: usize, width: usize) -> &str {
+        std::str::from_utf8(&data[offset..offset + width]).unwrap().trim_end()
+    }
+
+    /// Fetch one field for each signal
+    fn fields(data: &[u8], ns: usize, offset: usize, width: usize) -> Vec<&str> {
+        (0..ns).map(|i| text(data, 256 + ns * offset + i * width, width)).collect()
+    }
+
+    fn samples(data: &[u8]) -> Vec<i16> {
+        data.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    fn annotations(tals: &[u8]) -> Vec<u8> {
+        assert_eq!(tals.len(), ANNOTATION_SAMPLES * 2);
+        let end = tals.iter().rposition(|b| *b != 0).map(|i| i + 2).unwrap_or(0);
+        tals[..end].to_vec()
+    }
+
+    #[test]
+    fn export_session() {
+        let path = temp_path("export-session.edf");
+        let address = Address([1, 2, 3, 4, 5, 6]);
+
+        let records = vec![
+            reading(200, 97.0, 72.0),
+            Record::Pleth(PlethSample{ timestamp: at(600), value: 50.0, beat: false }),
+            reading(1200, 95.0, 70.0),
+            Record::Alarm(AlarmEvent{ timestamp: at(1500), condition: Condition::LowSpo2, priority: Priority::Medium, state: AlarmState::Active, value: Some(89.0) }),
+            Record::Disconnected{ timestamp: at(2500), address },
+        ];
+
+        let opts = EdfOptions{ pleth_rate: 4, ..Default::default() };
+        export(&records, &path, &opts).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Header, 256 bytes plus 256 per signal
+        let ns = 4;
+        assert_eq!(text(&data, 0, 8), "0");
+        assert_eq!(text(&data, 184, 8), "1280");
+        assert_eq!(text(&data, 192, 44), "EDF+C");
+        assert_eq!(text(&data, RECORD_COUNT_OFFSET as usize, 8), "3");
+        assert_eq!(text(&data, 244, 8), "1");
+        assert_eq!(text(&data, 252, 4), "4");
+
+        assert_eq!(fields(&data, ns, 0, 16), ["SpO2", "Pulse", "Pleth", "EDF Annotations"]);
+        assert_eq!(fields(&data, ns, 96, 8), ["%", "bpm", "", ""]);
+        assert_eq!(fields(&data, ns, 104, 8), ["0", "0", "0", "-1"]);
+        assert_eq!(fields(&data, ns, 112, 8), ["100", "300", "127", "1"]);
+        assert_eq!(fields(&data, ns, 120, 8), ["0", "0", "0", "-32768"]);
+        assert_eq!(fields(&data, ns, 128, 8), ["1000", "3000", "1270", "32767"]);
+        assert_eq!(fields(&data, ns, 216, 8), ["1", "1", "4", "64"]);
+
+        // Three one second records, the last written by finish
+        let header = 256 * (ns + 1);
+        let size = (1 + 1 + 4 + ANNOTATION_SAMPLES) * 2;
+        assert_eq!(data.len(), header + 3 * size);
+
+        let records: Vec<_> = data[header..].chunks(size).collect();
+
+        // Pleth samples are held across missing samples until the disconnection
+        assert_eq!(samples(&records[0][..12]), [970, 720, 0, 0, 500, 500]);
+        assert_eq!(samples(&records[1][..12]), [950, 700, 500, 500, 500, 500]);
+        assert_eq!(samples(&records[2][..12]), [0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(annotations(&records[0][12..]), b"+0\x14\x14\0");
+        assert_eq!(annotations(&records[1][12..]), b"+1\x14\x14\0+1.5\x14Alarm: medium priority low SpO2 alarm active (89)\x14\0");
+        assert_eq!(annotations(&records[2][12..]), b"+2\x14\x14\0+2.5\x14Sensor disconnected\x14\0");
+    }
+
+    #[test]
+    fn record_count() {
+        let path = temp_path("record-count.edf");
+
+        let opts = EdfOptions{ pleth_rate: 0, ..Default::default() };
+        let mut w = EdfWriter::create(&path, at(300), &opts).unwrap();
+        assert_eq!(w.start(), at(0));
+
+        for s in 0..3 {
+            if let Record::Reading(r) = reading(s * 1000 + 500, 96.0, 60.0) {
+                w.push(&Measurement::Reading(r)).unwrap();
+            }
+        }
+
+        // Completed records are counted before the file is finished
+        w.flush().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(text(&data, RECORD_COUNT_OFFSET as usize, 8), "2");
+        assert_eq!(data.len(), 256 * 4 + 2 * (2 + ANNOTATION_SAMPLES) * 2);
+
+        w.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(text(&data, RECORD_COUNT_OFFSET as usize, 8), "3");
+        assert_eq!(data.len(), 256 * 4 + 3 * (2 + ANNOTATION_SAMPLES) * 2);
+    }
+
+    #[test]
+    fn undated() {
+        let path = temp_path("undated.edf");
+
+        // Reading logs with only elapsed times can't be dated
+        let records = vec![Record::Reading(Reading{
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(500),
+            spo2: Some(97.0),
+            pulse_rate: Some(72.0),
+            perfusion_index: None,
+            status: Default::default(),
+        })];
+
+        let e = export(&records, &path, &EdfOptions::default()).unwrap_err();
+        assert!(matches!(e, Error::InvalidRecording(_)));
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,86 @@
+//! Device Information Service (0x180A) readout
+
+use log::{debug, warn};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::{Connection, Service};
+use crate::protocol::uuid16;
+
+
+/// Device Information Service
+pub const SERVICE: Uuid = uuid16(0x180A);
+
+/// Manufacturer Name String characteristic
+pub const MANUFACTURER_NAME: Uuid = uuid16(0x2A29);
+/// Model Number String characteristic
+pub const MODEL_NUMBER: Uuid = uuid16(0x2A24);
+/// Serial Number String characteristic
+pub const SERIAL_NUMBER: Uuid = uuid16(0x2A25);
+/// Hardware Revision String characteristic
+pub const HARDWARE_REVISION: Uuid = uuid16(0x2A27);
+/// Firmware Revision String characteristic
+pub const FIRMWARE_REVISION: Uuid = uuid16(0x2A26);
+/// Software Revision String characteristic
+pub const SOFTWARE_REVISION: Uuid = uuid16(0x2A28);
+
+
+/// Device information, fields are `None` where not exposed by the device
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hardware_revision: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_revision: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub software_revision: Option<String>,
+}
+
+impl DeviceInfo {
+    /// Read device information from a connected device, characteristics that are
+    /// missing or fail to read are left empty
+    pub async fn read(conn: &dyn Connection, services: &[Service]) -> Self {
+        let mut info = DeviceInfo::default();
+
+        let fields = [
+            (MANUFACTURER_NAME, &mut info.manufacturer),
+            (MODEL_NUMBER, &mut info.model),
+            (SERIAL_NUMBER, &mut info.serial),
+            (HARDWARE_REVISION, &mut info.hardware_revision),
+            (FIRMWARE_REVISION, &mut info.firmware_revision),
+            (SOFTWARE_REVISION, &mut info.software_revision),
+        ];
+
+        for (uuid, field) in fields {
+            let c = match crate::transport::find_characteristic(services, SERVICE, uuid) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            match conn.read(c).await {
+                Ok(v) => *field = Some(string(&v)),
+                Err(e) => warn!("Failed to read {}: {}", uuid, e),
+            }
+        }
+
+        debug!("Device info: {:?}", info);
+
+        info
+    }
+
+    /// Check whether no information is available
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Decode a UTF-8 string characteristic, trimming trailing nulls and whitespace
+fn string(v: &[u8]) -> String {
+    String::from_utf8_lossy(v).trim_end_matches(|c: char| c == '\0' || c.is_whitespace()).to_string()
+}
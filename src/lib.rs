@@ -1,30 +1,85 @@
 
-use std::time::{Duration, Instant};
+use std::time::SystemTime;
 
-use bluer::AdapterEvent;
-use futures::stream::StreamExt;
-use log::{trace, debug, info, error};
-
-use btleplug::platform::{Peripheral, Manager};
-use btleplug::api::{ScanFilter, Manager as _, Central as _, Peripheral as _, CentralEvent};
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use log::{trace, debug, info, warn};
 
 use structopt::StructOpt;
 
+pub mod transport;
+pub use transport::{Transport, Connection, Backend, Adaptor, Address, AddressType, DiscoveredDevice, ScanFilter, Service, Characteristic};
+
+pub mod reading;
+pub use reading::{Reading, Status, PlethSample, Measurement};
+
+pub mod protocol;
+pub use protocol::{Protocol, ProtocolDecoder, Decoder, Registry};
+
+pub mod info;
+pub use info::DeviceInfo;
+
+pub mod matching;
+pub use matching::MatchOptions;
+
+pub mod supervisor;
+pub use supervisor::{Supervisor, ReconnectOptions, Event, DeviceDetails};
+
+pub mod manager;
+pub use manager::{SensorManager, ManagerOptions, ManagedSensor};
+
+mod lines;
+
+pub mod recording;
+pub use recording::{Record, Recorder};
+
+pub mod sink;
+pub use sink::{ReadingSink, SinkFormat, SinkOptions};
+
+pub mod replay;
+pub use replay::{Replay, ReplayOptions};
+
+pub mod edf;
+pub use edf::{EdfWriter, EdfOptions};
+
+pub mod oscar;
+
+pub mod alarm;
+pub use alarm::{AlarmEngine, AlarmOptions, AlarmEvent};
+
+pub mod quality;
+pub use quality::{QualityAssessor, QualityOptions, QualityScore};
+
+pub mod analysis;
+
+pub mod report;
+
 
 #[derive(Debug)]
 pub struct Sensor {
-    p: Peripheral,
+    device: DiscoveredDevice,
+    conn: Box<dyn Connection>,
+    services: Vec<Service>,
+    info: DeviceInfo,
+    protocol: Option<Protocol>,
+    features: Option<protocol::plx::Features>,
 }
 
 #[derive(Debug, PartialEq, Clone, StructOpt)]
 pub struct Options {
-    /// BLE adaptor to use for discovery and connection
+    /// BLE backend to use (bluer or btleplug)
+    #[structopt(long, default_value="bluer")]
+    pub backend: Backend,
+
+    /// BLE adaptor to use for discovery and connection, by index, name (hci1) or controller address
     #[structopt(long, default_value="0")]
-    pub adaptor: usize,
+    pub adaptor: Adaptor,
+
+    /// Power on the adaptor if it is powered down
+    #[structopt(long)]
+    pub power_on: bool,
 
-    /// Device local name
-    #[structopt(long, default_value="J1")]
-    pub local_name: String,
+    #[structopt(flatten)]
+    pub matching: MatchOptions,
 
     /// Timeout for search operation
     #[structopt(long, default_value="10s")]
@@ -33,31 +88,62 @@ pub struct Options {
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[cfg(feature = "btleplug")]
     #[error("Btleplug: {0}")]
     Ble(btleplug::Error),
 
+    #[cfg(feature = "bluer")]
     #[error("Bluer: {0}")]
     Bluer(bluer::Error),
 
-    #[error("No matching adaptor for index {0}")]
-    NoMatchingAdaptor(usize),
+    #[error("Backend {0} not enabled in this build")]
+    BackendUnavailable(Backend),
+
+    #[error("No matching adaptor for {0}")]
+    NoMatchingAdaptor(Adaptor),
+
+    #[error("Adaptor {0} is powered off (see --power-on)")]
+    AdaptorPoweredOff(String),
 
     #[error("No device found")]
     NoDeviceFound,
-    
+
     #[error("Failed to connect to device")]
     ConnectFailed,
 
     #[error("Failed to discover services for device")]
     NoServicesFound,
+
+    #[error("No characteristic found with UUID {0}")]
+    NoCharacteristic(uuid::Uuid),
+
+    #[error("No supported protocol found for device")]
+    UnsupportedDevice,
+
+    #[error("Invalid packet: {0}")]
+    InvalidPacket(String),
+
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+
+    #[error("Invalid recording: {0}")]
+    InvalidRecording(String),
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(feature = "btleplug")]
 impl From<btleplug::Error> for Error {
     fn from(e: btleplug::Error) -> Self {
         Error::Ble(e)
     }
 }
 
+#[cfg(feature = "bluer")]
 impl From<bluer::Error> for Error {
     fn from(e: bluer::Error) -> Self {
         Error::Bluer(e)
@@ -65,221 +151,267 @@ impl From<bluer::Error> for Error {
 }
 
 impl Sensor {
-    pub async fn connect(opts: Options) -> Result<Self, Error> {
-
-        // Create bluez session
-        let session = bluer::Session::new().await?;
+    /// Scan for nearby devices using the backend selected in [`Options`]
+    pub async fn scan(opts: Options) -> Result<Vec<DiscoveredDevice>, Error> {
+        let transport = transport::open(&opts).await?;
 
-        // Connect to default adaptor
-        let adapter = session.default_adapter().await?;
-
-        // Start device discovery
-        let mut events = adapter.discover_devices().await?;
+        Self::scan_with(transport.as_ref(), &opts).await
+    }
 
-        let now = Instant::now();
-        while now.elapsed() < *opts.search_timeout {
+    /// Scan for nearby devices using the provided [`Transport`] for [`Options::search_timeout`],
+    /// returning every device seen (matching or not) ordered by signal strength
+    pub async fn scan_with(transport: &dyn Transport, opts: &Options) -> Result<Vec<DiscoveredDevice>, Error> {
+        let devices = transport.scan(&ScanFilter::default()).await?;
 
-            // Wait for incoming events
-            let evt = match tokio::time::timeout(Duration::from_millis(100), events.next() ).await {
-                Ok(Some(e)) => e,
-                // TODO: separate BLE errors from timeout
-                _ => continue,
-            };
+        let mut found: Vec<DiscoveredDevice> = vec![];
 
-            match evt {
-                AdapterEvent::DeviceAdded(addr) => {
-                    trace!("Device added: {:?}", addr);
+        // Collect devices until the timeout expires, discovery stops once the scan stream is dropped
+        let _ = tokio::time::timeout(*opts.search_timeout, devices.for_each(|d| {
+            debug!("Device found: {:?}", d);
 
-                    let mut device = adapter.device(addr)?;
+            // Keep the most recent information for each device
+            match found.iter_mut().find(|f| f.address == d.address) {
+                Some(f) => *f = d,
+                None => found.push(d),
+            }
 
-                    let name = device.name().await?;
-                    match name {
-                        Some(name) if name.starts_with(&opts.local_name) => {
-                            info!("Matching device!: {:?}", device);
+            async {}
+        })).await;
 
-                            device = adapter.connect_device(addr, bluer::AddressType::LeRandom).await?;
+        found.sort_by_key(|d| std::cmp::Reverse(d.rssi));
 
-                            info!("Device connected!");
+        Ok(found)
+    }
 
-                            let services = device.services().await?;
+    /// Connect to a sensor using the backend selected in [`Options`]
+    pub async fn connect(opts: Options) -> Result<Self, Error> {
+        let transport = transport::open(&opts).await?;
 
-                            info!("Services: {:?}", services);
+        Self::connect_with(transport.as_ref(), &opts).await
+    }
 
-                        },
-                        _ => (),
-                    }
-                },
-                _ => debug!("Event: {:?}", evt),
-            }
-        }
+    /// Connect to a sensor using the provided [`Transport`]
+    pub async fn connect_with(transport: &dyn Transport, opts: &Options) -> Result<Self, Error> {
+        // Start device discovery
+        let devices = transport.scan(&opts.matching.scan_filter()).await?;
 
+        // Search for a matching device, discovery stops once the scan stream is dropped
+        let device = find_device(opts, devices).await?;
 
-        todo!()
+        Self::connect_device(transport, device).await
     }
 
-    #[cfg(nope)]
-    pub async fn connect(opts: Options) -> Result<Self, Error> {
-
-        // Connect to BLE manager
-        let manager = Manager::new().await?;
+    /// Connect to a previously discovered device using the provided [`Transport`]
+    pub async fn connect_device(transport: &dyn Transport, mut device: DiscoveredDevice) -> Result<Self, Error> {
+        let conn = transport.connect(&device).await?;
 
-        // Fetch adapter for central role
-        let adapters = manager.adapters().await?;
-        let central = match adapters.into_iter().nth(opts.adaptor) {
-            Some(c) => c,
-            None => {
-                return Err(Error::NoMatchingAdaptor(opts.adaptor));
-            }
-        };
-
-        // Setup event channel
-        let mut events = central.events().await?;
-
-        // Start scanning
-        debug!("Starting scan for BLE devices");
-        central.start_scan(ScanFilter::default()).await?;
-
-        tokio::time::sleep(Duration::from_millis(1000)).await;
-
-        let mut device = None;
-        let mut pid = None;
-
-        let now = Instant::now();
-        while now.elapsed() < *opts.search_timeout {
-
-            // Wait for incoming events
-            let evt = match tokio::time::timeout(Duration::from_millis(100), events.next() ).await {
-                Ok(Some(e)) => e,
-                // TODO: separate BLE errors from timeout
-                _ => continue,
-            };
-
-            match (&evt, &pid) {
-                (CentralEvent::DeviceDiscovered(id), None) => {
-                    // Fetch peripheral information
-                    let periph = central.peripheral(&id).await?;
-                    trace!("Discovered peripheral {:?}: {:?}", id, periph);
-
-                    // Read out properties
-                    let props = match periph.properties().await {
-                        Ok(Some(p)) => p,
-                        Ok(None) => {
-                            error!("Failed to fetch properties for peripheral {:?}", id);
-                            continue;
-                        },
-                        Err(e) => {
-                            error!("Failed to fetch properties for peripheral {:?}: {:?}", periph, e);
-                            continue;
-                        },
-                    };
-                    trace!("Properties: {:?}", props);
-
-                    // TODO: match filters / return device
-                    match &props.local_name {
-                        Some(l) if l.starts_with(&opts.local_name) => {
-                            info!("Matching device!: {:?}", props);
-
-                            #[cfg(nope)]
-                            if !periph.is_connected().await? {
-                                periph.connect().await?;
-                            } else {
-                                periph.discover_services().await?;
-                            }
-
-                            device = Some((periph, props));
-                            pid = Some(id.clone());
-
-                            break;
-                        },
-                        _ => (),
-                    }
-                },
-                (CentralEvent::DeviceConnected(id), Some(pid)) if id == pid => {
-                    debug!("Connected event for {:?}", id);
-
-                    device.as_ref().unwrap().0.discover_services().await?;
-                },
-                (CentralEvent::DeviceDisconnected(id), Some(pid)) if id == pid => {
-                    debug!("Disconnected event for {:?}", id)  
-                },
-                (CentralEvent::ManufacturerDataAdvertisement{id, manufacturer_data}, Some(pid)) if id == pid => {
-                    debug!("Manufacturer info for {:?}: {:?}", id, manufacturer_data);
-                }
-                (CentralEvent::ServicesAdvertisement{id, services}, Some(pid)) if id == pid => {
-                    debug!("Service advertisement for {:?}: {:?}", id, services);
-                },
-                (CentralEvent::ServiceDataAdvertisement{id, service_data}, Some(pid)) if id == pid => {
-                    debug!("Service data advertisement for {:?}: {:?}", id, service_data);
-                },
-                (CentralEvent::DeviceUpdated(id), Some(pid)) if id == pid => {
-                    debug!("Updated event for {:?}", id);
-                },
-                _ => {
-                    trace!("Unhandled event: {:?}", evt)
-                }
-            };
+        // Record the address type used where the backend reports it
+        if let Some(t) = conn.address_type() {
+            device.address_type = Some(t);
         }
 
-        drop(events);
-
-        // Stop scanning
-        central.stop_scan().await?;
-
-        #[cfg(nope)]
-        let (device, props) = match &pid {
-            Some(pid) => {
-                let periph = central.peripheral(pid).await?;
-                let props = periph.properties().await?.unwrap();
-                (periph, props)
-            },
-            None => return Err(Error::NoDeviceFound),
-        };
-
-        // Check we found a useful device
-        let (device, props) = match device {
-            Some(d) => d,
-            None => return Err(Error::NoDeviceFound)
-        };
-
-        // Ensure we're connected
-        if !device.is_connected().await? {
-            debug!("Connecting to device");
-            device.connect().await?;
-
-            if !device.is_connected().await? {
-                error!("Failed to connect to device");
-                return Err(Error::ConnectFailed)
-            }
+        match device.address_type {
+            Some(t) => info!("Device connected! ({} address)", t),
+            None => info!("Device connected!"),
         }
-        debug!("Connected to peripheral: {:?}", props.local_name);
-
 
         // Discover services then characteristics
         debug!("Discovering services");
 
-        device.discover_services().await?;
-        #[cfg(nope)]
-        if device.services().is_empty() {
+        let services = conn.discover().await?;
+        if services.is_empty() {
             return Err(Error::NoServicesFound)
         }
 
-        for service in device.services() {
+        for service in &services {
             debug!("Service: {}, primary: {}", service.uuid, service.primary);
-            
-            for char in service.characteristics {
+
+            for char in &service.characteristics {
                 debug!("  - {:?}", char);
             }
         }
 
-        for char in device.characteristics() {
-            debug!("Characteristic: {:?}", char);
-        }
+        // Read device information, where available
+        let info = DeviceInfo::read(conn.as_ref(), &services).await;
+
+        // Read PLX features, where available
+        let features = protocol::plx::Features::read(conn.as_ref(), &services).await;
 
-        // TODO: start listener task, subscribe to notifications? though this could also be part of Sensor API
+        // Match services to a registered protocol
+        let protocol = Registry::global().detect(device.name.as_deref(), &services);
+        match &protocol {
+            Some(p) => info!("Using protocol: {}", p.name()),
+            None => warn!("No supported protocol found, readings will be unavailable"),
+        }
 
-        // Return device
         Ok(Self{
-            p: device,
+            device,
+            conn,
+            services,
+            info,
+            protocol,
+            features,
         })
     }
+
+    /// Fetch discovery information for the connected device
+    pub fn device(&self) -> &DiscoveredDevice {
+        &self.device
+    }
+
+    /// Fetch the underlying device connection
+    pub fn connection(&self) -> &dyn Connection {
+        self.conn.as_ref()
+    }
+
+    /// Fetch services discovered on connection
+    pub fn services(&self) -> &[Service] {
+        &self.services
+    }
+
+    /// Fetch device information read on connection
+    pub fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// Fetch Pulse Oximeter Service features read on connection, where supported
+    pub fn plx_features(&self) -> Option<&protocol::plx::Features> {
+        self.features.as_ref()
+    }
+
+    /// Fetch the protocol detected on connection
+    pub fn protocol(&self) -> Option<&Protocol> {
+        self.protocol.as_ref()
+    }
+
+    /// Subscribe to measurement notifications, returning a stream of all decoded measurements.
+    ///
+    /// Each call creates a new subscription, the stream ends when the sensor disconnects.
+    pub async fn measurements(&self) -> Result<BoxStream<'static, Measurement>, Error> {
+        let protocol = self.protocol.as_ref().ok_or(Error::UnsupportedDevice)?;
+
+        let notifications = self.conn.subscribe(&protocol.characteristic).await?;
+
+        let mut decoder = protocol.decoder();
+        let measurements = notifications.flat_map(move |data| {
+            trace!("Notification: {:02x?}", data);
+            stream::iter(decoder.decode(SystemTime::now(), &data))
+        });
+
+        Ok(Box::pin(measurements))
+    }
+
+    /// Subscribe to numeric SpO2 / pulse rate readings
+    pub async fn readings(&self) -> Result<BoxStream<'static, Reading>, Error> {
+        let readings = self.measurements().await?.filter_map(|m| async move {
+            match m {
+                Measurement::Reading(r) => Some(r),
+                _ => None,
+            }
+        });
+
+        Ok(Box::pin(readings))
+    }
+
+    /// Subscribe to plethysmograph waveform samples, where supported by the sensor protocol
+    pub async fn waveform(&self) -> Result<BoxStream<'static, PlethSample>, Error> {
+        let samples = self.measurements().await?.filter_map(|m| async move {
+            match m {
+                Measurement::Pleth(p) => Some(p),
+                _ => None,
+            }
+        });
+
+        Ok(Box::pin(samples))
+    }
+}
+
+/// Search a stream of discovered devices for the first device matching
+/// [`Options::matching`], returning [`Error::NoDeviceFound`] if no device is found
+/// before [`Options::search_timeout`] expires.
+pub async fn find_device<S>(opts: &Options, devices: S) -> Result<DiscoveredDevice, Error>
+where
+    S: Stream<Item=DiscoveredDevice>,
+{
+    let mut devices = Box::pin(devices);
+
+    let search = async {
+        while let Some(d) = devices.next().await {
+            trace!("Device found: {:?}", d);
+
+            if opts.matching.matches(&d) {
+                info!("Matching device!: {} ({})", d.address, d.name.as_deref().unwrap_or("unnamed"));
+                return Ok(d);
+            }
+        }
+
+        // Scan ended without finding a device
+        Err(Error::NoDeviceFound)
+    };
+
+    match tokio::time::timeout(*opts.search_timeout, search).await {
+        Ok(r) => r,
+        Err(_) => Err(Error::NoDeviceFound),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+
+    use crate::transport::mock::{MockDevice, MockTransport};
+    use super::*;
+
+    /// Path for a temporary test file, unique to the test process and name
+    pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("spo2-test-{}-{}", std::process::id(), name))
+    }
+
+    fn options() -> Options {
+        Options::from_iter(&["spo2"])
+    }
+
+    fn device(address: u8, name: &str) -> DiscoveredDevice {
+        DiscoveredDevice{ address: Address([address; 6]), name: Some(name.to_string()), ..Default::default() }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn find_device_returns_first_match() {
+        let devices = stream::iter(vec![device(1, "Other"), device(2, "J1 A"), device(3, "J1 B")]);
+
+        let d = find_device(&options(), devices).await.unwrap();
+        assert_eq!(d.address, Address([2; 6]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn find_device_times_out() {
+        let devices = stream::iter(vec![device(1, "Other")]).chain(stream::pending());
+
+        let start = tokio::time::Instant::now();
+        assert!(matches!(find_device(&options(), devices).await, Err(Error::NoDeviceFound)));
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_with_matching_device() {
+        let mut a = MockDevice::berrymed(Address([1; 6]));
+        a.advertise_after = Duration::from_secs(3);
+        let t = MockTransport::new(vec![MockDevice::new(Address([2; 6]), "Other"), a]);
+
+        let s = Sensor::connect_with(&t, &options()).await.unwrap();
+        assert_eq!(s.device().address, Address([1; 6]));
+        assert_eq!(s.protocol().map(|p| p.name()), Some("BerryMed serial (J1)"));
+        assert!(t.is_connected(Address([1; 6])));
+        assert!(!t.is_connected(Address([2; 6])));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_with_no_device_found() {
+        let mut a = MockDevice::new(Address([1; 6]), "J1 A");
+        a.advertise_after = Duration::from_secs(30);
+        let t = MockTransport::new(vec![a]);
+
+        assert!(matches!(Sensor::connect_with(&t, &options()).await, Err(Error::NoDeviceFound)));
+        assert_eq!(t.connections(Address([1; 6])), 0);
+    }
 }
@@ -0,0 +1,79 @@
+//! Line-oriented file output and input, shared by session recordings and reading logs.
+//!
+//! Each line is formatted in full before it is written, so a process killed mid-write
+//! leaves at most one partial line at the end of the file. Readers ignore a final
+//! line that fails to parse for the same reason.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use log::warn;
+
+use crate::Error;
+
+
+/// Buffered line writer
+#[derive(Debug)]
+pub(crate) struct LineWriter {
+    w: BufWriter<File>,
+}
+
+impl LineWriter {
+    /// Create (or truncate) a file for writing
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let f = File::create(path)?;
+
+        Ok(Self{ w: BufWriter::new(f) })
+    }
+
+    /// Write a complete line, lines are buffered until [`LineWriter::flush`] is called
+    /// (or the writer is dropped)
+    pub fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        let mut line = line.as_bytes().to_vec();
+        line.push(b'\n');
+
+        self.w.write_all(&line)?;
+
+        Ok(())
+    }
+
+    /// Flush buffered lines to disk
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.w.flush()?;
+        self.w.get_ref().sync_data()?;
+
+        Ok(())
+    }
+}
+
+/// Read the non-empty lines of a file, with their (1-based) line numbers
+pub(crate) fn read(path: &Path) -> Result<Vec<(usize, String)>, Error> {
+    let f = File::open(path)?;
+
+    let lines = BufReader::new(f).lines().collect::<Result<Vec<_>, _>>()?;
+
+    Ok(lines.into_iter()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| (i + 1, l))
+        .collect())
+}
+
+/// Parse lines, ignoring a final line that fails to parse as truncated
+pub(crate) fn parse<T, F>(lines: &[(usize, String)], mut parse: F) -> Result<Vec<T>, Error>
+where
+    F: FnMut(&str) -> Result<T, String>,
+{
+    let mut values = vec![];
+
+    for (n, (i, line)) in lines.iter().enumerate() {
+        match parse(line) {
+            Ok(v) => values.push(v),
+            Err(e) if n == lines.len() - 1 => warn!("Ignoring truncated final line {}: {}", i, e),
+            Err(e) => return Err(Error::InvalidRecording(format!("line {}: {}", i, e))),
+        }
+    }
+
+    Ok(values)
+}
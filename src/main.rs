@@ -1,27 +1,234 @@
 
-use log::{info, error};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use futures::stream::{BoxStream, StreamExt};
+use log::{info, warn, error};
+use tokio::sync::mpsc;
 
 use structopt::StructOpt;
 use simplelog::{TermLogger, LevelFilter, ConfigBuilder, TerminalMode, ColorChoice};
 
-use spo2::{Sensor, Options};
+use spo2::{Sensor, Supervisor, SensorManager, Options, ReconnectOptions, ManagerOptions, Event, Measurement, Reading, Recorder, Record, Replay, ReplayOptions, ReadingSink, SinkFormat, SinkOptions, EdfWriter, EdfOptions, AlarmEngine, AlarmOptions, AlarmEvent, QualityAssessor, QualityOptions, Address, Error};
+use spo2::analysis::{Summary, DesaturationOptions};
+use spo2::{edf, oscar, quality, recording, report};
+use spo2::alarm::{AlarmState, Priority};
 
 
 #[derive(Clone, PartialEq, Debug, StructOpt)]
 pub struct Config {
-
-    #[structopt(flatten)]
-    pub options: Options,
+    #[structopt(subcommand)]
+    pub command: Command,
 
     /// Application log level
     #[structopt(long, default_value = "info")]
     pub log_level: LevelFilter,
 }
 
+#[derive(Clone, PartialEq, Debug, StructOpt)]
+#[allow(clippy::large_enum_variant)]
+pub enum Command {
+    /// List nearby devices seen within --search-timeout
+    Scan {
+        #[structopt(flatten)]
+        options: Options,
+    },
+    /// Connect to a sensor and print live readings
+    Monitor {
+        #[structopt(flatten)]
+        options: Options,
+
+        #[structopt(flatten)]
+        reconnect: ReconnectOptions,
+
+        /// Monitor every matching sensor rather than the first found
+        #[structopt(long)]
+        all: bool,
+
+        #[structopt(flatten)]
+        manager: ManagerOptions,
+
+        #[structopt(flatten)]
+        alarms: AlarmOptions,
+
+        #[structopt(flatten)]
+        quality: QualityOptions,
+    },
+    /// Connect to a sensor and record the session to a file
+    Record {
+        #[structopt(flatten)]
+        options: Options,
+
+        #[structopt(flatten)]
+        reconnect: ReconnectOptions,
+
+        /// Output format, session recordings (replayable), csv / jsonl reading logs or edf
+        #[structopt(long, default_value="session")]
+        format: RecordFormat,
+
+        #[structopt(flatten)]
+        sink: SinkOptions,
+
+        #[structopt(flatten)]
+        edf: EdfOptions,
+
+        #[structopt(flatten)]
+        alarms: AlarmOptions,
+
+        #[structopt(flatten)]
+        quality: QualityOptions,
+
+        /// Recording file to write
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Play back a recorded session or reading log, printing readings as they were received
+    Replay {
+        #[structopt(flatten)]
+        replay: ReplayOptions,
+
+        #[structopt(flatten)]
+        quality: QualityOptions,
+
+        /// Recording file to read
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Summarise a recorded session or reading log: SpO2 and pulse statistics, T90 and ODI
+    Report {
+        /// Output format, text, json or html
+        #[structopt(long, default_value="text")]
+        format: ReportFormat,
+
+        #[structopt(flatten)]
+        desaturation: DesaturationOptions,
+
+        #[structopt(flatten)]
+        quality: QualityOptions,
+
+        /// Write the report to a file rather than stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Recording file to read
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Convert a recorded session for use with other tools
+    Export {
+        /// Output format, edf or oscar (.spoR, for import alongside CPAP data)
+        #[structopt(long, default_value="edf")]
+        format: ExportFormat,
+
+        #[structopt(flatten)]
+        edf: EdfOptions,
+
+        #[structopt(flatten)]
+        quality: QualityOptions,
+
+        /// Recording file to read
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Output file to write
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Connect to a sensor and print GATT services, characteristics and device information
+    Info {
+        #[structopt(flatten)]
+        options: Options,
+    },
+}
+
+/// Output format for the record command
+#[derive(Clone, PartialEq, Debug)]
+pub enum RecordFormat {
+    /// Full session recording, including waveform and connection events
+    Session,
+    /// Reading log
+    Readings(SinkFormat),
+    /// EDF+ file
+    Edf,
+}
+
+impl FromStr for RecordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "session" => Ok(RecordFormat::Session),
+            "edf" => Ok(RecordFormat::Edf),
+            _ => s.parse().map(RecordFormat::Readings)
+                .map_err(|_| format!("Unrecognised format '{}' (expected session, csv, jsonl or edf)", s)),
+        }
+    }
+}
+
+/// Output format for the report command
+#[derive(Clone, PartialEq, Debug)]
+pub enum ReportFormat {
+    Text,
+    Json,
+    /// Self-contained HTML with trend charts
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(format!("Unrecognised format '{}' (expected text, json or html)", s)),
+        }
+    }
+}
+
+/// Output format for the export command
+#[derive(Clone, PartialEq, Debug)]
+pub enum ExportFormat {
+    /// EDF+ file
+    Edf,
+    /// OSCAR compatible CMS50 .spoR file
+    Oscar,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "edf" => Ok(ExportFormat::Edf),
+            "oscar" | "spor" => Ok(ExportFormat::Oscar),
+            _ => Err(format!("Unrecognised format '{}' (expected edf or oscar)", s)),
+        }
+    }
+}
+
+/// Process exit codes
+mod exit {
+    /// General failure
+    pub const FAILURE: u8 = 1;
+    /// No matching device found
+    pub const NO_DEVICE: u8 = 3;
+    /// Failed to connect to or communicate with a device
+    pub const CONNECTION: u8 = 4;
+    /// Sensor connection lost and reconnection attempts exhausted
+    pub const CONNECTION_LOST: u8 = 5;
+    /// Failed to read or write a file
+    pub const IO: u8 = 6;
+}
 
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     // Parse command line arguments
     let cfg = Config::from_args();
 
@@ -33,16 +240,579 @@ async fn main() {
         .build();
     let _logger = TermLogger::init(cfg.log_level, log_cfg, TerminalMode::Mixed, ColorChoice::Auto);
 
-    // Connect to sensor
-    let s = match Sensor::connect(cfg.options).await {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to connect to sensor: {:?}", e);
+    let res = match cfg.command {
+        Command::Scan{ options } => scan(options).await,
+        Command::Monitor{ options, reconnect, all, manager, alarms, quality } => monitor(options, reconnect, all, manager, alarms, quality).await,
+        Command::Record{ options, reconnect, format, sink, edf, alarms, quality, output } => record(options, reconnect, format, sink, edf, alarms, quality, output).await,
+        Command::Replay{ replay: opts, quality, input } => replay(opts, quality, input).await,
+        Command::Report{ format, desaturation, quality, output, input } => report(format, desaturation, quality, output, input),
+        Command::Export{ format, edf, quality, input, output } => export(format, edf, quality, input, output),
+        Command::Info{ options } => device_info(options).await,
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => ExitCode::from(code),
+    }
+}
+
+/// Log an error and map it to an exit code
+fn failed(context: &str, e: Error) -> u8 {
+    error!("{}: {}", context, e);
+
+    match e {
+        Error::NoDeviceFound => exit::NO_DEVICE,
+        Error::Io(_) | Error::InvalidRecording(_) => exit::IO,
+        Error::ConnectFailed | Error::NoServicesFound | Error::NoCharacteristic(_) | Error::UnsupportedDevice => exit::CONNECTION,
+        _ => exit::FAILURE,
+    }
+}
+
+async fn scan(options: Options) -> Result<(), u8> {
+    info!("Scanning for devices ({})", options.search_timeout);
+
+    let devices = Sensor::scan(options.clone()).await
+        .map_err(|e| failed("Scan failed", e))?;
+
+    println!("  {:<17}  {:>4}  {:<6}  {:<24}  services", "address", "rssi", "type", "name");
+
+    for d in &devices {
+        let rssi = d.rssi.map(|r| r.to_string()).unwrap_or_else(|| "-".to_string());
+        let address_type = d.address_type.map(|t| t.to_string()).unwrap_or_else(|| "-".to_string());
+        let services: Vec<_> = d.service_uuids.iter().map(|u| u.to_string()).collect();
+
+        // Flag devices matching the selection options
+        let m = if options.matching.matches(d) { "*" } else { " " };
+
+        println!("{} {:<17}  {:>4}  {:<6}  {:<24}  {}", m, d.address, rssi, address_type,
+            d.name.as_deref().unwrap_or("-"), services.join(","));
+
+        for (company, data) in &d.manufacturer_data {
+            println!("  {:>17}  manufacturer {:04x}: {:02x?}", "", company, data);
+        }
+    }
+
+    info!("Found {} devices", devices.len());
+
+    Ok(())
+}
+
+async fn monitor(options: Options, reconnect: ReconnectOptions, all: bool, manager: ManagerOptions, alarms: AlarmOptions, quality: QualityOptions) -> Result<(), u8> {
+    // Monitor all matching sensors, tagging output with the device address, otherwise
+    // monitor the first matching sensor, reconnecting whenever it drops out
+    let mut events: BoxStream<(Option<Address>, Event)> = match all {
+        true => {
+            let manager = SensorManager::open(options, reconnect, manager).await
+                .map_err(|e| failed("Failed to open transport", e))?;
+
+            let events = manager.events().await
+                .map_err(|e| failed("Failed to start discovery", e))?;
+
+            events.map(|(d, e)| (Some(d.address), e)).boxed()
+        },
+        false => {
+            let supervisor = Supervisor::open(options, reconnect).await
+                .map_err(|e| failed("Failed to open transport", e))?;
+
+            supervisor.events().map(|e| (None, e)).boxed()
+        },
+    };
+
+    let mut alarms = Alarms::new(alarms);
+    let mut quality = Quality::new(quality);
+
+    loop {
+        tokio::select!{
+            e = events.next() => match e {
+                Some((address, mut e)) => {
+                    quality.update(address, &mut e);
+
+                    let tag = address.map(|a| a.to_string());
+                    print_event(tag.as_deref(), &e);
+
+                    if let Event::Measurement(Measurement::Reading(r)) = &e {
+                        for (_, a) in alarms.update(address, r) {
+                            print_alarm(tag.as_deref(), &a);
+                        }
+                    }
+                },
+                None => break,
+            },
+            a = alarms.next() => for (address, a) in a {
+                print_alarm(address.map(|a| a.to_string()).as_deref(), &a);
+            },
+        }
+    }
+
+    match all {
+        true => error!("Discovery ended"),
+        false => error!("Sensor connection lost"),
+    }
+    Err(exit::CONNECTION_LOST)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn record(options: Options, reconnect: ReconnectOptions, format: RecordFormat, sink: SinkOptions, edf: EdfOptions, alarms: AlarmOptions, quality: QualityOptions, output: PathBuf) -> Result<(), u8> {
+    let flush_interval = *sink.flush_interval;
+
+    let mut out = match format {
+        RecordFormat::Session => Recorder::create(&output).map(Output::Session),
+        RecordFormat::Readings(f) => ReadingSink::create(&output, f, sink).map(Output::Readings),
+        RecordFormat::Edf => EdfWriter::create(&output, SystemTime::now(), &edf).map(Output::Edf),
+    }.map_err(|e| failed("Failed to create recording", e))?;
+
+    info!("Recording to {}", output.display());
+
+    let supervisor = Supervisor::open(options, reconnect).await
+        .map_err(|e| failed("Failed to open transport", e))?;
+
+    let mut events = supervisor.events();
+    let mut flush = tokio::time::interval(flush_interval);
+    let mut alarms = Alarms::new(alarms);
+    let mut quality = Quality::new(quality);
+    let mut address = None;
+
+    // Record until interrupted, the connection is lost or a write fails
+    let res = loop {
+        let e = tokio::select!{
+            e = events.next() => e,
+            _ = flush.tick() => {
+                if let Err(e) = out.flush() {
+                    break Err(failed("Failed to write recording", e));
+                }
+                continue;
+            },
+            a = alarms.next() => {
+                let r = a.into_iter().try_for_each(|(_, a)| {
+                    print_alarm(None, &a);
+                    out.alarm(&a)
+                });
+                if let Err(e) = r {
+                    break Err(failed("Failed to write recording", e));
+                }
+                continue;
+            },
+            _ = shutdown() => {
+                info!("Recording stopped");
+                break Ok(());
+            },
+        };
+
+        let mut e = match e {
+            Some(e) => e,
+            None => {
+                error!("Sensor connection lost");
+                break Err(exit::CONNECTION_LOST);
+            },
+        };
+
+        quality.update(None, &mut e);
+
+        print_event(None, &e);
+
+        if let Event::Connected{ address: a, .. } | Event::Reconnected{ address: a, .. } = &e {
+            address = Some(*a);
+        }
+
+        let r = match &e {
+            Event::Measurement(Measurement::Reading(r)) => alarms.update(None, r).into_iter().try_for_each(|(_, a)| {
+                print_alarm(None, &a);
+                out.alarm(&a)
+            }),
+            _ => Ok(()),
+        };
+
+        let r = r.and_then(|_| match (&mut out, e) {
+            (Output::Session(r), e) => r.write(&Record::from_event(e)),
+            (Output::Readings(s), Event::Measurement(Measurement::Reading(r))) => s.write(address, &r),
+            (Output::Edf(w), e) => w.record(&Record::from_event(e)),
+            _ => Ok(()),
+        });
+        if let Err(e) = r {
+            break Err(failed("Failed to write recording", e));
+        }
+    };
+
+    // Always finish the output so the recording is readable up to the failure,
+    // the first error determines the exit code
+    let finished = out.finish().map_err(|e| failed("Failed to write recording", e));
+
+    res.and(finished)
+}
+
+/// Record command output
+enum Output {
+    Session(Recorder),
+    Readings(ReadingSink),
+    Edf(EdfWriter),
+}
+
+impl Output {
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Output::Session(r) => r.flush(),
+            Output::Readings(s) => s.flush(),
+            Output::Edf(w) => w.flush(),
+        }
+    }
+
+    /// Write an alarm state change, reading logs do not include alarms
+    fn alarm(&mut self, a: &AlarmEvent) -> Result<(), Error> {
+        match self {
+            Output::Session(r) => r.write(&Record::Alarm(a.clone())),
+            Output::Edf(w) => w.record(&Record::Alarm(a.clone())),
+            Output::Readings(_) => Ok(()),
+        }
+    }
+
+    /// Flush and close the output, EDF files have a final partial record to write
+    fn finish(mut self) -> Result<(), Error> {
+        match self {
+            Output::Edf(w) => w.finish(),
+            _ => self.flush(),
+        }
+    }
+}
+
+/// Alarm evaluation for the monitor and record commands, with an engine per sensor
+struct Alarms {
+    opts: AlarmOptions,
+    engines: HashMap<Option<Address>, AlarmEngine>,
+    ack: mpsc::UnboundedReceiver<()>,
+    tick: tokio::time::Interval,
+}
+
+impl Alarms {
+    fn new(opts: AlarmOptions) -> Self {
+        let (tx, ack) = mpsc::unbounded_channel();
+
+        // Acknowledge all alarms on each line of input
+        if opts.alarms {
+            info!("Alarms enabled, press enter to acknowledge");
+
+            std::thread::spawn(move || {
+                for l in std::io::stdin().lines() {
+                    if l.is_err() || tx.send(()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        Self{ opts, engines: HashMap::new(), ack, tick: tokio::time::interval(Duration::from_secs(1)) }
+    }
+
+    /// Evaluate a reading from the sensor with the provided address
+    fn update(&mut self, address: Option<Address>, r: &Reading) -> Vec<(Option<Address>, AlarmEvent)> {
+        if !self.opts.alarms {
+            return vec![];
+        }
+
+        let engine = self.engines.entry(address).or_insert_with(|| AlarmEngine::new(self.opts.clone()));
+        engine.update(r).into_iter().map(|a| (address, a)).collect()
+    }
+
+    /// Wait for the next acknowledgement or periodic evaluation
+    async fn next(&mut self) -> Vec<(Option<Address>, AlarmEvent)> {
+        let ack = tokio::select!{
+            Some(()) = self.ack.recv() => true,
+            _ = self.tick.tick() => false,
+        };
+
+        let now = SystemTime::now();
+        self.engines.iter_mut()
+            .flat_map(|(address, e)| {
+                let events = match ack {
+                    true => e.acknowledge(None, now),
+                    false => e.tick(now),
+                };
+                events.into_iter().map(|a| (*address, a))
+            })
+            .collect()
+    }
+}
+
+/// Signal quality assessment for the monitor, record and replay commands, with an assessor per sensor
+struct Quality {
+    opts: QualityOptions,
+    assessors: HashMap<Option<Address>, QualityAssessor>,
+}
+
+impl Quality {
+    fn new(opts: QualityOptions) -> Self {
+        Self{ opts, assessors: HashMap::new() }
+    }
+
+    /// Assess an event from the sensor with the provided address, flagging low quality readings
+    fn update(&mut self, address: Option<Address>, e: &mut Event) {
+        if !self.opts.enabled {
             return;
         }
+
+        let assessor = self.assessors.entry(address).or_insert_with(|| QualityAssessor::new(self.opts.clone()));
+        match e {
+            Event::Measurement(m) => { assessor.push(m); },
+            Event::Disconnected{ .. } => assessor.reset(),
+            _ => (),
+        }
+    }
+}
+
+/// Resolve on interrupt (ctrl-c) or, on unix, termination signals
+async fn shutdown() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut term = match signal(SignalKind::terminate()) {
+            Ok(s) => s,
+            Err(_) => return std::future::pending().await,
+        };
+
+        tokio::select!{
+            _ = tokio::signal::ctrl_c() => (),
+            _ = term.recv() => (),
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn replay(opts: ReplayOptions, quality: QualityOptions, input: PathBuf) -> Result<(), u8> {
+    let replay = Replay::open(&input, opts)
+        .map_err(|e| failed("Failed to load recording", e))?;
+
+    info!("Replaying {} ({} records)", input.display(), replay.records().len());
+
+    let mut events: BoxStream<Event> = replay.events();
+    let mut quality = Quality::new(quality);
+
+    while let Some(mut e) = events.next().await {
+        quality.update(None, &mut e);
+        print_event(None, &e);
+    }
+
+    info!("Replay complete");
+
+    Ok(())
+}
+
+/// Load a recording for analysis, flagging readings by signal quality where enabled
+fn load(input: &Path, quality: &QualityOptions) -> Result<Vec<Record>, u8> {
+    let mut records = recording::load(input)
+        .map_err(|e| failed("Failed to load recording", e))?;
+
+    if quality.enabled {
+        let n = quality::assess(&mut records, quality);
+        info!("Signal quality assessment flagged {} readings", n);
+    }
+
+    Ok(records)
+}
+
+fn report(format: ReportFormat, desaturation: DesaturationOptions, quality: QualityOptions, output: Option<PathBuf>, input: PathBuf) -> Result<(), u8> {
+    let records = load(&input, &quality)?;
+
+    let summary = Summary::from_records(&records, &desaturation);
+
+    let report = match format {
+        ReportFormat::Text => format_summary(&summary),
+        ReportFormat::Json => serde_json::to_string_pretty(&summary)
+            .map_err(|e| failed("Failed to encode report", Error::InvalidRecording(e.to_string())))?,
+        ReportFormat::Html => report::html(&records, &summary),
     };
 
-    // TODO: whatever
+    match output {
+        Some(path) => {
+            std::fs::write(&path, report).map_err(|e| failed("Failed to write report", e.into()))?;
+            info!("Report written to {}", path.display());
+        },
+        None => println!("{}", report.trim_end()),
+    }
+
+    Ok(())
+}
+
+fn format_summary(s: &Summary) -> String {
+    let mut out = String::new();
+    let d = |d: Duration| humantime::format_duration(Duration::from_secs(d.as_secs())).to_string();
+
+    let _ = writeln!(out, "Recording");
+    let _ = writeln!(out, "  start:         {}", humantime::format_rfc3339_seconds(s.start));
+    let _ = writeln!(out, "  end:           {}", humantime::format_rfc3339_seconds(s.end));
+    let _ = writeln!(out, "  duration:      {}", d(s.end.duration_since(s.start).unwrap_or_default()));
+    let _ = writeln!(out, "  valid time:    {}", d(s.valid_time));
+    let _ = writeln!(out, "  disconnects:   {}", s.disconnects);
+    let _ = writeln!(out, "  probe off:     {}", s.probe_off);
+
+    let _ = writeln!(out, "SpO2");
+    match &s.spo2 {
+        Some(v) => {
+            let _ = writeln!(out, "  mean:          {:.1} %", v.mean);
+            let _ = writeln!(out, "  median:        {:.1} %", v.median);
+            let _ = writeln!(out, "  nadir:         {:.0} % at {}", v.nadir, humantime::format_rfc3339_seconds(v.nadir_time));
+            let _ = writeln!(out, "  T90:           {}", d(v.t90));
+            let _ = writeln!(out, "  T88:           {}", d(v.t88));
+            let _ = writeln!(out, "  CT90:          {:.1} %", v.ct90);
+        },
+        None => { let _ = writeln!(out, "  no valid readings"); },
+    }
+
+    let _ = writeln!(out, "Pulse rate");
+    match &s.pulse_rate {
+        Some(p) => { let _ = writeln!(out, "  min / mean / max: {:.0} / {:.0} / {:.0} bpm", p.min, p.mean, p.max); },
+        None => { let _ = writeln!(out, "  no valid readings"); },
+    }
+
+    let o = &s.desaturations;
+    let _ = writeln!(out, "Desaturations");
+    let _ = writeln!(out, "  events:        {}", o.events.len());
+    let _ = writeln!(out, "  ODI3:          {:.1} /h", o.odi3);
+    let _ = writeln!(out, "  ODI4:          {:.1} /h", o.odi4);
+
+    let b = &s.hypoxic_burden;
+    let _ = writeln!(out, "Hypoxic burden");
+    let _ = writeln!(out, "  ensemble:      {:.1} %·min/h", b.ensemble_averaged.burden);
+    if let Some((from, to)) = b.ensemble_averaged.search_window {
+        let _ = writeln!(out, "  search window: {:+} s to {:+} s from nadir", from, to);
+    }
+    let _ = writeln!(out, "  baseline area: {:.1} %·min/h", b.baseline_area.burden);
+
+    if let Some(v) = &s.spo2 {
+        let _ = writeln!(out, "SpO2 histogram");
+
+        let total = s.valid_time.as_secs_f64().max(1.0);
+        for b in v.histogram.iter().rev() {
+            let pct = b.time.as_secs_f64() * 100.0 / total;
+            let _ = writeln!(out, "  {:>3} %  {:>10}  {:>5.1} %  {}", b.spo2, d(b.time), pct, "#".repeat((pct / 2.0).round() as usize));
+        }
+    }
+
+    out
+}
+
+fn export(format: ExportFormat, edf: EdfOptions, quality: QualityOptions, input: PathBuf, output: PathBuf) -> Result<(), u8> {
+    let records = load(&input, &quality)?;
+
+    info!("Exporting {} ({} records) to {}", input.display(), records.len(), output.display());
+
+    match format {
+        ExportFormat::Edf => edf::export(&records, &output, &edf),
+        ExportFormat::Oscar => oscar::export(&records, &output),
+    }.map_err(|e| failed("Failed to export recording", e))?;
+
+    info!("Export complete");
 
+    Ok(())
 }
 
+async fn device_info(options: Options) -> Result<(), u8> {
+    let s = Sensor::connect(options).await
+        .map_err(|e| failed("Failed to connect to sensor", e))?;
+
+    let d = s.device();
+    println!("Device");
+    println!("  address:       {}", d.address);
+    println!("  name:          {}", d.name.as_deref().unwrap_or("-"));
+    println!("  address type:  {}", d.address_type.map(|t| t.to_string()).unwrap_or_else(|| "-".to_string()));
+    println!("  rssi:          {}", d.rssi.map(|r| format!("{} dBm", r)).unwrap_or_else(|| "-".to_string()));
+    println!("  protocol:      {}", s.protocol().map(|p| p.name()).unwrap_or("unsupported"));
+
+    let i = s.info();
+    println!("Device information");
+    for (k, v) in [
+        ("manufacturer", &i.manufacturer),
+        ("model", &i.model),
+        ("serial", &i.serial),
+        ("hardware", &i.hardware_revision),
+        ("firmware", &i.firmware_revision),
+        ("software", &i.software_revision),
+    ] {
+        println!("  {:<14} {}", format!("{}:", k), v.as_deref().unwrap_or("-"));
+    }
+
+    if let Some(f) = s.plx_features() {
+        use spo2::protocol::plx::Features;
+
+        let names: Vec<_> = [
+            (Features::MEASUREMENT_STATUS, "measurement-status"),
+            (Features::DEVICE_STATUS, "device-status"),
+            (Features::SPOT_CHECK_STORAGE, "spot-check-storage"),
+            (Features::SPOT_CHECK_TIMESTAMP, "spot-check-timestamp"),
+            (Features::SPO2PR_FAST, "fast"),
+            (Features::SPO2PR_SLOW, "slow"),
+            (Features::PULSE_AMPLITUDE_INDEX, "pulse-amplitude-index"),
+            (Features::MULTIPLE_BONDS, "multiple-bonds"),
+        ].iter().filter(|(v, _)| f.contains(*v)).map(|(_, n)| *n).collect();
+
+        println!("PLX features");
+        println!("  supported:     {}", names.join(", "));
+    }
+
+    println!("Services");
+    for service in s.services() {
+        println!("  {}{}", service.uuid, if service.primary { " (primary)" } else { "" });
+
+        for c in &service.characteristics {
+            let p = &c.properties;
+            let props: Vec<_> = [
+                (p.read, "read"),
+                (p.write, "write"),
+                (p.write_without_response, "write-without-response"),
+                (p.notify, "notify"),
+                (p.indicate, "indicate"),
+            ].iter().filter(|(set, _)| *set).map(|(_, n)| *n).collect();
+
+            println!("    {}  [{}]", c.uuid, props.join(", "));
+        }
+    }
+
+    if let Err(e) = s.connection().disconnect().await {
+        warn!("Disconnect failed: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Print a supervisor event, readings go to stdout and connection changes to the log
+fn print_event(tag: Option<&str>, e: &Event) {
+    let tag = tag.map(|t| format!("[{}] ", t)).unwrap_or_default();
+
+    match e {
+        Event::Connected{ address, .. } => info!("{}Connected to sensor {}", tag, address),
+        Event::Reconnected{ address, attempts, .. } => info!("{}Reconnected to sensor {} after {} attempts", tag, address, attempts),
+        Event::Disconnected{ address, .. } => warn!("{}Sensor {} disconnected", tag, address),
+        Event::Device(d) => info!("{}Device: {} ({}), protocol: {}", tag, d.device.name.as_deref().unwrap_or("unnamed"),
+            d.device.address, d.protocol.as_deref().unwrap_or("unsupported")),
+        Event::Measurement(Measurement::Reading(r)) => println!("{}{}", tag, format_reading(r)),
+        Event::Measurement(_) => (),
+    }
+}
+
+/// Print an alarm state change, raised alarms are logged as errors or warnings by priority
+fn print_alarm(tag: Option<&str>, a: &AlarmEvent) {
+    let tag = tag.map(|t| format!("[{}] ", t)).unwrap_or_default();
+
+    match (a.state, a.priority) {
+        (AlarmState::Active, Priority::High) => error!("{}ALARM: {}", tag, a),
+        (AlarmState::Active, _) | (AlarmState::Latched, _) => warn!("{}ALARM: {}", tag, a),
+        _ => info!("{}Alarm: {}", tag, a),
+    }
+}
+
+fn format_reading(r: &Reading) -> String {
+    let v = |v: Option<f32>, precision: usize| v.map(|v| format!("{:.*}", precision, v)).unwrap_or_else(|| "--".to_string());
+
+    let mut flags = vec![];
+    if r.status.probe_off { flags.push("probe-off"); }
+    if r.status.searching { flags.push("searching"); }
+    if r.status.low_signal { flags.push("low-signal"); }
+    if r.status.motion { flags.push("motion"); }
+    if r.status.sensor_fault { flags.push("fault"); }
+    if r.status.low_battery { flags.push("low-battery"); }
+    if r.status.low_quality { flags.push("low-quality"); }
+
+    format!("{}  SpO2 {:>3} %  PR {:>3} bpm  PI {:>4} %  {}",
+        humantime::format_rfc3339_seconds(r.timestamp), v(r.spo2, 0), v(r.pulse_rate, 0), v(r.perfusion_index, 1), flags.join(" "))
+}
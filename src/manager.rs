@@ -0,0 +1,324 @@
+//! Multi-sensor manager, connects to and supervises every matching device
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use log::{debug, info, warn};
+use structopt::StructOpt;
+use tokio::sync::mpsc;
+
+use crate::{Options, ReconnectOptions, Supervisor, Event, Transport, Address, DiscoveredDevice, Reading, Measurement};
+
+
+/// Multi-sensor options
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct ManagerOptions {
+    /// Maximum number of concurrently connected sensors. BlueZ does not report the
+    /// adapter's connection limit, so this should be set to match the controller (commonly 7)
+    #[structopt(long, default_value="7")]
+    pub max_sensors: usize,
+}
+
+impl Default for ManagerOptions {
+    fn default() -> Self {
+        Self{ max_sensors: 7 }
+    }
+}
+
+/// Sensor handed out by a [`SensorManager`]
+pub struct ManagedSensor {
+    /// Device the sensor was discovered as
+    pub device: DiscoveredDevice,
+
+    /// Supervisor events for this device, the stream ends if reconnection attempts
+    /// are exhausted and dropping it releases the device
+    pub events: BoxStream<'static, Event>,
+}
+
+impl std::fmt::Debug for ManagedSensor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ManagedSensor").field("device", &self.device).finish()
+    }
+}
+
+impl ManagedSensor {
+    /// Fetch the device address
+    pub fn address(&self) -> Address {
+        self.device.address
+    }
+
+    /// Convert into a stream of numeric readings
+    pub fn readings(self) -> BoxStream<'static, Reading> {
+        Box::pin(self.events.filter_map(|e| async move {
+            match e {
+                Event::Measurement(Measurement::Reading(r)) => Some(r),
+                _ => None,
+            }
+        }))
+    }
+}
+
+/// Manages connections to multiple sensors.
+///
+/// A single discovery session runs for the life of the manager, each device matching
+/// [`Options::matching`] is connected (up to [`ManagerOptions::max_sensors`]) and
+/// supervised independently, reconnecting by address when its connection drops.
+///
+/// A device keeps its slot while reconnecting. Slots are released once the device's
+/// reconnection attempts are exhausted or its event stream is dropped, after which
+/// other devices advertising are connected in its place.
+#[derive(Debug, Clone)]
+pub struct SensorManager {
+    transport: Arc<dyn Transport>,
+    opts: Options,
+    reconnect: ReconnectOptions,
+    manager: ManagerOptions,
+}
+
+impl SensorManager {
+    /// Create a new manager using the provided transport
+    pub fn new(transport: Arc<dyn Transport>, opts: Options, reconnect: ReconnectOptions, manager: ManagerOptions) -> Self {
+        Self{ transport, opts, reconnect, manager }
+    }
+
+    /// Open the transport selected by [`Options::backend`] and create a manager
+    pub async fn open(opts: Options, reconnect: ReconnectOptions, manager: ManagerOptions) -> Result<Self, crate::Error> {
+        let transport = crate::transport::open(&opts).await?;
+
+        Ok(Self::new(Arc::from(transport), opts, reconnect, manager))
+    }
+
+    /// Start discovery, returning a stream of sensors as matching devices are found.
+    ///
+    /// Dropping the stream stops discovery, sensors already handed out continue
+    /// until their event streams are dropped.
+    pub async fn sensors(self) -> Result<BoxStream<'static, ManagedSensor>, crate::Error> {
+        let devices = self.transport.scan(&self.opts.matching.scan_filter()).await?;
+
+        let (tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            tokio::select!{
+                _ = self.run(devices, &tx) => (),
+                _ = tx.closed() => debug!("Sensor receiver dropped, stopping discovery"),
+            }
+        });
+
+        Ok(Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|s| (s, rx))
+        })))
+    }
+
+    /// Start discovery, returning a merged stream of events tagged with the originating device
+    pub async fn events(self) -> Result<BoxStream<'static, (DiscoveredDevice, Event)>, crate::Error> {
+        let mut sensors = self.sensors().await?;
+
+        let (tx, rx) = mpsc::channel(256);
+
+        // Forward events from each sensor as it is connected
+        tokio::spawn(async move {
+            while let Some(s) = sensors.next().await {
+                let tx = tx.clone();
+                let ManagedSensor{ device, mut events } = s;
+
+                tokio::spawn(async move {
+                    while let Some(e) = events.next().await {
+                        if tx.send((device.clone(), e)).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|e| (e, rx))
+        })))
+    }
+
+    async fn run(&self, mut devices: BoxStream<'static, DiscoveredDevice>, tx: &mpsc::Sender<ManagedSensor>) {
+        let active = Arc::new(Mutex::new(HashSet::new()));
+
+        while let Some(d) = devices.next().await {
+            if !self.opts.matching.matches(&d) {
+                continue;
+            }
+
+            // Skip devices already managed and enforce the connection limit
+            {
+                let mut active = active.lock().unwrap();
+                if active.contains(&d.address) {
+                    continue;
+                }
+                if active.len() >= self.manager.max_sensors {
+                    warn!("Ignoring device {}, connection limit ({}) reached", d.address, self.manager.max_sensors);
+                    continue;
+                }
+                active.insert(d.address);
+            }
+
+            info!("Managing device {} ({})", d.address, d.name.as_deref().unwrap_or("unnamed"));
+
+            let supervisor = Supervisor::for_device(self.transport.clone(), d.clone(), self.opts.clone(), self.reconnect.clone());
+            let mut events = supervisor.events();
+
+            // Forward supervisor events, releasing the device slot when they end
+            let (etx, erx) = mpsc::channel(256);
+            let address = d.address;
+            let a = active.clone();
+            tokio::spawn(async move {
+                loop {
+                    let e = tokio::select!{
+                        e = events.next() => e,
+                        _ = etx.closed() => None,
+                    };
+
+                    let e = match e {
+                        Some(e) => e,
+                        None => break,
+                    };
+                    if etx.send(e).await.is_err() {
+                        break;
+                    }
+                }
+
+                debug!("Releasing device {}", address);
+                a.lock().unwrap().remove(&address);
+            });
+
+            let sensor = ManagedSensor{
+                device: d,
+                events: Box::pin(stream::unfold(erx, |mut rx| async move {
+                    rx.recv().await.map(|e| (e, rx))
+                })),
+            };
+
+            if tx.send(sensor).await.is_err() {
+                return;
+            }
+        }
+
+        warn!("Discovery ended");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::transport::mock::{MockDevice, MockTransport, Step};
+    use super::*;
+
+    fn device(address: u8, advertise_after: Duration) -> MockDevice {
+        let mut d = MockDevice::berrymed(Address([address; 6]));
+        d.advertise_after = advertise_after;
+        d.sessions = vec![vec![Step::berrymed_notify(Duration::from_millis(100)); 10]];
+        d
+    }
+
+    fn manager(t: &MockTransport, max_sensors: usize, reconnect: ReconnectOptions) -> SensorManager {
+        SensorManager::new(Arc::new(t.clone()), Options::from_iter(&["spo2"]), reconnect, ManagerOptions{ max_sensors })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_devices() {
+        let (a, b) = (Address([1; 6]), Address([2; 6]));
+        let t = MockTransport::new(vec![
+            device(1, Duration::from_secs(0)),
+            device(2, Duration::from_secs(1)),
+            MockDevice::new(Address([3; 6]), "Phone"),
+        ]);
+
+        let events = manager(&t, 7, ReconnectOptions::default()).events().await.unwrap();
+        let events: Vec<_> = events.take(20).collect().await;
+
+        // Events are tagged with the device they originate from
+        for (d, e) in &events {
+            if let Event::Connected{ address, .. } = e {
+                assert_eq!(d.address, *address);
+            }
+        }
+
+        let readings = |address| events.iter()
+            .filter(|(d, e)| d.address == address && matches!(e, Event::Measurement(Measurement::Reading(_))))
+            .count();
+        assert!(readings(a) > 0 && readings(b) > 0);
+
+        assert!(t.is_connected(a) && t.is_connected(b));
+        assert_eq!(t.connections(Address([3; 6])), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_limit() {
+        let t = MockTransport::new((1..=3).map(|i| device(i, Duration::from_secs(i as u64))).collect());
+
+        let mut sensors = manager(&t, 2, ReconnectOptions::default()).sensors().await.unwrap();
+
+        // Sensors are held, as dropping one releases its slot
+        let mut held = vec![];
+        for _ in 0..2 {
+            held.push(sensors.next().await.unwrap());
+        }
+        assert_eq!(held.iter().map(|s| s.address()).collect::<Vec<_>>(), vec![Address([1; 6]), Address([2; 6])]);
+
+        assert!(tokio::time::timeout(Duration::from_secs(60), sensors.next()).await.is_err());
+        assert_eq!(t.connections(Address([3; 6])), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnecting_device_keeps_slot() {
+        let a = Address([1; 6]);
+        let mut d = device(1, Duration::from_secs(0));
+        d.sessions = vec![
+            vec![Step::berrymed_notify(Duration::from_millis(100)), Step::Disconnect{ delay: Duration::from_secs(1) }],
+            vec![Step::berrymed_notify(Duration::from_millis(100))],
+        ];
+
+        // Advertising while the first device is reconnecting
+        let t = MockTransport::new(vec![d, device(2, Duration::from_millis(1500))]);
+
+        let mut sensors = manager(&t, 1, ReconnectOptions::default()).sensors().await.unwrap();
+        let mut s = sensors.next().await.unwrap();
+        assert_eq!(s.address(), a);
+
+        while let Some(e) = s.events.next().await {
+            if let Event::Reconnected{ address, .. } = e {
+                assert_eq!(address, a);
+                break;
+            }
+        }
+
+        assert!(tokio::time::timeout(Duration::from_secs(60), sensors.next()).await.is_err());
+        assert_eq!(t.connections(Address([2; 6])), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_device_releases_slot() {
+        let a = Address([1; 6]);
+        let mut d = device(1, Duration::from_secs(0));
+        d.sessions = vec![vec![Step::berrymed_notify(Duration::from_millis(100)), Step::Disconnect{ delay: Duration::from_secs(1) }]];
+
+        let t = MockTransport::new(vec![d, device(2, Duration::from_secs(30))]);
+        let reconnect = ReconnectOptions{ reconnect_attempts: 2, ..Default::default() };
+
+        let mut sensors = manager(&t, 1, reconnect).sensors().await.unwrap();
+        let mut s = sensors.next().await.unwrap();
+        assert_eq!(s.address(), a);
+
+        // The device stops accepting connections once disconnected
+        let mut lost = MockDevice::berrymed(a);
+        lost.connect_failures = usize::MAX;
+        while let Some(e) = s.events.next().await {
+            if let Event::Disconnected{ .. } = e {
+                t.add(lost.clone());
+            }
+        }
+
+        // Reconnection attempts are exhausted, so the next device takes the slot
+        let s = sensors.next().await.unwrap();
+        assert_eq!(s.address(), Address([2; 6]));
+        assert!(!t.is_connected(a));
+    }
+}
+
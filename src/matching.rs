@@ -0,0 +1,246 @@
+//! Device matching, selects which discovered device to connect to
+
+use std::fmt;
+use std::str::FromStr;
+
+use regex::Regex;
+use structopt::StructOpt;
+use uuid::Uuid;
+
+use crate::{Address, DiscoveredDevice};
+use crate::transport::ScanFilter;
+
+
+/// Local name prefix used where no other match criteria are provided
+pub const DEFAULT_LOCAL_NAME: &str = "J1";
+
+/// Device matching options.
+///
+/// Each provided criterion must match (or, with `match_any`, at least one). Unless a
+/// name, address or allow-list criterion identifies the device, the [`DEFAULT_LOCAL_NAME`]
+/// prefix must also match, so service and signal strength criteria alone never select
+/// unrelated devices.
+#[derive(Debug, PartialEq, Clone, Default, StructOpt)]
+pub struct MatchOptions {
+    /// Device local name prefix (J1 where no name or address criteria are provided)
+    #[structopt(long)]
+    pub local_name: Option<String>,
+
+    /// Device local name regular expression
+    #[structopt(long)]
+    pub name_regex: Option<NameRegex>,
+
+    /// Device address
+    #[structopt(long)]
+    pub address: Option<Address>,
+
+    /// Allowed device address, may be repeated
+    #[structopt(long = "allow", number_of_values = 1)]
+    pub allow: Vec<Address>,
+
+    /// Advertised service UUID, used as a BlueZ discovery filter with the btleplug backend only
+    #[structopt(long)]
+    pub service: Option<Uuid>,
+
+    /// Minimum signal strength (dBm), applied to discovered devices rather than as a discovery filter
+    #[structopt(long, allow_hyphen_values = true)]
+    pub min_rssi: Option<i16>,
+
+    /// Match devices satisfying any of the provided criteria, rather than all
+    #[structopt(long)]
+    pub match_any: bool,
+}
+
+impl MatchOptions {
+    /// Check whether a discovered device matches the configured criteria
+    pub fn matches(&self, d: &DiscoveredDevice) -> bool {
+        let mut results = vec![];
+
+        if let Some(prefix) = &self.local_name {
+            results.push(d.name.as_deref().map(|n| n.starts_with(prefix.as_str())).unwrap_or(false));
+        }
+        if let Some(r) = &self.name_regex {
+            results.push(d.name.as_deref().map(|n| r.0.is_match(n)).unwrap_or(false));
+        }
+        if let Some(a) = &self.address {
+            results.push(d.address == *a);
+        }
+        if !self.allow.is_empty() {
+            results.push(self.allow.contains(&d.address));
+        }
+        if let Some(s) = &self.service {
+            results.push(d.service_uuids.contains(s));
+        }
+        if let Some(min) = self.min_rssi {
+            results.push(d.rssi.map(|r| r >= min).unwrap_or(false));
+        }
+
+        let matched = match self.match_any {
+            true => results.is_empty() || results.iter().any(|r| *r),
+            false => results.iter().all(|r| *r),
+        };
+
+        // Require the default name prefix where no criterion identifies the device
+        match self.identifies() {
+            true => matched,
+            false => matched && d.name.as_deref().map(|n| n.starts_with(DEFAULT_LOCAL_NAME)).unwrap_or(false),
+        }
+    }
+
+    /// Check whether a name or address criterion is provided
+    fn identifies(&self) -> bool {
+        self.local_name.is_some() || self.name_regex.is_some() || self.address.is_some() || !self.allow.is_empty()
+    }
+
+    /// Build a backend scan filter from the configured criteria.
+    ///
+    /// Filters are only applied where all criteria must match, as any-of matching
+    /// may accept devices the filter would exclude. See [`ScanFilter`] for how each
+    /// backend applies the filter.
+    pub fn scan_filter(&self) -> ScanFilter {
+        if self.match_any {
+            return ScanFilter::default();
+        }
+
+        ScanFilter{
+            services: self.service.iter().cloned().collect(),
+            min_rssi: self.min_rssi,
+        }
+    }
+}
+
+/// Compiled device name regular expression
+#[derive(Debug, Clone)]
+pub struct NameRegex(pub Regex);
+
+impl PartialEq for NameRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl fmt::Display for NameRegex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.as_str())
+    }
+}
+
+impl FromStr for NameRegex {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Regex::new(s).map(NameRegex)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::plx::SERVICE;
+    use super::*;
+
+    /// Pulse oximeter service, as passed on the command line
+    const SERVICE_STR: &str = "00001822-0000-1000-8000-00805f9b34fb";
+
+    fn options(args: &[&str]) -> MatchOptions {
+        MatchOptions::from_iter([&["spo2"], args].concat())
+    }
+
+    fn device(address: u8, name: Option<&str>, rssi: Option<i16>, services: &[Uuid]) -> DiscoveredDevice {
+        DiscoveredDevice{
+            address: Address([address; 6]),
+            name: name.map(|n| n.to_string()),
+            rssi,
+            service_uuids: services.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_name() {
+        let o = options(&[]);
+
+        assert!(o.matches(&device(1, Some("J1 1234"), None, &[])));
+        assert!(!o.matches(&device(1, Some("Phone"), None, &[])));
+        assert!(!o.matches(&device(1, None, None, &[])));
+    }
+
+    #[test]
+    fn default_name_with_other_criteria() {
+        // Service and signal strength criteria still require the default name
+        let o = options(&["--min-rssi", "-70", "--service", SERVICE_STR]);
+        assert!(o.matches(&device(1, Some("J1"), Some(-60), &[SERVICE])));
+        assert!(!o.matches(&device(1, Some("Phone"), Some(-60), &[SERVICE])));
+
+        let o = options(&["--min-rssi", "-70", "--service", SERVICE_STR, "--match-any"]);
+        assert!(o.matches(&device(1, Some("J1"), Some(-90), &[SERVICE])));
+        assert!(!o.matches(&device(1, Some("Phone"), Some(-60), &[SERVICE])));
+        assert!(!o.matches(&device(1, Some("J1"), Some(-90), &[])));
+
+        // Name and address criteria replace the default
+        assert!(options(&["--local-name", "BM"]).matches(&device(1, Some("BM1000"), None, &[])));
+        assert!(options(&["--address", "01:01:01:01:01:01"]).matches(&device(1, None, None, &[])));
+        assert!(options(&["--allow", "01:01:01:01:01:01"]).matches(&device(1, Some("Phone"), None, &[])));
+    }
+
+    #[test]
+    fn all_or_any() {
+        let args = ["--local-name", "J1", "--address", "02:02:02:02:02:02"];
+
+        let o = options(&args);
+        assert!(o.matches(&device(2, Some("J1 a"), None, &[])));
+        assert!(!o.matches(&device(1, Some("J1 b"), None, &[])));
+        assert!(!o.matches(&device(2, Some("Other"), None, &[])));
+
+        let o = options(&[&args[..], &["--match-any"]].concat());
+        assert!(o.matches(&device(2, Some("J1 a"), None, &[])));
+        assert!(o.matches(&device(1, Some("J1 b"), None, &[])));
+        assert!(o.matches(&device(2, Some("Other"), None, &[])));
+        assert!(!o.matches(&device(1, Some("Other"), None, &[])));
+    }
+
+    #[test]
+    fn allow_list() {
+        let o = options(&["--allow", "01:01:01:01:01:01", "--allow", "03:03:03:03:03:03"]);
+
+        assert_eq!(o.allow.len(), 2);
+        assert!(o.matches(&device(1, None, None, &[])));
+        assert!(!o.matches(&device(2, Some("J1"), None, &[])));
+        assert!(o.matches(&device(3, Some("J1"), None, &[])));
+    }
+
+    #[test]
+    fn min_rssi() {
+        let o = options(&["--local-name", "J1", "--min-rssi", "-70"]);
+
+        assert!(o.matches(&device(1, Some("J1"), Some(-70), &[])));
+        assert!(!o.matches(&device(1, Some("J1"), Some(-71), &[])));
+
+        // Devices without a reported signal strength do not match
+        assert!(!o.matches(&device(1, Some("J1"), None, &[])));
+    }
+
+    #[test]
+    fn name_regex() {
+        let o = options(&["--name-regex", "^(J1|BM1000)-[0-9]+$"]);
+
+        assert!(o.matches(&device(1, Some("J1-42"), None, &[])));
+        assert!(o.matches(&device(1, Some("BM1000-7"), None, &[])));
+        assert!(!o.matches(&device(1, Some("J1 42"), None, &[])));
+        assert!(!o.matches(&device(1, None, None, &[])));
+
+        assert!(MatchOptions::from_iter_safe(&["spo2", "--name-regex", "("]).is_err());
+    }
+
+    #[test]
+    fn scan_filter() {
+        assert_eq!(options(&[]).scan_filter(), ScanFilter::default());
+
+        let f = options(&["--service", SERVICE_STR, "--min-rssi", "-80"]).scan_filter();
+        assert_eq!(f, ScanFilter{ services: vec![SERVICE], min_rssi: Some(-80) });
+
+        // Any-of matching may accept devices a filter would exclude
+        let f = options(&["--service", SERVICE_STR, "--min-rssi", "-80", "--match-any"]).scan_filter();
+        assert_eq!(f, ScanFilter::default());
+    }
+}
+
@@ -0,0 +1,159 @@
+//! Export to OSCAR compatible oximetry files.
+//!
+//! Sessions are written as Contec CMS50 / SpO2 Review `.spoR` files, which OSCAR
+//! imports directly via its CMS50 importer (Oximetry > Import from file). Samples
+//! are written at 1 Hz as a pulse rate and SpO2 byte pair, with zero marking
+//! periods without a valid reading. The start time is written in local time, as
+//! OSCAR expects.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use log::debug;
+
+use crate::Error;
+use crate::analysis;
+use crate::recording::{self, Record};
+
+
+/// Offset of sample data, the remainder of the header is zero padded
+const DATA_OFFSET: u16 = 64;
+
+/// Offset of the UTF-16 start time string
+const START_TIME_OFFSET: usize = 8;
+
+/// Export a recorded session to an OSCAR compatible `.spoR` file
+pub fn export(records: &[Record], path: impl AsRef<Path>) -> Result<(), Error> {
+    let samples = analysis::resample(records);
+
+    let start = match samples.first() {
+        Some((t, _)) => *t,
+        None => return Err(Error::InvalidRecording("no readings".to_string())),
+    };
+
+    recording::check_dated(start)?;
+
+    let f = File::create(path.as_ref())?;
+    let mut w = BufWriter::new(f);
+
+    debug!("Writing {} samples to {}", samples.len(), path.as_ref().display());
+
+    // Header: data offset, format marker, duration (s) and start time as MM/dd/yy HH:mm:ss
+    let mut header = vec![0u8; DATA_OFFSET as usize];
+    header[0..2].copy_from_slice(&DATA_OFFSET.to_le_bytes());
+    header[2..4].copy_from_slice(&2u16.to_le_bytes());
+    header[4..6].copy_from_slice(&(samples.len().min(u16::MAX as usize) as u16).to_le_bytes());
+
+    let local: DateTime<Local> = start.into();
+    let start_time = local.format("%m/%d/%y %H:%M:%S").to_string();
+    for (i, c) in start_time.encode_utf16().enumerate() {
+        let o = START_TIME_OFFSET + i * 2;
+        header[o..o + 2].copy_from_slice(&c.to_le_bytes());
+    }
+
+    w.write_all(&header)?;
+
+    for (_, r) in &samples {
+        let (pulse, spo2) = match r {
+            Some(r) => (r.pulse_rate.unwrap_or(0.0), r.spo2.unwrap_or(0.0)),
+            None => (0.0, 0.0),
+        };
+        w.write_all(&[pulse.round().clamp(0.0, 254.0) as u8, spo2.round().clamp(0.0, 100.0) as u8])?;
+    }
+
+    w.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::{Address, Reading, Status};
+    use crate::tests::temp_path;
+    use super::*;
+
+    const START: u64 = 1_760_745_600;
+
+    fn at(ms: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(START * 1000 + ms)
+    }
+
+    fn reading(ms: u64, spo2: Option<f32>, pulse_rate: Option<f32>, status: Status) -> Record {
+        Record::Reading(Reading{ timestamp: at(ms), spo2, pulse_rate, perfusion_index: None, status })
+    }
+
+    fn export_bytes(name: &str, records: &[Record]) -> Vec<u8> {
+        let path = temp_path(name);
+        export(records, &path).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        data
+    }
+
+    #[test]
+    fn layout() {
+        let probe_off = Status{ probe_off: true, ..Default::default() };
+        let records = vec![
+            reading(500, Some(97.0), Some(72.0), Status::default()),
+            reading(1500, None, Some(80.0), Status::default()),
+            Record::Disconnected{ timestamp: at(2500), address: Address([1, 2, 3, 4, 5, 6]) },
+            reading(4500, Some(95.0), Some(300.0), Status::default()),
+            reading(5500, Some(96.0), Some(70.0), probe_off),
+        ];
+
+        let data = export_bytes("layout.spoR", &records);
+
+        // Header
+        assert_eq!(u16::from_le_bytes([data[0], data[1]]), 64);
+        assert_eq!(u16::from_le_bytes([data[2], data[3]]), 2);
+        assert_eq!(u16::from_le_bytes([data[4], data[5]]), 6);
+
+        let local: DateTime<Local> = at(0).into();
+        let expected = local.format("%m/%d/%y %H:%M:%S").to_string();
+        let start: Vec<u16> = data[START_TIME_OFFSET..DATA_OFFSET as usize].chunks(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+        assert_eq!(String::from_utf16(&start).unwrap(), expected);
+        assert_eq!(expected.len(), 17);
+        assert!(data[START_TIME_OFFSET + 34..DATA_OFFSET as usize].iter().all(|b| *b == 0));
+
+        // [pulse, spo2] pairs at 1 Hz, with zero for invalid values, gaps and probe off
+        assert_eq!(&data[DATA_OFFSET as usize..], &[
+            72, 97,
+            80, 0,
+            0, 0,
+            0, 0,
+            254, 95,
+            0, 0,
+        ]);
+    }
+
+    #[test]
+    fn long_duration() {
+        let records = vec![
+            reading(0, Some(97.0), Some(72.0), Status::default()),
+            reading(70_000_000, Some(97.0), Some(72.0), Status::default()),
+        ];
+
+        let data = export_bytes("long-duration.spoR", &records);
+
+        // Duration saturates, all samples are still written
+        assert_eq!(u16::from_le_bytes([data[4], data[5]]), u16::MAX);
+        assert_eq!(data.len(), DATA_OFFSET as usize + 70_001 * 2);
+    }
+
+    #[test]
+    fn no_readings() {
+        let path = temp_path("no-readings.spoR");
+        let e = export(&[], &path).unwrap_err();
+
+        assert!(matches!(e, Error::InvalidRecording(_)));
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,336 @@
+//! BerryMed-style vendor protocol, used by the "J1" and similar fingertip oximeters.
+//!
+//! Devices notify a stream of 5-byte packets at ~100 Hz (often batched four to a
+//! 20-byte notification) on a vendor serial service. The first byte of each packet
+//! has the high bit set for synchronisation, all following bytes have it cleared.
+//!
+//! | Byte | Bits | Field                                      |
+//! |------|------|--------------------------------------------|
+//! | 0    | 7    | Sync (always 1)                            |
+//! |      | 6    | Pulse beep                                 |
+//! |      | 4    | Searching too long                         |
+//! |      | 0-3  | Signal strength (0-8, 15 invalid)          |
+//! | 1    | 0-6  | Pleth waveform sample (0-100)              |
+//! | 2    | 6    | Pulse rate bit 7                           |
+//! |      | 5    | Searching for pulse                        |
+//! |      | 4    | Probe off                                  |
+//! |      | 0-3  | Bar graph                                  |
+//! | 3    | 0-6  | Pulse rate bits 0-6 (255 invalid)          |
+//! | 4    | 0-6  | SpO2 (127 invalid)                         |
+
+use std::time::{Duration, SystemTime};
+
+use log::{trace, debug};
+use uuid::Uuid;
+
+use crate::{Measurement, PlethSample, Reading, Status};
+use super::{Decoder, ProtocolDecoder};
+
+
+/// Vendor serial service
+pub const SERVICE: Uuid = Uuid::from_u128(0x49535343_FE7D_4AE5_8FA9_9FAFD205E455);
+
+/// Measurement notification characteristic
+pub const NOTIFY: Uuid = Uuid::from_u128(0x49535343_1E4D_4BD9_BA61_23C647249616);
+
+/// Command write characteristic
+pub const WRITE: Uuid = Uuid::from_u128(0x49535343_8841_43F4_A8D4_ECBE34729BB3);
+
+/// Packet length in bytes
+pub const PACKET_LEN: usize = 5;
+
+/// Interval at which unchanged readings are repeated
+const REPEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Nominal packet (and pleth sample) interval
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+
+/// Decoded vendor packet
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Packet {
+    /// Signal strength (0-8), `None` if invalid
+    pub signal_strength: Option<u8>,
+    /// Searching for a pulse for too long
+    pub searching_too_long: bool,
+    /// Pulse beep, set on the sample at which a beat is detected
+    pub beat: bool,
+    /// Pleth waveform sample (0-100)
+    pub pleth: u8,
+    /// Bar graph (pulse intensity)
+    pub bar_graph: u8,
+    /// Probe disconnected or finger not detected
+    pub probe_off: bool,
+    /// Searching for pulse
+    pub searching: bool,
+    /// Pulse rate (bpm), `None` if invalid
+    pub pulse_rate: Option<u8>,
+    /// SpO2 (%), `None` if invalid
+    pub spo2: Option<u8>,
+}
+
+impl Packet {
+    /// Parse a single framed packet, returning `None` if framing bits are invalid
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < PACKET_LEN || data[0] & 0x80 == 0 || data[1..PACKET_LEN].iter().any(|b| b & 0x80 != 0) {
+            return None;
+        }
+
+        let signal = data[0] & 0x0F;
+        let pulse_rate = (data[3] & 0x7F) | ((data[2] & 0x40) << 1);
+        let spo2 = data[4] & 0x7F;
+
+        Some(Self{
+            signal_strength: if signal <= 8 { Some(signal) } else { None },
+            searching_too_long: data[0] & 0x10 != 0,
+            beat: data[0] & 0x40 != 0,
+            pleth: data[1] & 0x7F,
+            bar_graph: data[2] & 0x0F,
+            probe_off: data[2] & 0x10 != 0,
+            searching: data[2] & 0x20 != 0,
+            pulse_rate: if pulse_rate != 0xFF && pulse_rate != 0 { Some(pulse_rate) } else { None },
+            spo2: if spo2 != 0x7F && spo2 != 0 { Some(spo2) } else { None },
+        })
+    }
+
+    /// Convert to a [`PlethSample`]
+    pub fn pleth(&self, timestamp: SystemTime) -> PlethSample {
+        PlethSample{
+            timestamp,
+            value: self.pleth as f32,
+            beat: self.beat,
+        }
+    }
+
+    /// Convert to a [`Reading`]
+    pub fn reading(&self, timestamp: SystemTime) -> Reading {
+        Reading{
+            timestamp,
+            spo2: self.spo2.map(|v| v as f32),
+            pulse_rate: self.pulse_rate.map(|v| v as f32),
+            perfusion_index: None,
+            status: Status{
+                probe_off: self.probe_off,
+                searching: self.searching,
+                low_signal: self.searching_too_long || self.signal_strength.is_none(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Framing-resilient stream decoder for the vendor protocol.
+///
+/// Notifications are buffered and split into packets on sync bytes, discarding corrupt
+/// or partial packets and resynchronising on the next sync byte. A pleth sample is emitted
+/// for every packet, with packets batched into one notification spaced back from the
+/// notification time at the nominal sample interval. Readings are emitted when the SpO2,
+/// pulse rate or status change, and repeated each second otherwise.
+#[derive(Debug, Default)]
+pub struct BerryMedDecoder {
+    buff: Vec<u8>,
+    last: Option<(SystemTime, Reading)>,
+}
+
+impl BerryMedDecoder {
+    /// Split buffered bytes into packets, retaining any trailing partial packet
+    pub fn packets(&mut self, data: &[u8]) -> Vec<Packet> {
+        self.buff.extend_from_slice(data);
+
+        let mut packets = vec![];
+        let mut i = 0;
+
+        while i < self.buff.len() {
+            // Skip to next sync byte
+            if self.buff[i] & 0x80 == 0 {
+                trace!("Discarding unsynchronised byte: {:02x}", self.buff[i]);
+                i += 1;
+                continue;
+            }
+
+            // Wait for remainder of packet
+            if self.buff.len() - i < PACKET_LEN {
+                break;
+            }
+
+            match Packet::parse(&self.buff[i..i+PACKET_LEN]) {
+                Some(p) => {
+                    packets.push(p);
+                    i += PACKET_LEN;
+                },
+                None => {
+                    // Sync byte within packet, resynchronise from there
+                    debug!("Corrupt packet: {:02x?}", &self.buff[i..i+PACKET_LEN]);
+                    i += 1;
+                },
+            }
+        }
+
+        self.buff.drain(..i);
+
+        packets
+    }
+}
+
+impl Decoder for BerryMedDecoder {
+    fn decode(&mut self, timestamp: SystemTime, data: &[u8]) -> Vec<Measurement> {
+        let mut measurements = vec![];
+
+        let packets = self.packets(data);
+        let n = packets.len() as u32;
+
+        for (i, p) in packets.iter().enumerate() {
+            // The last packet in a notification is assumed to be the most recent
+            let t = timestamp.checked_sub(SAMPLE_INTERVAL * (n - 1 - i as u32)).unwrap_or(timestamp);
+
+            measurements.push(Measurement::Pleth(p.pleth(t)));
+
+            let r = p.reading(t);
+
+            // Emit on change or once the repeat interval has elapsed
+            let emit = match &self.last {
+                Some((last_t, l)) => l.spo2 != r.spo2 || l.pulse_rate != r.pulse_rate || l.status != r.status
+                    || t.duration_since(*last_t).map(|d| d >= REPEAT_INTERVAL).unwrap_or(true),
+                None => true,
+            };
+
+            if emit {
+                self.last = Some((t, r.clone()));
+                measurements.push(Measurement::Reading(r));
+            }
+        }
+
+        measurements
+    }
+}
+
+/// BerryMed vendor protocol
+///
+/// The vendor service is a generic serial profile also used by unrelated devices,
+/// so matching is restricted to known device names.
+#[derive(Debug, Default)]
+pub struct BerryMed;
+
+impl ProtocolDecoder for BerryMed {
+    fn name(&self) -> &str {
+        "BerryMed serial (J1)"
+    }
+
+    fn service(&self) -> Uuid {
+        SERVICE
+    }
+
+    fn characteristic(&self) -> Uuid {
+        NOTIFY
+    }
+
+    fn name_patterns(&self) -> &[&str] {
+        &["J1", "BerryMed", "BM1000"]
+    }
+
+    fn decoder(&self) -> Box<dyn Decoder> {
+        Box::new(BerryMedDecoder::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SpO2 97 %, pulse rate 72 bpm, pleth 50
+    const PACKET: [u8; 5] = [0x85, 0x32, 0x00, 0x48, 0x61];
+
+    fn pleth(m: &[Measurement]) -> Vec<&PlethSample> {
+        m.iter().filter_map(|m| match m { Measurement::Pleth(p) => Some(p), _ => None }).collect()
+    }
+
+    fn readings(m: &[Measurement]) -> Vec<&Reading> {
+        m.iter().filter_map(|m| match m { Measurement::Reading(r) => Some(r), _ => None }).collect()
+    }
+
+    #[test]
+    fn parse() {
+        let p = Packet::parse(&PACKET).unwrap();
+        assert_eq!(p, Packet{ signal_strength: Some(5), pleth: 50, pulse_rate: Some(72), spo2: Some(97), ..Default::default() });
+
+        // Beat, probe off and searching flags, pulse rate above 127 bpm
+        let p = Packet::parse(&[0xC5, 0x32, 0x73, 0x02, 0x61]).unwrap();
+        assert!(p.beat && p.probe_off && p.searching);
+        assert_eq!((p.pulse_rate, p.bar_graph), (Some(130), 3));
+
+        // Invalid values
+        let p = Packet::parse(&[0x8F, 0x00, 0x40, 0x7F, 0x7F]).unwrap();
+        assert_eq!((p.signal_strength, p.pulse_rate, p.spo2), (None, None, None));
+        assert!(p.reading(SystemTime::UNIX_EPOCH).status.low_signal);
+
+        // Framing errors
+        assert_eq!(Packet::parse(&PACKET[..4]), None);
+        assert_eq!(Packet::parse(&[0x05, 0x32, 0x00, 0x48, 0x61]), None);
+        assert_eq!(Packet::parse(&[0x85, 0x32, 0x80, 0x48, 0x61]), None);
+    }
+
+    #[test]
+    fn split_packets() {
+        let mut d = BerryMedDecoder::default();
+
+        let data = [PACKET, PACKET].concat();
+        assert_eq!(d.packets(&data[..3]), vec![]);
+        assert_eq!(d.packets(&data[3..7]).len(), 1);
+        assert_eq!(d.packets(&data[7..9]), vec![]);
+        assert_eq!(d.packets(&data[9..]).len(), 1);
+    }
+
+    #[test]
+    fn leading_garbage() {
+        let mut d = BerryMedDecoder::default();
+
+        let data = [&[0x00, 0x12, 0x7F][..], &PACKET].concat();
+        assert_eq!(d.packets(&data), vec![Packet::parse(&PACKET).unwrap()]);
+    }
+
+    #[test]
+    fn sync_byte_within_packet() {
+        let mut d = BerryMedDecoder::default();
+
+        // Truncated packet followed by a complete one, the decoder resynchronises on the next sync byte
+        let data = [&PACKET[..2], &PACKET[..]].concat();
+        assert_eq!(d.packets(&data), vec![Packet::parse(&PACKET).unwrap()]);
+
+        // Truncated packet completed by the next notification
+        assert_eq!(d.packets(&PACKET[..3]), vec![]);
+        assert_eq!(d.packets(&PACKET), vec![Packet::parse(&PACKET).unwrap()]);
+    }
+
+    #[test]
+    fn batched_timestamps() {
+        let mut d = BerryMedDecoder::default();
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(5);
+
+        let m = d.decode(t, &[PACKET; 4].concat());
+
+        // Packets are spaced back from the notification time, the last is the most recent
+        let times: Vec<_> = pleth(&m).iter().map(|p| p.timestamp).collect();
+        assert_eq!(times, (0..4).rev().map(|i| t - SAMPLE_INTERVAL * i).collect::<Vec<_>>());
+        assert!(pleth(&m).iter().all(|p| p.value == 50.0));
+
+        // Only the first, unchanged readings are suppressed
+        let r = readings(&m);
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].timestamp, t - SAMPLE_INTERVAL * 3);
+        assert_eq!((r[0].spo2, r[0].pulse_rate), (Some(97.0), Some(72.0)));
+    }
+
+    #[test]
+    fn repeated_readings() {
+        let mut d = BerryMedDecoder::default();
+        let t = SystemTime::UNIX_EPOCH;
+
+        assert_eq!(readings(&d.decode(t, &PACKET)).len(), 1);
+        assert_eq!(readings(&d.decode(t + Duration::from_millis(500), &PACKET)).len(), 0);
+        assert_eq!(readings(&d.decode(t + REPEAT_INTERVAL, &PACKET)).len(), 1);
+
+        // Changes are emitted immediately
+        let r = d.decode(t + Duration::from_millis(1010), &[0x85, 0x32, 0x00, 0x48, 0x60]);
+        assert_eq!(readings(&r)[0].spo2, Some(96.0));
+    }
+}
@@ -0,0 +1,161 @@
+//! Sensor protocol detection and decoding.
+//!
+//! Each supported oximeter protocol implements [`ProtocolDecoder`], declaring the GATT
+//! service and characteristic it uses and the device names it applies to. Protocols are
+//! held in a [`Registry`], which [`Sensor::connect`](crate::Sensor::connect) consults
+//! after service discovery. Additional protocols may be added at runtime with [`register`].
+
+use std::fmt::{self, Debug};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+use crate::{Measurement, Service, Characteristic};
+
+pub mod plx;
+
+pub mod berrymed;
+
+
+/// Decoder for measurement notifications from a sensor
+pub trait Decoder: Send {
+    /// Decode a notification received at `timestamp`, returning any completed
+    /// readings and waveform samples in time order
+    fn decode(&mut self, timestamp: SystemTime, data: &[u8]) -> Vec<Measurement>;
+}
+
+/// Oximeter protocol, describes the devices handled and creates [`Decoder`]s
+pub trait ProtocolDecoder: Send + Sync {
+    /// Protocol name
+    fn name(&self) -> &str;
+
+    /// Service carrying measurement notifications
+    fn service(&self) -> Uuid;
+
+    /// Characteristic carrying measurement notifications
+    fn characteristic(&self) -> Uuid;
+
+    /// Device name prefixes handled by this protocol, if empty any device
+    /// exposing the protocol service and characteristic is matched
+    fn name_patterns(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Create a new decoder instance
+    fn decoder(&self) -> Box<dyn Decoder>;
+
+    /// Check whether this protocol applies to a device with the provided name and services
+    fn matches(&self, name: Option<&str>, services: &[Service]) -> bool {
+        let patterns = self.name_patterns();
+        let name_match = patterns.is_empty() || name.map(|n| patterns.iter().any(|p| n.starts_with(p))).unwrap_or(false);
+
+        name_match && crate::transport::find_characteristic(services, self.service(), self.characteristic()).is_some()
+    }
+}
+
+/// Protocol registry, used to select a [`ProtocolDecoder`] for a connected device
+#[derive(Clone)]
+pub struct Registry {
+    protocols: Arc<RwLock<Vec<Arc<dyn ProtocolDecoder>>>>,
+}
+
+impl Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.protocols().iter().map(|p| p.name().to_string()).collect();
+        f.debug_struct("Registry").field("protocols", &names).finish()
+    }
+}
+
+impl Default for Registry {
+    /// Create a registry containing the built-in protocols
+    fn default() -> Self {
+        let r = Self::empty();
+        // Continuous measurements take precedence over spot-checks where both are available
+        r.register(plx::PlxSpotCheck);
+        r.register(plx::Plx);
+        r.register(berrymed::BerryMed);
+        r
+    }
+}
+
+impl Registry {
+    /// Create an empty registry
+    pub fn empty() -> Self {
+        Self{ protocols: Arc::new(RwLock::new(vec![])) }
+    }
+
+    /// Fetch the global registry, initialised with the built-in protocols
+    pub fn global() -> &'static Registry {
+        static GLOBAL: OnceLock<Registry> = OnceLock::new();
+        GLOBAL.get_or_init(Registry::default)
+    }
+
+    /// Register a protocol, protocols registered later take precedence when
+    /// more than one matches a device
+    pub fn register(&self, protocol: impl ProtocolDecoder + 'static) {
+        self.protocols.write().unwrap().push(Arc::new(protocol));
+    }
+
+    /// Fetch registered protocols in registration order
+    pub fn protocols(&self) -> Vec<Arc<dyn ProtocolDecoder>> {
+        self.protocols.read().unwrap().clone()
+    }
+
+    /// Select a protocol for a device from its name and discovered services
+    pub fn detect(&self, name: Option<&str>, services: &[Service]) -> Option<Protocol> {
+        let protocols = self.protocols.read().unwrap();
+
+        protocols.iter().rev().find_map(|p| {
+            if !p.matches(name, services) {
+                return None;
+            }
+
+            let characteristic = crate::transport::find_characteristic(services, p.service(), p.characteristic())?;
+
+            Some(Protocol{
+                characteristic: characteristic.clone(),
+                protocol: p.clone(),
+            })
+        })
+    }
+}
+
+/// Register a protocol with the global registry
+pub fn register(protocol: impl ProtocolDecoder + 'static) {
+    Registry::global().register(protocol)
+}
+
+/// Expand a 16-bit Bluetooth SIG assigned number to a full UUID
+pub const fn uuid16(v: u16) -> Uuid {
+    Uuid::from_u128(((v as u128) << 96) | 0x0000_0000_0000_1000_8000_0080_5F9B_34FB)
+}
+
+/// Protocol detected on a connected sensor
+#[derive(Clone)]
+pub struct Protocol {
+    /// Characteristic carrying measurement notifications
+    pub characteristic: Characteristic,
+    protocol: Arc<dyn ProtocolDecoder>,
+}
+
+impl Debug for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Protocol")
+            .field("name", &self.name())
+            .field("characteristic", &self.characteristic)
+            .finish()
+    }
+}
+
+impl Protocol {
+    /// Protocol name
+    pub fn name(&self) -> &str {
+        self.protocol.name()
+    }
+
+    /// Create a new decoder instance for this protocol
+    pub fn decoder(&self) -> Box<dyn Decoder> {
+        self.protocol.decoder()
+    }
+}
@@ -0,0 +1,654 @@
+//! Bluetooth SIG Pulse Oximeter Service (0x1822) decoding.
+//!
+//! Implements the PLX Continuous Measurement (0x2A5F), PLX Spot-Check Measurement (0x2A5E)
+//! and PLX Features (0x2A60) characteristics, as used by Nonin, Masimo and other
+//! standards-compliant oximeters. Continuous measurements are used where available,
+//! falling back to spot-check indications for devices that only take spot readings.
+
+use std::time::SystemTime;
+
+use log::{debug, warn};
+use uuid::Uuid;
+
+use crate::{Connection, Error, Measurement, Reading, Service, Status};
+use super::{Decoder, ProtocolDecoder, uuid16};
+
+
+/// Pulse Oximeter Service
+pub const SERVICE: Uuid = uuid16(0x1822);
+
+/// PLX Spot-Check Measurement characteristic (indicate)
+pub const SPOT_CHECK_MEASUREMENT: Uuid = uuid16(0x2A5E);
+
+/// PLX Continuous Measurement characteristic (notify)
+pub const CONTINUOUS_MEASUREMENT: Uuid = uuid16(0x2A5F);
+
+/// PLX Features characteristic (read)
+pub const FEATURES: Uuid = uuid16(0x2A60);
+
+
+/// SpO2 and pulse rate value pair
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SpO2PR {
+    /// Oxygen saturation (%)
+    pub spo2: Option<f32>,
+    /// Pulse rate (beats per minute)
+    pub pulse_rate: Option<f32>,
+}
+
+/// PLX Measurement Status field
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct MeasurementStatus(pub u16);
+
+impl MeasurementStatus {
+    pub const MEASUREMENT_ONGOING: u16 = 1 << 5;
+    pub const EARLY_ESTIMATED_DATA: u16 = 1 << 6;
+    pub const VALIDATED_DATA: u16 = 1 << 7;
+    pub const FULLY_QUALIFIED_DATA: u16 = 1 << 8;
+    pub const DATA_FROM_STORAGE: u16 = 1 << 9;
+    pub const DATA_FOR_DEMONSTRATION: u16 = 1 << 10;
+    pub const DATA_FOR_TESTING: u16 = 1 << 11;
+    pub const CALIBRATION_ONGOING: u16 = 1 << 12;
+    pub const MEASUREMENT_UNAVAILABLE: u16 = 1 << 13;
+    pub const QUESTIONABLE_MEASUREMENT: u16 = 1 << 14;
+    pub const INVALID_MEASUREMENT: u16 = 1 << 15;
+
+    /// Check whether a status flag is set
+    pub fn contains(&self, flag: u16) -> bool {
+        self.0 & flag != 0
+    }
+}
+
+/// PLX Device and Sensor Status field (24-bit)
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct DeviceStatus(pub u32);
+
+impl DeviceStatus {
+    pub const EXTENDED_DISPLAY_UPDATE_ONGOING: u32 = 1 << 0;
+    pub const EQUIPMENT_MALFUNCTION: u32 = 1 << 1;
+    pub const SIGNAL_PROCESSING_IRREGULARITY: u32 = 1 << 2;
+    pub const INADEQUATE_SIGNAL: u32 = 1 << 3;
+    pub const POOR_SIGNAL: u32 = 1 << 4;
+    pub const LOW_PERFUSION: u32 = 1 << 5;
+    pub const ERRATIC_SIGNAL: u32 = 1 << 6;
+    pub const NONPULSATILE_SIGNAL: u32 = 1 << 7;
+    pub const QUESTIONABLE_PULSE: u32 = 1 << 8;
+    pub const SIGNAL_ANALYSIS_ONGOING: u32 = 1 << 9;
+    pub const SENSOR_INTERFERENCE: u32 = 1 << 10;
+    pub const SENSOR_UNCONNECTED_TO_USER: u32 = 1 << 11;
+    pub const UNKNOWN_SENSOR_CONNECTED: u32 = 1 << 12;
+    pub const SENSOR_DISPLACED: u32 = 1 << 13;
+    pub const SENSOR_MALFUNCTIONING: u32 = 1 << 14;
+    pub const SENSOR_DISCONNECTED: u32 = 1 << 15;
+
+    /// Check whether a status flag is set
+    pub fn contains(&self, flag: u32) -> bool {
+        self.0 & flag != 0
+    }
+}
+
+/// PLX Continuous Measurement
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ContinuousMeasurement {
+    /// SpO2 and pulse rate with normal averaging
+    pub normal: SpO2PR,
+    /// SpO2 and pulse rate with fast averaging
+    pub fast: Option<SpO2PR>,
+    /// SpO2 and pulse rate with slow averaging
+    pub slow: Option<SpO2PR>,
+    pub measurement_status: Option<MeasurementStatus>,
+    pub device_status: Option<DeviceStatus>,
+    /// Pulse amplitude index (%)
+    pub pulse_amplitude_index: Option<f32>,
+}
+
+/// Date and time reported with a spot-check measurement
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+/// PLX Spot-Check Measurement
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SpotCheckMeasurement {
+    pub spot_check: SpO2PR,
+    pub timestamp: Option<DateTime>,
+    /// Device clock has not been set, timestamps are relative to device power-on
+    pub clock_not_set: bool,
+    pub measurement_status: Option<MeasurementStatus>,
+    pub device_status: Option<DeviceStatus>,
+    /// Pulse amplitude index (%)
+    pub pulse_amplitude_index: Option<f32>,
+}
+
+/// PLX Features
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Features {
+    /// Supported features bitfield
+    pub supported: u16,
+    /// Measurement status flags supported by the device
+    pub measurement_status_support: Option<MeasurementStatus>,
+    /// Device and sensor status flags supported by the device
+    pub device_status_support: Option<DeviceStatus>,
+}
+
+impl Features {
+    pub const MEASUREMENT_STATUS: u16 = 1 << 0;
+    pub const DEVICE_STATUS: u16 = 1 << 1;
+    pub const SPOT_CHECK_STORAGE: u16 = 1 << 2;
+    pub const SPOT_CHECK_TIMESTAMP: u16 = 1 << 3;
+    pub const SPO2PR_FAST: u16 = 1 << 4;
+    pub const SPO2PR_SLOW: u16 = 1 << 5;
+    pub const PULSE_AMPLITUDE_INDEX: u16 = 1 << 6;
+    pub const MULTIPLE_BONDS: u16 = 1 << 7;
+
+    /// Check whether a feature is supported
+    pub fn contains(&self, feature: u16) -> bool {
+        self.supported & feature != 0
+    }
+}
+
+/// Decode an IEEE-11073 16-bit SFLOAT, returning `None` for NaN, NRes and +/- infinity
+pub fn sfloat(v: u16) -> Option<f32> {
+    // +INF (0x07FE), NaN (0x07FF), NRes (0x0800), reserved (0x0801) and -INF (0x0802),
+    // special values are only defined with a zero exponent
+    if (0x07FE..=0x0802).contains(&v) {
+        return None;
+    }
+
+    // Signed 12-bit mantissa, signed 4-bit base-10 exponent
+    let mantissa = (v & 0x0FFF) as i16;
+    let exponent = (v >> 12) as i8;
+
+    let mantissa = if mantissa >= 0x0800 { mantissa - 0x1000 } else { mantissa };
+    let exponent = if exponent >= 0x08 { exponent - 0x10 } else { exponent };
+
+    Some(mantissa as f32 * 10f32.powi(exponent as i32))
+}
+
+/// Cursor over little-endian packet fields
+struct Fields<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self{ data, index: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let d = self.data.get(self.index..self.index + n)
+            .ok_or_else(|| Error::InvalidPacket(format!("expected at least {} bytes, found {}", self.index + n, self.data.len())))?;
+        self.index += n;
+        Ok(d)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let d = self.take(2)?;
+        Ok(u16::from_le_bytes([d[0], d[1]]))
+    }
+
+    fn u24(&mut self) -> Result<u32, Error> {
+        let d = self.take(3)?;
+        Ok(u32::from_le_bytes([d[0], d[1], d[2], 0]))
+    }
+
+    fn sfloat(&mut self) -> Result<Option<f32>, Error> {
+        Ok(sfloat(self.u16()?))
+    }
+
+    fn spo2pr(&mut self) -> Result<SpO2PR, Error> {
+        Ok(SpO2PR{
+            spo2: self.sfloat()?,
+            pulse_rate: self.sfloat()?,
+        })
+    }
+
+    fn datetime(&mut self) -> Result<DateTime, Error> {
+        Ok(DateTime{
+            year: self.u16()?,
+            month: self.u8()?,
+            day: self.u8()?,
+            hours: self.u8()?,
+            minutes: self.u8()?,
+            seconds: self.u8()?,
+        })
+    }
+}
+
+impl ContinuousMeasurement {
+    /// Parse a PLX Continuous Measurement value
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut f = Fields::new(data);
+        let flags = f.u8()?;
+
+        let normal = f.spo2pr()?;
+
+        Ok(Self{
+            normal,
+            fast: if flags & (1 << 0) != 0 { Some(f.spo2pr()?) } else { None },
+            slow: if flags & (1 << 1) != 0 { Some(f.spo2pr()?) } else { None },
+            measurement_status: if flags & (1 << 2) != 0 { Some(MeasurementStatus(f.u16()?)) } else { None },
+            device_status: if flags & (1 << 3) != 0 { Some(DeviceStatus(f.u24()?)) } else { None },
+            pulse_amplitude_index: if flags & (1 << 4) != 0 { f.sfloat()? } else { None },
+        })
+    }
+}
+
+impl SpotCheckMeasurement {
+    /// Parse a PLX Spot-Check Measurement value
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut f = Fields::new(data);
+        let flags = f.u8()?;
+
+        let spot_check = f.spo2pr()?;
+
+        Ok(Self{
+            spot_check,
+            timestamp: if flags & (1 << 0) != 0 { Some(f.datetime()?) } else { None },
+            measurement_status: if flags & (1 << 1) != 0 { Some(MeasurementStatus(f.u16()?)) } else { None },
+            device_status: if flags & (1 << 2) != 0 { Some(DeviceStatus(f.u24()?)) } else { None },
+            pulse_amplitude_index: if flags & (1 << 3) != 0 { f.sfloat()? } else { None },
+            clock_not_set: flags & (1 << 4) != 0,
+        })
+    }
+}
+
+impl Features {
+    /// Read PLX features from a connected device, `None` where the characteristic is
+    /// missing or fails to read
+    pub async fn read(conn: &dyn Connection, services: &[Service]) -> Option<Self> {
+        let c = crate::transport::find_characteristic(services, SERVICE, FEATURES)?;
+
+        let features = conn.read(c).await.and_then(|v| Self::parse(&v));
+        match &features {
+            Ok(f) => debug!("PLX features: {:?}", f),
+            Err(e) => warn!("Failed to read PLX features: {}", e),
+        }
+
+        features.ok()
+    }
+
+    /// Parse a PLX Features value
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut f = Fields::new(data);
+        let supported = f.u16()?;
+
+        Ok(Self{
+            supported,
+            measurement_status_support: if supported & Self::MEASUREMENT_STATUS != 0 { Some(MeasurementStatus(f.u16()?)) } else { None },
+            device_status_support: if supported & Self::DEVICE_STATUS != 0 { Some(DeviceStatus(f.u24()?)) } else { None },
+        })
+    }
+}
+
+/// Map PLX status fields to [`Status`] flags
+fn status(measurement: Option<MeasurementStatus>, device: Option<DeviceStatus>) -> Status {
+    let m = measurement.unwrap_or_default();
+    let d = device.unwrap_or_default();
+
+    Status{
+        probe_off: d.contains(DeviceStatus::SENSOR_DISCONNECTED)
+            || d.contains(DeviceStatus::SENSOR_UNCONNECTED_TO_USER)
+            || d.contains(DeviceStatus::SENSOR_DISPLACED),
+        searching: m.contains(MeasurementStatus::MEASUREMENT_ONGOING)
+            || d.contains(DeviceStatus::SIGNAL_ANALYSIS_ONGOING),
+        low_signal: d.contains(DeviceStatus::INADEQUATE_SIGNAL)
+            || d.contains(DeviceStatus::POOR_SIGNAL)
+            || d.contains(DeviceStatus::LOW_PERFUSION)
+            || d.contains(DeviceStatus::NONPULSATILE_SIGNAL),
+        motion: m.contains(MeasurementStatus::QUESTIONABLE_MEASUREMENT)
+            || d.contains(DeviceStatus::ERRATIC_SIGNAL)
+            || d.contains(DeviceStatus::SENSOR_INTERFERENCE),
+        sensor_fault: d.contains(DeviceStatus::EQUIPMENT_MALFUNCTION)
+            || d.contains(DeviceStatus::SENSOR_MALFUNCTIONING)
+            || d.contains(DeviceStatus::UNKNOWN_SENSOR_CONNECTED),
+        low_battery: false,
+        low_quality: false,
+    }
+}
+
+/// Check whether the measurement status marks values as unusable
+fn invalid(measurement: Option<MeasurementStatus>) -> bool {
+    measurement.map(|m| m.contains(MeasurementStatus::INVALID_MEASUREMENT) || m.contains(MeasurementStatus::MEASUREMENT_UNAVAILABLE))
+        .unwrap_or(false)
+}
+
+impl ContinuousMeasurement {
+    /// Convert to a [`Reading`] using the normal averaged values
+    pub fn reading(&self, timestamp: SystemTime) -> Reading {
+        let valid = !invalid(self.measurement_status);
+
+        Reading{
+            timestamp,
+            spo2: self.normal.spo2.filter(|_| valid),
+            pulse_rate: self.normal.pulse_rate.filter(|_| valid),
+            perfusion_index: self.pulse_amplitude_index,
+            status: status(self.measurement_status, self.device_status),
+        }
+    }
+}
+
+impl SpotCheckMeasurement {
+    /// Convert to a [`Reading`]
+    pub fn reading(&self, timestamp: SystemTime) -> Reading {
+        let valid = !invalid(self.measurement_status);
+
+        Reading{
+            timestamp,
+            spo2: self.spot_check.spo2.filter(|_| valid),
+            pulse_rate: self.spot_check.pulse_rate.filter(|_| valid),
+            perfusion_index: self.pulse_amplitude_index,
+            status: status(self.measurement_status, self.device_status),
+        }
+    }
+}
+
+/// Decoder for PLX Continuous Measurement notifications.
+///
+/// The PLX profile does not carry waveform data, so only readings are produced.
+#[derive(Debug, Default)]
+pub struct PlxDecoder;
+
+impl Decoder for PlxDecoder {
+    fn decode(&mut self, timestamp: SystemTime, data: &[u8]) -> Vec<Measurement> {
+        match ContinuousMeasurement::parse(data) {
+            Ok(m) => vec![Measurement::Reading(m.reading(timestamp))],
+            Err(e) => {
+                warn!("Failed to parse PLX continuous measurement {:02x?}: {}", data, e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// Decoder for PLX Spot-Check Measurement indications.
+///
+/// Readings are timestamped on receipt, as device timestamps are optional and the
+/// device clock may not be set.
+#[derive(Debug, Default)]
+pub struct SpotCheckDecoder;
+
+impl Decoder for SpotCheckDecoder {
+    fn decode(&mut self, timestamp: SystemTime, data: &[u8]) -> Vec<Measurement> {
+        match SpotCheckMeasurement::parse(data) {
+            Ok(m) => vec![Measurement::Reading(m.reading(timestamp))],
+            Err(e) => {
+                warn!("Failed to parse PLX spot-check measurement {:02x?}: {}", data, e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// Bluetooth SIG Pulse Oximeter Service protocol, using continuous measurements
+#[derive(Debug, Default)]
+pub struct Plx;
+
+impl ProtocolDecoder for Plx {
+    fn name(&self) -> &str {
+        "Bluetooth Pulse Oximeter Service"
+    }
+
+    fn service(&self) -> Uuid {
+        SERVICE
+    }
+
+    fn characteristic(&self) -> Uuid {
+        CONTINUOUS_MEASUREMENT
+    }
+
+    fn decoder(&self) -> Box<dyn Decoder> {
+        Box::new(PlxDecoder)
+    }
+}
+
+/// Bluetooth SIG Pulse Oximeter Service protocol, for devices exposing only spot-check
+/// measurements
+#[derive(Debug, Default)]
+pub struct PlxSpotCheck;
+
+impl ProtocolDecoder for PlxSpotCheck {
+    fn name(&self) -> &str {
+        "Bluetooth Pulse Oximeter Service (spot-check)"
+    }
+
+    fn service(&self) -> Uuid {
+        SERVICE
+    }
+
+    fn characteristic(&self) -> Uuid {
+        SPOT_CHECK_MEASUREMENT
+    }
+
+    fn decoder(&self) -> Box<dyn Decoder> {
+        Box::new(SpotCheckDecoder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use futures::StreamExt;
+    use structopt::StructOpt;
+
+    use crate::{Sensor, Options, Address, Characteristic};
+    use crate::protocol::Registry;
+    use crate::transport::Properties;
+    use crate::transport::mock::{MockDevice, MockTransport, Step};
+    use super::*;
+
+    /// Normal SpO2 97 %, pulse rate 72 bpm
+    const SPO2PR: [u8; 4] = [0x61, 0x00, 0x48, 0x00];
+
+    fn packet(flags: u8, fields: &[&[u8]]) -> Vec<u8> {
+        let mut p = vec![flags];
+        p.extend_from_slice(&SPO2PR);
+        for f in fields {
+            p.extend_from_slice(f);
+        }
+        p
+    }
+
+    fn service(characteristics: &[(Uuid, Properties)]) -> Service {
+        Service{
+            uuid: SERVICE,
+            primary: true,
+            characteristics: characteristics.iter().map(|(uuid, properties)| Characteristic{ service: SERVICE, uuid: *uuid, properties: *properties }).collect(),
+        }
+    }
+
+    const NOTIFY: Properties = Properties{ read: false, write: false, write_without_response: false, notify: true, indicate: false };
+    const INDICATE: Properties = Properties{ read: false, write: false, write_without_response: false, notify: false, indicate: true };
+    const READ: Properties = Properties{ read: true, write: false, write_without_response: false, notify: false, indicate: false };
+
+    #[test]
+    fn sfloat_values() {
+        assert_eq!(sfloat(0x0062), Some(98.0));
+        assert_eq!(sfloat(0x0000), Some(0.0));
+        assert_eq!(sfloat(0x1062), Some(980.0));
+        assert_eq!(sfloat(0x0FFF), Some(-1.0));
+
+        // Negative exponents
+        assert_eq!(sfloat(0xF3E8), Some(100.0));
+        assert!((sfloat(0xE3D4).unwrap() - 9.8).abs() < 1e-5);
+        assert!((sfloat(0xFFFF).unwrap() + 0.1).abs() < 1e-6);
+
+        // Largest and smallest mantissas with a non-zero exponent are not special values
+        assert_eq!(sfloat(0x17FF), Some(20470.0));
+        assert_eq!(sfloat(0x1802), Some(-20460.0));
+    }
+
+    #[test]
+    fn sfloat_special_values() {
+        assert_eq!(sfloat(0x07FE), None, "+INF");
+        assert_eq!(sfloat(0x07FF), None, "NaN");
+        assert_eq!(sfloat(0x0800), None, "NRes");
+        assert_eq!(sfloat(0x0801), None, "reserved");
+        assert_eq!(sfloat(0x0802), None, "-INF");
+    }
+
+    #[test]
+    fn continuous_minimal() {
+        let m = ContinuousMeasurement::parse(&packet(0x00, &[])).unwrap();
+
+        assert_eq!(m, ContinuousMeasurement{ normal: SpO2PR{ spo2: Some(97.0), pulse_rate: Some(72.0) }, ..Default::default() });
+    }
+
+    #[test]
+    fn continuous_optional_fields() {
+        let fast = [0x60, 0x00, 0x4A, 0x00];
+        let slow = [0x62, 0x00, 0x46, 0x00];
+
+        let m = ContinuousMeasurement::parse(&packet(0x01, &[&fast])).unwrap();
+        assert_eq!(m.fast, Some(SpO2PR{ spo2: Some(96.0), pulse_rate: Some(74.0) }));
+        assert_eq!(m.slow, None);
+
+        let m = ContinuousMeasurement::parse(&packet(0x02, &[&slow])).unwrap();
+        assert_eq!(m.fast, None);
+        assert_eq!(m.slow, Some(SpO2PR{ spo2: Some(98.0), pulse_rate: Some(70.0) }));
+
+        let m = ContinuousMeasurement::parse(&packet(0x04, &[&[0x20, 0x40]])).unwrap();
+        assert_eq!(m.measurement_status, Some(MeasurementStatus(0x4020)));
+        assert!(m.measurement_status.unwrap().contains(MeasurementStatus::QUESTIONABLE_MEASUREMENT));
+
+        let m = ContinuousMeasurement::parse(&packet(0x08, &[&[0x00, 0x80, 0x00]])).unwrap();
+        assert_eq!(m.device_status, Some(DeviceStatus(0x008000)));
+        assert!(m.device_status.unwrap().contains(DeviceStatus::SENSOR_DISCONNECTED));
+
+        let m = ContinuousMeasurement::parse(&packet(0x10, &[&[0x2D, 0xF0]])).unwrap();
+        assert!((m.pulse_amplitude_index.unwrap() - 4.5).abs() < 1e-5);
+
+        // All fields, in order
+        let m = ContinuousMeasurement::parse(&packet(0x1F, &[&fast, &slow, &[0x20, 0x40], &[0x00, 0x80, 0x00], &[0x2D, 0xF0]])).unwrap();
+        assert_eq!(m.fast.unwrap().spo2, Some(96.0));
+        assert_eq!(m.slow.unwrap().spo2, Some(98.0));
+        assert_eq!(m.measurement_status, Some(MeasurementStatus(0x4020)));
+        assert_eq!(m.device_status, Some(DeviceStatus(0x008000)));
+        assert!((m.pulse_amplitude_index.unwrap() - 4.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn continuous_truncated() {
+        assert!(matches!(ContinuousMeasurement::parse(&[]), Err(Error::InvalidPacket(_))));
+        assert!(matches!(ContinuousMeasurement::parse(&packet(0x01, &[])), Err(Error::InvalidPacket(_))));
+        assert!(matches!(ContinuousMeasurement::parse(&packet(0x08, &[&[0x00, 0x80]])), Err(Error::InvalidPacket(_))));
+    }
+
+    #[test]
+    fn continuous_reading() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+
+        let r = ContinuousMeasurement::parse(&packet(0x18, &[&[0x00, 0x80, 0x00], &[0x2D, 0xF0]])).unwrap().reading(t);
+        assert_eq!(r.timestamp, t);
+        assert_eq!(r.spo2, Some(97.0));
+        assert_eq!(r.perfusion_index, Some(4.5));
+        assert!(r.status.probe_off);
+
+        // Invalid measurements have no values
+        let r = ContinuousMeasurement::parse(&packet(0x04, &[&[0x00, 0x80]])).unwrap().reading(t);
+        assert_eq!((r.spo2, r.pulse_rate), (None, None));
+    }
+
+    #[test]
+    fn spot_check_optional_fields() {
+        let timestamp = [0xE8, 0x07, 10, 18, 22, 30, 15];
+
+        let m = SpotCheckMeasurement::parse(&packet(0x00, &[])).unwrap();
+        assert_eq!(m, SpotCheckMeasurement{ spot_check: SpO2PR{ spo2: Some(97.0), pulse_rate: Some(72.0) }, ..Default::default() });
+
+        let m = SpotCheckMeasurement::parse(&packet(0x01, &[&timestamp])).unwrap();
+        assert_eq!(m.timestamp, Some(DateTime{ year: 2024, month: 10, day: 18, hours: 22, minutes: 30, seconds: 15 }));
+
+        let m = SpotCheckMeasurement::parse(&packet(0x02, &[&[0x20, 0x00]])).unwrap();
+        assert_eq!(m.measurement_status, Some(MeasurementStatus(MeasurementStatus::MEASUREMENT_ONGOING)));
+
+        let m = SpotCheckMeasurement::parse(&packet(0x04, &[&[0x08, 0x00, 0x00]])).unwrap();
+        assert_eq!(m.device_status, Some(DeviceStatus(DeviceStatus::INADEQUATE_SIGNAL)));
+
+        let m = SpotCheckMeasurement::parse(&packet(0x08, &[&[0x2D, 0xF0]])).unwrap();
+        assert!((m.pulse_amplitude_index.unwrap() - 4.5).abs() < 1e-5);
+
+        let m = SpotCheckMeasurement::parse(&packet(0x10, &[])).unwrap();
+        assert!(m.clock_not_set);
+
+        let m = SpotCheckMeasurement::parse(&packet(0x1F, &[&timestamp, &[0x20, 0x00], &[0x08, 0x00, 0x00], &[0x2D, 0xF0]])).unwrap();
+        assert_eq!(m.timestamp.unwrap().year, 2024);
+        assert!(m.measurement_status.is_some() && m.device_status.is_some() && m.pulse_amplitude_index.is_some() && m.clock_not_set);
+
+        assert!(SpotCheckMeasurement::parse(&packet(0x01, &[&timestamp[..6]])).is_err());
+    }
+
+    #[test]
+    fn features() {
+        let f = Features::parse(&[0x4C, 0x00]).unwrap();
+        assert!(f.contains(Features::SPOT_CHECK_STORAGE) && f.contains(Features::SPOT_CHECK_TIMESTAMP) && f.contains(Features::PULSE_AMPLITUDE_INDEX));
+        assert!(!f.contains(Features::MEASUREMENT_STATUS));
+        assert_eq!((f.measurement_status_support, f.device_status_support), (None, None));
+
+        let f = Features::parse(&[0x03, 0x00, 0xE0, 0x7F, 0xFF, 0xFF, 0x00]).unwrap();
+        assert_eq!(f.measurement_status_support, Some(MeasurementStatus(0x7FE0)));
+        assert_eq!(f.device_status_support, Some(DeviceStatus(0x00FFFF)));
+
+        assert!(Features::parse(&[0x02, 0x00, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn decoders() {
+        let t = SystemTime::UNIX_EPOCH;
+
+        assert!(matches!(PlxDecoder.decode(t, &packet(0x00, &[]))[..], [Measurement::Reading(ref r)] if r.spo2 == Some(97.0)));
+        assert!(PlxDecoder.decode(t, &[0x00, 0x61]).is_empty());
+
+        assert!(matches!(SpotCheckDecoder.decode(t, &packet(0x00, &[]))[..], [Measurement::Reading(ref r)] if r.pulse_rate == Some(72.0)));
+        assert!(SpotCheckDecoder.decode(t, &[0x01]).is_empty());
+    }
+
+    #[test]
+    fn detection() {
+        let r = Registry::default();
+
+        let continuous = [service(&[(CONTINUOUS_MEASUREMENT, NOTIFY), (SPOT_CHECK_MEASUREMENT, INDICATE)])];
+        let p = r.detect(None, &continuous).unwrap();
+        assert_eq!(p.name(), "Bluetooth Pulse Oximeter Service");
+        assert_eq!(p.characteristic.uuid, CONTINUOUS_MEASUREMENT);
+
+        let spot_check = [service(&[(SPOT_CHECK_MEASUREMENT, INDICATE), (FEATURES, READ)])];
+        let p = r.detect(None, &spot_check).unwrap();
+        assert_eq!(p.name(), "Bluetooth Pulse Oximeter Service (spot-check)");
+        assert_eq!(p.characteristic.uuid, SPOT_CHECK_MEASUREMENT);
+
+        assert!(r.detect(None, &[service(&[(FEATURES, READ)])]).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spot_check_device() {
+        let mut d = MockDevice::new(Address([1; 6]), "Oximeter");
+        d.services = vec![service(&[(SPOT_CHECK_MEASUREMENT, INDICATE), (FEATURES, READ)])];
+        d.values.insert(FEATURES, vec![0x08, 0x00]);
+        d.sessions = vec![vec![
+            Step::Notify{ delay: Duration::from_secs(1), characteristic: SPOT_CHECK_MEASUREMENT, data: packet(0x00, &[]) },
+            Step::Disconnect{ delay: Duration::from_secs(1) },
+        ]];
+        let t = MockTransport::new(vec![d]);
+
+        let s = Sensor::connect_with(&t, &Options::from_iter(&["spo2", "--local-name", "Oximeter"])).await.unwrap();
+        assert_eq!(s.plx_features().map(|f| f.supported), Some(Features::SPOT_CHECK_TIMESTAMP));
+
+        let readings: Vec<_> = s.readings().await.unwrap().collect().await;
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].spo2, Some(97.0));
+    }
+}
+
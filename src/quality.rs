@@ -0,0 +1,490 @@
+//! Signal quality assessment from the plethysmograph waveform.
+//!
+//! Each second the [`QualityAssessor`] scores the preceding [`QualityOptions::window`]
+//! of pleth samples on three checks, each from 0 (unusable) to 1 (clean):
+//!
+//! - periodicity, the normalised autocorrelation at the pulse period, after removing
+//!   baseline wander
+//! - template, the mean correlation of each beat with the window's average beat
+//! - amplitude, the consistency of beat-to-beat amplitude
+//!
+//! The signal quality index (SQI) is the lowest of the three. Readings received within
+//! a window of an SQI below [`QualityOptions::threshold`] are flagged with
+//! [`Status::low_quality`](crate::Status::low_quality), so analysis and alarms can
+//! exclude them. Holding the flag for a window covers brief passes within an artifact
+//! and the sensor's own averaging after it. Readings from sensors without a waveform
+//! are left unchanged.
+
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use futures::stream::{BoxStream, Stream, StreamExt};
+use log::debug;
+use serde::{Serialize, Deserialize};
+use structopt::StructOpt;
+
+use crate::{Measurement, PlethSample, Reading};
+use crate::reading::unix_time;
+use crate::recording::Record;
+
+
+/// Interval between quality scores
+const SCORE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Approximate rate the waveform is decimated to for scoring (Hz)
+const ANALYSIS_RATE: f64 = 25.0;
+
+/// Plausible pulse rates for periodicity and beat detection (bpm)
+const MIN_PULSE: f64 = 30.0;
+const MAX_PULSE: f64 = 240.0;
+
+/// Points each beat is resampled to for template matching
+const TEMPLATE_LEN: usize = 16;
+
+/// Signal quality options
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct QualityOptions {
+    /// Assess signal quality from the pleth waveform, flagging readings taken during artifact
+    #[structopt(long="signal-quality")]
+    pub enabled: bool,
+
+    /// Signal quality index (0 - 1) below which readings are flagged
+    #[structopt(long="sqi-threshold", default_value="0.5")]
+    pub threshold: f32,
+
+    /// Pleth window scored for signal quality
+    #[structopt(long="sqi-window", default_value="5s")]
+    pub window: humantime::Duration,
+}
+
+impl Default for QualityOptions {
+    fn default() -> Self {
+        Self{
+            enabled: false,
+            threshold: 0.5,
+            window: Duration::from_secs(5).into(),
+        }
+    }
+}
+
+/// Signal quality score for a window of pleth samples
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct QualityScore {
+    /// Time of the last sample in the window
+    #[serde(with = "unix_time")]
+    pub timestamp: SystemTime,
+    /// Signal quality index, the lowest of the individual checks
+    pub sqi: f32,
+    pub periodicity: f32,
+    pub template: f32,
+    pub amplitude: f32,
+}
+
+/// Incremental signal quality assessment for a single sensor
+#[derive(Debug, Clone)]
+pub struct QualityAssessor {
+    opts: QualityOptions,
+    samples: VecDeque<(SystemTime, f32)>,
+    last: Option<QualityScore>,
+    /// Time of the latest score below the threshold
+    last_low: Option<SystemTime>,
+}
+
+impl QualityAssessor {
+    /// Create a new assessor
+    pub fn new(opts: QualityOptions) -> Self {
+        Self{ opts, samples: VecDeque::new(), last: None, last_low: None }
+    }
+
+    /// Fetch the latest quality score
+    pub fn score(&self) -> Option<&QualityScore> {
+        self.last.as_ref()
+    }
+
+    /// Discard buffered samples, call when the sensor disconnects
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.last = None;
+        self.last_low = None;
+    }
+
+    /// Assess a measurement, updating the score from pleth samples and flagging
+    /// readings in place. Returns a score where a new one was calculated.
+    pub fn push(&mut self, m: &mut Measurement) -> Option<QualityScore> {
+        match m {
+            Measurement::Pleth(p) => self.push_pleth(p),
+            Measurement::Reading(r) => {
+                self.flag(r);
+                None
+            },
+        }
+    }
+
+    /// Add a pleth sample, returning a score where a new one was calculated
+    pub fn push_pleth(&mut self, p: &PlethSample) -> Option<QualityScore> {
+        let t = p.timestamp;
+        let window = *self.opts.window;
+
+        self.samples.push_back((t, p.value));
+        while let Some((h, _)) = self.samples.front() {
+            match t.duration_since(*h).map(|d| d > window) {
+                Ok(true) => self.samples.pop_front(),
+                _ => break,
+            };
+        }
+
+        let due = match &self.last {
+            Some(l) => t.duration_since(l.timestamp).map(|d| d >= SCORE_INTERVAL).unwrap_or(false),
+            None => true,
+        };
+        if !due {
+            return None;
+        }
+
+        let s = self.assess()?;
+        debug!("Signal quality {:.2} (periodicity {:.2}, template {:.2}, amplitude {:.2})", s.sqi, s.periodicity, s.template, s.amplitude);
+
+        if s.sqi < self.opts.threshold {
+            self.last_low = Some(s.timestamp);
+        }
+
+        self.last = Some(s.clone());
+        Some(s)
+    }
+
+    /// Flag a reading where a score within the preceding window was below the threshold
+    pub fn flag(&self, r: &mut Reading) {
+        let low = match self.last_low {
+            Some(t) => r.timestamp.duration_since(t).map(|d| d <= *self.opts.window).unwrap_or(true),
+            None => false,
+        };
+
+        r.status.low_quality |= low;
+    }
+
+    /// Score the current window, `None` until a full window is buffered
+    fn assess(&self) -> Option<QualityScore> {
+        let (first, last) = (self.samples.front()?.0, self.samples.back()?.0);
+        let span = last.duration_since(first).ok()?.as_secs_f64();
+        if span < self.opts.window.as_secs_f64() * 0.8 || self.samples.len() < 8 {
+            return None;
+        }
+
+        // Decimate by block averaging, treating samples as evenly spaced over the window
+        let rate = (self.samples.len() - 1) as f64 / span;
+        let step = ((rate / ANALYSIS_RATE).round() as usize).max(1);
+        let rate = rate / step as f64;
+
+        let values: Vec<_> = self.samples.iter().map(|(_, v)| *v as f64).collect();
+        let x: Vec<_> = values.chunks(step).map(|c| c.iter().sum::<f64>() / c.len() as f64).collect();
+        let x = detrend(&x, (rate * 60.0 / MIN_PULSE).round() as usize);
+
+        let (periodicity, period) = periodicity(&x, rate);
+        let beats = beats(&x, period);
+        let (template, amplitude) = beat_scores(&x, &beats);
+
+        let (periodicity, template, amplitude) = (periodicity as f32, template as f32, amplitude as f32);
+
+        Some(QualityScore{
+            timestamp: last,
+            sqi: periodicity.min(template).min(amplitude),
+            periodicity,
+            template,
+            amplitude,
+        })
+    }
+}
+
+/// Remove baseline wander by subtracting a centred moving average over the provided width
+fn detrend(x: &[f64], width: usize) -> Vec<f64> {
+    let half = (width / 2).max(1);
+
+    (0..x.len()).map(|i| {
+        let w = &x[i.saturating_sub(half)..(i + half + 1).min(x.len())];
+        x[i] - w.iter().sum::<f64>() / w.len() as f64
+    }).collect()
+}
+
+/// Normalised autocorrelation at the pulse period, with the period in samples.
+///
+/// Only lags following the first zero crossing are considered, so slowly varying
+/// (non-pulsatile) signals score zero. The period is the first peak within 80% of the
+/// highest, avoiding multiples of the period where the signal is strongly periodic.
+fn periodicity(x: &[f64], rate: f64) -> (f64, usize) {
+    let min_lag = ((rate * 60.0 / MAX_PULSE).floor() as usize).max(1);
+    let max_lag = ((rate * 60.0 / MIN_PULSE).ceil() as usize).min(x.len() / 2);
+
+    let r: Vec<_> = (0..=max_lag + 1)
+        .map(|lag| match lag < x.len() {
+            true => correlation(&x[..x.len() - lag], &x[lag..]),
+            false => 0.0,
+        })
+        .collect();
+
+    let from = match r.iter().position(|v| *v < 0.0) {
+        Some(c) => c.max(min_lag),
+        None => return (0.0, min_lag),
+    };
+    if from > max_lag {
+        return (0.0, min_lag);
+    }
+
+    let highest = r[from..=max_lag].iter().cloned().fold(0.0, f64::max);
+
+    (from..=max_lag)
+        .find(|lag| r[*lag] >= highest * 0.8 && r[*lag] >= r[lag - 1] && r[*lag] >= r[lag + 1])
+        .map(|lag| (r[lag].max(0.0), lag))
+        .unwrap_or((0.0, min_lag))
+}
+
+/// Locate beats as the largest positive peak within each half period either side
+fn beats(x: &[f64], period: usize) -> Vec<usize> {
+    let half = (period / 2).max(1);
+
+    (0..x.len()).filter(|i| {
+        let (from, to) = (i.saturating_sub(half), (i + half + 1).min(x.len()));
+        x[*i] > 0.0 && x[from..to].iter().all(|v| *v <= x[*i]) && x[from..*i].iter().all(|v| *v < x[*i])
+    }).collect()
+}
+
+/// Template correlation and amplitude consistency of the beats between detected peaks
+fn beat_scores(x: &[f64], beats: &[usize]) -> (f64, f64) {
+    let segments: Vec<_> = beats.windows(2).map(|w| &x[w[0]..=w[1]]).collect();
+    if segments.len() < 2 {
+        return (0.0, 0.0);
+    }
+
+    // Resample each beat to a common length and average to form the template
+    let resampled: Vec<Vec<f64>> = segments.iter().map(|s| {
+        (0..TEMPLATE_LEN).map(|i| {
+            let p = i as f64 * (s.len() - 1) as f64 / (TEMPLATE_LEN - 1) as f64;
+            let (j, f) = (p.floor() as usize, p.fract());
+            match s.get(j + 1) {
+                Some(n) => s[j] * (1.0 - f) + n * f,
+                None => s[j],
+            }
+        }).collect()
+    }).collect();
+
+    let template: Vec<_> = (0..TEMPLATE_LEN)
+        .map(|i| resampled.iter().map(|b| b[i]).sum::<f64>() / resampled.len() as f64)
+        .collect();
+
+    let template_score = resampled.iter().map(|b| correlation(b, &template)).sum::<f64>() / resampled.len() as f64;
+
+    // Peak to trough amplitude of each beat, scored by the coefficient of variation
+    let amplitudes: Vec<_> = segments.iter().map(|s| {
+        let min = s.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = s.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        max - min
+    }).collect();
+
+    let mean = amplitudes.iter().sum::<f64>() / amplitudes.len() as f64;
+    let amplitude_score = match mean > 0.0 {
+        true => {
+            let var = amplitudes.iter().map(|a| (a - mean).powi(2)).sum::<f64>() / amplitudes.len() as f64;
+            1.0 - var.sqrt() / mean
+        },
+        false => 0.0,
+    };
+
+    (template_score.clamp(0.0, 1.0), amplitude_score.clamp(0.0, 1.0))
+}
+
+/// Pearson correlation, zero where either series is flat
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len().min(b.len()) as f64;
+    if n < 2.0 {
+        return 0.0;
+    }
+
+    let (ma, mb) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let (mut ab, mut aa, mut bb) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        ab += (x - ma) * (y - mb);
+        aa += (x - ma).powi(2);
+        bb += (y - mb).powi(2);
+    }
+
+    match aa > 0.0 && bb > 0.0 {
+        true => ab / (aa * bb).sqrt(),
+        false => 0.0,
+    }
+}
+
+/// Assess a live measurement stream, flagging readings taken during low signal quality
+pub fn measurements<S>(measurements: S, opts: QualityOptions) -> BoxStream<'static, Measurement>
+where
+    S: Stream<Item=Measurement> + Send + 'static,
+{
+    let mut assessor = QualityAssessor::new(opts);
+
+    Box::pin(measurements.map(move |mut m| {
+        assessor.push(&mut m);
+        m
+    }))
+}
+
+/// Assess a recorded session, flagging readings taken during low signal quality.
+/// Returns the number of readings flagged.
+pub fn assess(records: &mut [Record], opts: &QualityOptions) -> usize {
+    let mut assessor = QualityAssessor::new(opts.clone());
+    let mut flagged = 0;
+
+    for r in records {
+        match r {
+            Record::Pleth(p) => {
+                assessor.push_pleth(p);
+            },
+            Record::Reading(r) => {
+                let low = r.status.low_quality;
+                assessor.flag(r);
+                if r.status.low_quality && !low {
+                    flagged += 1;
+                }
+            },
+            Record::Disconnected{ .. } => assessor.reset(),
+            _ => (),
+        }
+    }
+
+    flagged
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    /// Deterministic noise source
+    struct Noise(u64);
+
+    impl Noise {
+        /// Next value, uniform in -0.5..0.5
+        fn next(&mut self) -> f64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) as f64 / (1u64 << 31) as f64 - 0.5
+        }
+    }
+
+    fn at(secs: f64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_secs_f64(secs)
+    }
+
+    /// Pulse shape at 72 bpm with a sharp upstroke, slow decay and dicrotic notch
+    fn pulse(t: f64) -> f32 {
+        let ph = (t * 1.2).fract();
+        let v = match ph < 0.15 {
+            true => ph / 0.15,
+            false => (1.0 - (ph - 0.15) / 0.85).powi(2) + 0.1 * ((ph - 0.15) * 12.0).sin().max(0.0) * (1.0 - ph),
+        };
+        (40.0 + 40.0 * v) as f32
+    }
+
+    /// Score a waveform sampled at the provided rate, returning the final score
+    fn score(rate: f64, secs: f64, mut f: impl FnMut(f64) -> f32) -> QualityScore {
+        let mut a = QualityAssessor::new(QualityOptions::default());
+
+        (0..(rate * secs) as usize)
+            .map(|i| i as f64 / rate)
+            .filter_map(|t| a.push_pleth(&PlethSample{ timestamp: at(t), value: f(t), beat: false }))
+            .last()
+            .unwrap()
+    }
+
+    #[test]
+    fn clean_sine() {
+        let s = score(50.0, 20.0, |t| (50.0 + 20.0 * (2.0 * PI * 1.5 * t).sin()) as f32);
+
+        assert!(s.sqi > 0.9, "{:?}", s);
+
+        // Scored each second from the first sample
+        assert_eq!(s.timestamp, at(19.0));
+    }
+
+    #[test]
+    fn clean_pulse() {
+        let s = score(100.0, 20.0, pulse);
+        assert!(s.sqi > QualityOptions::default().threshold + 0.25, "{:?}", s);
+
+        // Tolerates low level noise
+        let mut n = Noise(7);
+        let s = score(100.0, 20.0, |t| pulse(t) + 2.0 * n.next() as f32);
+        assert!(s.sqi > QualityOptions::default().threshold, "{:?}", s);
+    }
+
+    #[test]
+    fn noise() {
+        let mut n = Noise(1);
+        let s = score(100.0, 20.0, |_| (50.0 + 40.0 * n.next()) as f32);
+
+        assert!(s.sqi < QualityOptions::default().threshold, "{:?}", s);
+    }
+
+    #[test]
+    fn motion() {
+        // Random walk baseline, as the finger moves in the probe
+        let (mut n, mut walk) = (Noise(3), 0.0);
+        let s = score(100.0, 20.0, |_| {
+            let r = n.next();
+            walk = (walk + r * 8.0).clamp(-40.0, 40.0);
+            (60.0 + walk + 10.0 * r) as f32
+        });
+
+        assert!(s.sqi < QualityOptions::default().threshold, "{:?}", s);
+    }
+
+    #[test]
+    fn flat() {
+        let s = score(50.0, 10.0, |_| 50.0);
+        assert_eq!(s.sqi, 0.0);
+    }
+
+    #[test]
+    fn partial_window() {
+        let mut a = QualityAssessor::new(QualityOptions::default());
+
+        let scores = (0..300).filter_map(|i| a.push_pleth(&PlethSample{ timestamp: at(i as f64 / 100.0), value: pulse(i as f64 / 100.0), beat: false })).count();
+        assert_eq!(scores, 0);
+    }
+
+    #[test]
+    fn flags_readings_during_artifact() {
+        // One minute of pleth at 100 Hz with motion from 20 to 35 s, and readings each second
+        let (mut n, mut walk) = (Noise(1), 0.0);
+        let mut records = vec![];
+
+        for i in 0..6000 {
+            let t = i as f64 / 100.0;
+            let value = match (20.0..35.0).contains(&t) {
+                true => {
+                    let r = n.next();
+                    walk = (walk + r * 8.0).clamp(-40.0, 40.0);
+                    (60.0 + walk + 10.0 * r) as f32
+                },
+                false => pulse(t),
+            };
+            records.push(Record::Pleth(PlethSample{ timestamp: at(t), value, beat: false }));
+
+            if i % 100 == 50 {
+                records.push(Record::Reading(Reading{ timestamp: at(t), spo2: Some(96.0), pulse_rate: Some(72.0), perfusion_index: None, status: Default::default() }));
+            }
+        }
+
+        let n = assess(&mut records, &QualityOptions::default());
+
+        // Flagged from shortly after the artifact starts until a window after the last low score
+        let flagged: Vec<_> = records.iter().filter_map(|r| match r {
+            Record::Reading(r) if r.status.low_quality => Some(r.timestamp.duration_since(at(0.0)).unwrap().as_secs()),
+            _ => None,
+        }).collect();
+
+        assert_eq!(n, flagged.len());
+        assert!(flagged.len() >= 12, "{:?}", flagged);
+        assert!(flagged.iter().all(|t| (20..43).contains(t)), "{:?}", flagged);
+
+        // Flags are not applied twice
+        assert_eq!(assess(&mut records, &QualityOptions::default()), 0);
+    }
+}
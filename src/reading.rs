@@ -0,0 +1,172 @@
+//! Measurement types produced by sensor decoders
+
+use std::time::SystemTime;
+
+use serde::{Serialize, Deserialize};
+
+
+/// SpO2 / pulse rate measurement
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Reading {
+    /// Time the measurement was received
+    #[serde(with = "unix_time")]
+    pub timestamp: SystemTime,
+
+    /// Oxygen saturation (%), `None` where the sensor reports an invalid value
+    pub spo2: Option<f32>,
+
+    /// Pulse rate (beats per minute), `None` where the sensor reports an invalid value
+    pub pulse_rate: Option<f32>,
+
+    /// Perfusion index (%), if reported by the sensor
+    pub perfusion_index: Option<f32>,
+
+    /// Sensor status flags
+    #[serde(default)]
+    pub status: Status,
+}
+
+/// Sensor status flags reported alongside a [`Reading`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Status {
+    /// Probe disconnected or finger not detected
+    pub probe_off: bool,
+
+    /// Sensor is searching for a pulse
+    pub searching: bool,
+
+    /// Signal too weak for a reliable measurement
+    pub low_signal: bool,
+
+    /// Motion or other artifact detected
+    pub motion: bool,
+
+    /// Sensor reports a fault
+    pub sensor_fault: bool,
+
+    /// Sensor battery low
+    pub low_battery: bool,
+
+    /// Pleth signal quality below threshold, set by [`quality`](crate::quality) assessment
+    pub low_quality: bool,
+}
+
+impl Reading {
+    /// Check whether the reading contains a usable SpO2 or pulse rate value
+    pub fn is_valid(&self) -> bool {
+        !self.status.probe_off && (self.spo2.is_some() || self.pulse_rate.is_some())
+    }
+}
+
+/// Plethysmograph waveform sample
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PlethSample {
+    /// Time the sample was taken
+    #[serde(with = "unix_time")]
+    pub timestamp: SystemTime,
+
+    /// Waveform amplitude, in protocol specific units
+    pub value: f32,
+
+    /// Set where the sensor flags a detected beat at this sample
+    #[serde(default)]
+    pub beat: bool,
+}
+
+/// Measurement decoded from a sensor notification
+#[derive(Debug, PartialEq, Clone)]
+pub enum Measurement {
+    /// Numeric SpO2 / pulse rate reading
+    Reading(Reading),
+    /// Plethysmograph waveform sample
+    Pleth(PlethSample),
+}
+
+impl Measurement {
+    /// Fetch the measurement timestamp
+    pub fn timestamp(&self) -> SystemTime {
+        match self {
+            Measurement::Reading(r) => r.timestamp,
+            Measurement::Pleth(p) => p.timestamp,
+        }
+    }
+}
+
+/// Serialise timestamps as fractional seconds since the unix epoch
+pub mod unix_time {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{Serializer, Deserializer, Deserialize};
+
+    /// Convert a timestamp to fractional seconds since the unix epoch
+    pub fn to_secs(t: SystemTime) -> f64 {
+        match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        }
+    }
+
+    /// Convert fractional seconds since the unix epoch to a timestamp, `None` where
+    /// the value is not finite or out of range
+    pub fn from_secs(s: f64) -> Option<SystemTime> {
+        let d = Duration::try_from_secs_f64(s.abs()).ok()?;
+
+        match s >= 0.0 {
+            true => UNIX_EPOCH.checked_add(d),
+            false => UNIX_EPOCH.checked_sub(d),
+        }
+    }
+
+    pub fn serialize<S: Serializer>(t: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        // Round to microseconds to keep records compact
+        s.serialize_f64((to_secs(*t) * 1e6).round() / 1e6)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        let v = f64::deserialize(d)?;
+
+        from_secs(v).ok_or_else(|| serde::de::Error::custom(format!("invalid timestamp {}", v)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde::{Serialize, Deserialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Timestamp(#[serde(with = "unix_time")] SystemTime);
+
+    #[test]
+    fn unix_time_round_trip() {
+        for t in [UNIX_EPOCH + Duration::from_micros(1_760_745_600_123_456), UNIX_EPOCH - Duration::from_millis(1500)] {
+            let s = serde_json::to_string(&Timestamp(t)).unwrap();
+            let Timestamp(v) = serde_json::from_str(&s).unwrap();
+
+            // Accurate to the serialised microseconds
+            let diff = v.duration_since(t).or_else(|_| t.duration_since(v)).unwrap();
+            assert!(diff < Duration::from_micros(1), "{:?} != {:?}", v, t);
+        }
+
+        assert_eq!(serde_json::to_string(&Timestamp(UNIX_EPOCH + Duration::from_millis(1500))).unwrap(), "1.5");
+    }
+
+    #[test]
+    fn unix_time_out_of_range() {
+        assert_eq!(unix_time::from_secs(-2.5), Some(UNIX_EPOCH - Duration::from_millis(2500)));
+
+        for v in [f64::NAN, f64::INFINITY, 1e19, 1e30, -1e30] {
+            assert_eq!(unix_time::from_secs(v), None, "{}", v);
+        }
+
+        for v in ["1e30", "-1e30", "1e19"] {
+            let e = serde_json::from_str::<Timestamp>(v).unwrap_err();
+            assert!(e.to_string().contains("invalid timestamp"), "{}", e);
+        }
+    }
+}
+
@@ -0,0 +1,274 @@
+//! Session recording format.
+//!
+//! Sessions are stored as newline-delimited JSON, one [`Record`] per line, starting
+//! with a [`Record::Header`]. Timestamps are fractional seconds since the unix epoch.
+//!
+//! ```text
+//! {"type":"header","version":1,"created":1760745600.0,"software":"spo2 0.1.0"}
+//! {"type":"connected","timestamp":1760745601.2,"address":"AA:BB:CC:DD:EE:FF"}
+//! {"type":"reading","timestamp":1760745602.0,"spo2":97.0,"pulse_rate":64.0,"perfusion_index":null,"status":{...}}
+//! {"type":"pleth","timestamp":1760745602.01,"value":48.0,"beat":false}
+//! ```
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use log::debug;
+use serde::{Serialize, Deserialize};
+
+use crate::{Error, Event, DeviceDetails, Address, AddressType, DiscoveredDevice, DeviceInfo, Measurement, Reading, PlethSample, AlarmEvent};
+use crate::reading::unix_time;
+use crate::lines::{self, LineWriter};
+use crate::sink;
+
+
+/// Recording format version
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Earliest start time accepted for export (1985-01-01, the EDF clipping date)
+const EARLIEST_EXPORT: Duration = Duration::from_secs(473_385_600);
+
+/// Single line of a session recording
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    /// Recording header, always the first record
+    Header {
+        version: u32,
+        #[serde(with = "unix_time")]
+        created: SystemTime,
+        #[serde(default)]
+        software: String,
+    },
+    /// Connected device details
+    Device {
+        #[serde(with = "unix_time")]
+        timestamp: SystemTime,
+        address: Address,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        address_type: Option<AddressType>,
+        #[serde(default)]
+        protocol: Option<String>,
+        #[serde(default)]
+        info: DeviceInfo,
+    },
+    /// Sensor connected
+    Connected {
+        #[serde(with = "unix_time")]
+        timestamp: SystemTime,
+        address: Address,
+    },
+    /// Sensor disconnected
+    Disconnected {
+        #[serde(with = "unix_time")]
+        timestamp: SystemTime,
+        address: Address,
+    },
+    /// Sensor reconnected
+    Reconnected {
+        #[serde(with = "unix_time")]
+        timestamp: SystemTime,
+        address: Address,
+        #[serde(default)]
+        attempts: usize,
+    },
+    /// SpO2 / pulse rate reading
+    Reading(Reading),
+    /// Pleth waveform sample
+    Pleth(PlethSample),
+    /// Alarm state change
+    Alarm(AlarmEvent),
+}
+
+impl Record {
+    /// Create a header record for a new recording
+    pub fn header() -> Self {
+        Record::Header{
+            version: FORMAT_VERSION,
+            created: SystemTime::now(),
+            software: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    /// Fetch the record timestamp
+    pub fn timestamp(&self) -> SystemTime {
+        match self {
+            Record::Header{ created, .. } => *created,
+            Record::Device{ timestamp, .. } => *timestamp,
+            Record::Connected{ timestamp, .. } => *timestamp,
+            Record::Disconnected{ timestamp, .. } => *timestamp,
+            Record::Reconnected{ timestamp, .. } => *timestamp,
+            Record::Reading(r) => r.timestamp,
+            Record::Pleth(p) => p.timestamp,
+            Record::Alarm(a) => a.timestamp,
+        }
+    }
+
+    /// Convert a supervisor event to a record, device details are stamped with the current time
+    pub fn from_event(e: Event) -> Self {
+        match e {
+            Event::Connected{ address, timestamp } => Record::Connected{ timestamp, address },
+            Event::Disconnected{ address, timestamp } => Record::Disconnected{ timestamp, address },
+            Event::Reconnected{ address, timestamp, attempts } => Record::Reconnected{ timestamp, address, attempts },
+            Event::Device(d) => {
+                let DeviceDetails{ device, info, protocol } = *d;
+                Record::Device{
+                    timestamp: SystemTime::now(),
+                    address: device.address,
+                    name: device.name,
+                    address_type: device.address_type,
+                    protocol,
+                    info,
+                }
+            },
+            Event::Measurement(Measurement::Reading(r)) => Record::Reading(r),
+            Event::Measurement(Measurement::Pleth(p)) => Record::Pleth(p),
+        }
+    }
+
+    /// Convert a record to the equivalent supervisor event, headers and alarms have no equivalent
+    pub fn to_event(&self) -> Option<Event> {
+        let e = match self.clone() {
+            Record::Header{ .. } | Record::Alarm(_) => return None,
+            Record::Device{ address, name, address_type, protocol, info, .. } => Event::Device(Box::new(DeviceDetails{
+                device: DiscoveredDevice{ address, name, address_type, ..Default::default() },
+                info,
+                protocol,
+            })),
+            Record::Connected{ timestamp, address } => Event::Connected{ address, timestamp },
+            Record::Disconnected{ timestamp, address } => Event::Disconnected{ address, timestamp },
+            Record::Reconnected{ timestamp, address, attempts } => Event::Reconnected{ address, timestamp, attempts },
+            Record::Reading(r) => Event::Measurement(Measurement::Reading(r)),
+            Record::Pleth(p) => Event::Measurement(Measurement::Pleth(p)),
+        };
+
+        Some(e)
+    }
+}
+
+/// Writes session records to a file
+#[derive(Debug)]
+pub struct Recorder {
+    w: LineWriter,
+}
+
+impl Recorder {
+    /// Create a new recording, writing the header record
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let w = LineWriter::create(path.as_ref())?;
+
+        debug!("Recording to {}", path.as_ref().display());
+
+        let mut r = Self{ w };
+        r.write(&Record::header())?;
+        r.flush()?;
+
+        Ok(r)
+    }
+
+    /// Write a record, records are buffered until [`Recorder::flush`] is called
+    /// (or the recorder is dropped)
+    pub fn write(&mut self, record: &Record) -> Result<(), Error> {
+        let line = serde_json::to_string(record)
+            .map_err(|e| Error::InvalidRecording(e.to_string()))?;
+
+        self.w.write_line(&line)
+    }
+
+    /// Flush buffered records to disk
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.w.flush()
+    }
+}
+
+/// Read all records from a recording file
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Record>, Error> {
+    let lines = lines::read(path.as_ref())?;
+
+    let records = lines::parse(&lines, |l| serde_json::from_str(l).map_err(|e| e.to_string()))?;
+
+    match records.first() {
+        Some(Record::Header{ version, .. }) if *version <= FORMAT_VERSION => Ok(records),
+        Some(Record::Header{ version, .. }) => Err(Error::InvalidRecording(format!("unsupported version {}", version))),
+        _ => Err(Error::InvalidRecording("missing header".to_string())),
+    }
+}
+
+/// Read a session recording or CSV / JSON Lines reading log, reading logs are
+/// returned as reading records
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<Record>, Error> {
+    match is_session(path.as_ref())? {
+        true => read(path),
+        false => Ok(sink::read(path)?.into_iter().map(Record::Reading).collect()),
+    }
+}
+
+/// Check a recording start time is a wall-clock time that can be exported. Reading
+/// logs with only elapsed times are timestamped from the unix epoch so are rejected.
+pub(crate) fn check_dated(start: SystemTime) -> Result<(), Error> {
+    match start >= SystemTime::UNIX_EPOCH + EARLIEST_EXPORT {
+        true => Ok(()),
+        false => Err(Error::InvalidRecording("no wall-clock timestamps, reading logs must include the timestamp column to be exported".to_string())),
+    }
+}
+
+/// Check whether a file is a session recording (starting with a header record)
+/// rather than a reading log
+fn is_session(path: &Path) -> Result<bool, Error> {
+    let f = File::open(path)?;
+
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        return Ok(matches!(serde_json::from_str(&line), Ok(Record::Header{ .. })));
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::tests::temp_path;
+    use super::*;
+
+    fn reading(secs: u64) -> Record {
+        Record::Reading(Reading{
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            spo2: Some(97.0),
+            pulse_rate: Some(72.0),
+            perfusion_index: None,
+            status: Default::default(),
+        })
+    }
+
+    #[test]
+    fn corrupt_timestamp() {
+        let path = temp_path("corrupt-timestamp.jsonl");
+
+        let mut r = Recorder::create(&path).unwrap();
+        r.write(&reading(1)).unwrap();
+        r.flush().unwrap();
+        drop(r);
+
+        // Out of range timestamps are reported as an invalid recording rather than panicking
+        let mut data = std::fs::read_to_string(&path).unwrap();
+        data.push_str("{\"type\":\"connected\",\"timestamp\":1e30,\"address\":\"01:02:03:04:05:06\"}\n");
+        data.push_str("{\"type\":\"disconnected\",\"timestamp\":2.0,\"address\":\"01:02:03:04:05:06\"}\n");
+        std::fs::write(&path, data).unwrap();
+
+        let e = read(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(&e, Error::InvalidRecording(m) if m.starts_with("line 3:") && m.contains("invalid timestamp")), "{:?}", e);
+    }
+}
+
@@ -0,0 +1,363 @@
+//! Replay of recorded sessions and reading logs
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use structopt::StructOpt;
+
+use crate::{Error, Event, Measurement, Reading, PlethSample};
+use crate::recording::{self, Record};
+
+
+/// Interval between the final record of one pass and the first of the next, when looping
+const LOOP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replay speed
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Speed {
+    /// Multiple of real time, 1 replays with the original spacing between records
+    Factor(f64),
+    /// As fast as the consumer reads
+    Max,
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Speed::Factor(v) => write!(f, "{}x", v),
+            Speed::Max => write!(f, "max"),
+        }
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "max" {
+            return Ok(Speed::Max);
+        }
+
+        match s.trim_end_matches('x').parse::<f64>() {
+            Ok(v) if v.is_finite() && v > 0.0 => Ok(Speed::Factor(v)),
+            _ => Err(format!("Invalid speed '{}' (expected a multiple such as 1, 60x or max)", s)),
+        }
+    }
+}
+
+/// Replay options
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct ReplayOptions {
+    /// Replay speed, a multiple of real time (eg. 1, 60x) or max for as fast as possible
+    #[structopt(long, default_value="1")]
+    pub speed: Speed,
+
+    /// Restart from the seek position once the end of the recording is reached
+    #[structopt(long="loop")]
+    pub repeat: bool,
+
+    /// Start replay at this offset from the start of the recording
+    #[structopt(long)]
+    pub seek: Option<humantime::Duration>,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self{
+            speed: Speed::Factor(1.0),
+            repeat: false,
+            seek: None,
+        }
+    }
+}
+
+/// Replays a recorded session or reading log as supervisor [`Event`]s, or through
+/// the same measurement streams as a connected [`Sensor`](crate::Sensor)
+#[derive(Debug, Clone)]
+pub struct Replay {
+    records: Arc<Vec<Record>>,
+    opts: ReplayOptions,
+}
+
+impl Replay {
+    /// Load a session recording or CSV / JSON Lines reading log for replay
+    pub fn open(path: impl AsRef<Path>, opts: ReplayOptions) -> Result<Self, Error> {
+        let records = recording::load(path)?;
+
+        Ok(Self::new(records, opts))
+    }
+
+    /// Create a replay from previously loaded records
+    pub fn new(records: Vec<Record>, opts: ReplayOptions) -> Self {
+        Self{ records: Arc::new(records), opts }
+    }
+
+    /// Fetch the loaded records
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Replay events at the configured speed, preserving the relative spacing between records.
+    ///
+    /// When looping, each pass is shifted forward by the length of the replayed records
+    /// so timestamps continue to increase.
+    pub fn events(&self) -> BoxStream<'static, Event> {
+        let records = self.records.clone();
+        let speed = self.opts.speed;
+
+        // Skip the header, the remaining records are replayed from the seek position
+        let first = records.iter().position(|r| !matches!(r, Record::Header{ .. })).unwrap_or(records.len());
+        let start = match (self.opts.seek, records.get(first)) {
+            (Some(seek), Some(r)) => {
+                let at = r.timestamp() + *seek;
+                records[first..].iter()
+                    .position(|r| is_measurement(r) && r.timestamp() >= at)
+                    .map(|i| first + i)
+                    .unwrap_or(records.len())
+            },
+            _ => first,
+        };
+
+        // Device and connection records before the seek position are emitted immediately
+        let prelude: Vec<_> = records[first..start].iter()
+            .filter(|r| !is_measurement(r))
+            .filter_map(|r| r.to_event())
+            .collect();
+
+        // Only loop where a pass produces events, otherwise the replay would never yield
+        let repeat = self.opts.repeat && records[start..].iter().any(|r| r.to_event().is_some());
+
+        let period = {
+            let replayed = records[start..].iter().map(|r| r.timestamp());
+            match (replayed.clone().min(), replayed.max()) {
+                (Some(first), Some(last)) => last.duration_since(first).unwrap_or_default() + LOOP_INTERVAL,
+                _ => LOOP_INTERVAL,
+            }
+        };
+
+        let state = (start, None, Duration::ZERO);
+        let events = stream::unfold(state, move |(i, last, offset): (usize, Option<SystemTime>, Duration)| {
+            let records = records.clone();
+
+            async move {
+                let (i, offset) = match i < records.len() {
+                    true => (i, offset),
+                    false if repeat => (start, offset + period),
+                    false => return None,
+                };
+
+                let r = match offset.is_zero() {
+                    true => records[i].clone(),
+                    false => shift(&records[i], offset),
+                };
+
+                // Delay by the (scaled) time elapsed since the previous record
+                if let (Some(l), Speed::Factor(f)) = (last, speed) {
+                    let delay = r.timestamp().duration_since(l).unwrap_or_default();
+                    let delay = Duration::try_from_secs_f64(delay.as_secs_f64() / f).unwrap_or(Duration::MAX);
+                    tokio::time::sleep(delay).await;
+                }
+
+                Some((r.to_event(), (i + 1, Some(r.timestamp()), offset)))
+            }
+        })
+        .filter_map(|e| async move { e });
+
+        Box::pin(stream::iter(prelude).chain(events))
+    }
+
+    /// Replay measurements, equivalent to [`Sensor::measurements`](crate::Sensor::measurements)
+    pub async fn measurements(&self) -> Result<BoxStream<'static, Measurement>, Error> {
+        let measurements = self.events().filter_map(|e| async move {
+            match e {
+                Event::Measurement(m) => Some(m),
+                _ => None,
+            }
+        });
+
+        Ok(Box::pin(measurements))
+    }
+
+    /// Replay numeric SpO2 / pulse rate readings, equivalent to [`Sensor::readings`](crate::Sensor::readings)
+    pub async fn readings(&self) -> Result<BoxStream<'static, Reading>, Error> {
+        let readings = self.measurements().await?.filter_map(|m| async move {
+            match m {
+                Measurement::Reading(r) => Some(r),
+                _ => None,
+            }
+        });
+
+        Ok(Box::pin(readings))
+    }
+
+    /// Replay plethysmograph waveform samples, equivalent to [`Sensor::waveform`](crate::Sensor::waveform)
+    pub async fn waveform(&self) -> Result<BoxStream<'static, PlethSample>, Error> {
+        let samples = self.measurements().await?.filter_map(|m| async move {
+            match m {
+                Measurement::Pleth(p) => Some(p),
+                _ => None,
+            }
+        });
+
+        Ok(Box::pin(samples))
+    }
+}
+
+fn is_measurement(r: &Record) -> bool {
+    matches!(r, Record::Reading(_) | Record::Pleth(_))
+}
+
+/// Shift the timestamps of a record forward
+fn shift(r: &Record, by: Duration) -> Record {
+    let mut r = r.clone();
+    match &mut r {
+        Record::Header{ created: t, .. }
+        | Record::Device{ timestamp: t, .. }
+        | Record::Connected{ timestamp: t, .. }
+        | Record::Disconnected{ timestamp: t, .. }
+        | Record::Reconnected{ timestamp: t, .. } => *t += by,
+        Record::Reading(v) => v.timestamp += by,
+        Record::Pleth(p) => p.timestamp += by,
+        Record::Alarm(a) => a.timestamp += by,
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use crate::Address;
+    use crate::alarm::{AlarmEvent, AlarmState, Condition, Priority};
+    use super::*;
+
+    const START: u64 = 1_760_745_600;
+    const ADDRESS: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(START + secs)
+    }
+
+    fn reading(secs: u64, spo2: f32) -> Record {
+        Record::Reading(Reading{
+            timestamp: at(secs),
+            spo2: Some(spo2),
+            pulse_rate: Some(72.0),
+            perfusion_index: None,
+            status: Default::default(),
+        })
+    }
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record::header(),
+            Record::Connected{ timestamp: at(0), address: ADDRESS },
+            reading(0, 97.0),
+            reading(2, 96.0),
+            reading(3, 95.0),
+        ]
+    }
+
+    /// Collect events with the (paused) time elapsed since the replay started
+    async fn timed(replay: &Replay, n: usize) -> Vec<(Duration, Event)> {
+        let now = Instant::now();
+        replay.events().take(n)
+            .map(|e| (now.elapsed(), e))
+            .collect().await
+    }
+
+    fn timestamp(e: &Event) -> SystemTime {
+        match e {
+            Event::Connected{ timestamp, .. } => *timestamp,
+            Event::Measurement(Measurement::Reading(r)) => r.timestamp,
+            e => panic!("Unexpected event {:?}", e),
+        }
+    }
+
+    /// Check elapsed times are within the timer resolution of the expected times (ms)
+    fn assert_spacing(events: &[(Duration, Event)], expected: &[u64]) {
+        let elapsed: Vec<_> = events.iter().map(|(d, _)| d.as_millis() as u64).collect();
+        assert_eq!(elapsed.len(), expected.len());
+        for (e, x) in elapsed.iter().zip(expected) {
+            assert!(e.abs_diff(*x) <= 1, "elapsed {:?}, expected {:?}", elapsed, expected);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn real_time() {
+        let replay = Replay::new(records(), ReplayOptions::default());
+
+        let events = timed(&replay, 10).await;
+        assert_spacing(&events, &[0, 0, 2000, 3000]);
+
+        let timestamps: Vec<_> = events.iter().map(|(_, e)| timestamp(e)).collect();
+        assert_eq!(timestamps, [at(0), at(0), at(2), at(3)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scaled() {
+        let replay = Replay::new(records(), ReplayOptions{ speed: Speed::Factor(60.0), ..Default::default() });
+
+        let events = timed(&replay, 10).await;
+        assert_spacing(&events, &[0, 0, 33, 50]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_speed() {
+        let replay = Replay::new(records(), ReplayOptions{ speed: Speed::Max, ..Default::default() });
+
+        let events = timed(&replay, 10).await;
+        assert_spacing(&events, &[0, 0, 0, 0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn extreme_speed() {
+        // Scaled delays beyond the representable range are clamped rather than panicking
+        let replay = Replay::new(records(), ReplayOptions{ speed: Speed::Factor(f64::MIN_POSITIVE), ..Default::default() });
+
+        let events = timed(&replay, 3).await;
+        assert_eq!(events.len(), 3);
+        assert!(events[2].0 > Duration::from_secs(365 * 24 * 3600));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn seek() {
+        let opts = ReplayOptions{ seek: Some(Duration::from_secs(1).into()), ..Default::default() };
+        let replay = Replay::new(records(), opts);
+
+        // Connection events before the seek position are emitted immediately
+        let events = timed(&replay, 10).await;
+        assert_spacing(&events, &[0, 0, 1000]);
+
+        let timestamps: Vec<_> = events.iter().map(|(_, e)| timestamp(e)).collect();
+        assert!(matches!(events[0].1, Event::Connected{ .. }));
+        assert_eq!(timestamps, [at(0), at(2), at(3)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeat() {
+        let replay = Replay::new(records(), ReplayOptions{ repeat: true, ..Default::default() });
+
+        // Each pass is shifted by the recording length plus the loop interval
+        let events = timed(&replay, 9).await;
+        assert_spacing(&events, &[0, 0, 2000, 3000, 4000, 4000, 6000, 7000, 8000]);
+
+        let timestamps: Vec<_> = events.iter().map(|(_, e)| timestamp(e)).collect();
+        assert_eq!(timestamps, [at(0), at(0), at(2), at(3), at(4), at(4), at(6), at(7), at(8)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeat_without_events() {
+        let alarm = AlarmEvent{ timestamp: at(0), condition: Condition::LowSpo2, priority: Priority::Medium, state: AlarmState::Active, value: None };
+        let opts = ReplayOptions{ speed: Speed::Max, repeat: true, ..Default::default() };
+        let replay = Replay::new(vec![Record::header(), Record::Alarm(alarm)], opts);
+
+        // Replay ends rather than looping over records without events
+        let events: Vec<_> = replay.events().collect().await;
+        assert!(events.is_empty());
+    }
+}
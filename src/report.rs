@@ -0,0 +1,370 @@
+//! Self-contained HTML overnight reports, with inline SVG trend charts.
+//!
+//! Reports are generated entirely from a recording, with no external scripts,
+//! stylesheets or fonts, so they can be archived or emailed as a single file.
+
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
+
+use crate::analysis::{self, Summary};
+use crate::recording::Record;
+
+
+/// Chart dimensions, in SVG user units
+const WIDTH: f64 = 1000.0;
+const HEIGHT: f64 = 200.0;
+const MARGIN: f64 = 40.0;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.5em; }
+h2 { font-size: 1.2em; margin-top: 1.5em; }
+table { border-collapse: collapse; }
+td, th { padding: 0.2em 1em 0.2em 0; text-align: left; }
+th { font-weight: normal; color: #666; }
+svg { width: 100%; max-width: 1080px; height: auto; }
+svg text { font-size: 11px; fill: #666; }
+.legend span { display: inline-block; width: 1em; height: 1em; vertical-align: middle; margin: 0 0.3em 0 1em; }
+";
+
+/// Generate an HTML report for a recorded session
+pub fn html(records: &[Record], summary: &Summary) -> String {
+    let samples = analysis::resample(records);
+    let mut h = String::new();
+
+    let _ = write!(h, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Overnight oximetry report {}</title>\n<style>{}</style>\n</head>\n<body>\n",
+        local(summary.start, "%Y-%m-%d"), STYLE);
+    let _ = writeln!(h, "<h1>Overnight oximetry report</h1>");
+    let _ = writeln!(h, "<p>{} to {}</p>", local(summary.start, "%Y-%m-%d %H:%M"), local(summary.end, "%Y-%m-%d %H:%M"));
+
+    h += &device(records);
+    h += &statistics(summary);
+
+    let gaps = gaps(records, summary.end);
+    let desaturations: Vec<_> = summary.desaturations.events.iter().map(|e| (e.start, e.end)).collect();
+
+    // SpO2 on a fixed scale down to at least 80%, keeping nadirs in view
+    let spo2_min = summary.spo2.as_ref().map(|s| (s.nadir - 2.0).floor().min(80.0)).unwrap_or(80.0);
+    let spo2: Vec<_> = samples.iter().map(|s| (s.0, analysis::spo2(s))).collect();
+
+    let _ = writeln!(h, "<h2>SpO2 (%)</h2>");
+    h += &chart(summary, &bucket(&spo2, summary, Bucket::Min), (spo2_min as f64, 100.0), &desaturations, &gaps, "#1f77b4");
+
+    let pulse: Vec<_> = samples.iter().map(|s| (s.0, analysis::pulse_rate(s))).collect();
+    let (pmin, pmax) = summary.pulse_rate.as_ref()
+        .map(|p| (((p.min - 10.0) / 10.0).floor() * 10.0, ((p.max + 10.0) / 10.0).ceil() * 10.0))
+        .unwrap_or((40.0, 120.0));
+
+    let _ = writeln!(h, "<h2>Pulse rate (bpm)</h2>");
+    h += &chart(summary, &bucket(&pulse, summary, Bucket::Mean), (pmin as f64, pmax as f64), &desaturations, &gaps, "#d62728");
+
+    let _ = writeln!(h, "<p class=\"legend\"><span style=\"background: #f4cccc\"></span>Desaturation<span style=\"background: #ddd\"></span>Disconnected</p>");
+
+    h += &histogram(summary);
+    h += &events(summary);
+    h += &burden(summary);
+
+    let _ = write!(h, "<p><small>Generated by {} {}</small></p>\n</body>\n</html>\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+
+    h
+}
+
+/// Device and firmware details, from the last device record
+fn device(records: &[Record]) -> String {
+    let mut h = String::new();
+
+    let (address, name, protocol, info) = match records.iter().rev().find_map(|r| match r {
+        Record::Device{ address, name, protocol, info, .. } => Some((address, name, protocol, info)),
+        _ => None,
+    }) {
+        Some(d) => d,
+        None => return h,
+    };
+
+    let _ = writeln!(h, "<h2>Device</h2>\n<table>");
+    for (k, v) in [
+        ("Name", name.as_deref()),
+        ("Address", Some(address.to_string().as_str())),
+        ("Protocol", protocol.as_deref()),
+        ("Manufacturer", info.manufacturer.as_deref()),
+        ("Model", info.model.as_deref()),
+        ("Serial", info.serial.as_deref()),
+        ("Hardware", info.hardware_revision.as_deref()),
+        ("Firmware", info.firmware_revision.as_deref()),
+        ("Software", info.software_revision.as_deref()),
+    ] {
+        if let Some(v) = v {
+            let _ = writeln!(h, "<tr><th>{}</th><td>{}</td></tr>", k, escape(v));
+        }
+    }
+    let _ = writeln!(h, "</table>");
+
+    h
+}
+
+fn statistics(s: &Summary) -> String {
+    let mut rows: Vec<(&str, String)> = vec![
+        ("Recording time", duration(s.end.duration_since(s.start).unwrap_or_default())),
+        ("Valid time", duration(s.valid_time)),
+    ];
+
+    if let Some(v) = &s.spo2 {
+        rows.extend([
+            ("Mean SpO2", format!("{:.1} %", v.mean)),
+            ("Median SpO2", format!("{:.1} %", v.median)),
+            ("Nadir SpO2", format!("{:.0} % at {}", v.nadir, local(v.nadir_time, "%H:%M:%S"))),
+            ("T90", duration(v.t90)),
+            ("T88", duration(v.t88)),
+            ("CT90", format!("{:.1} %", v.ct90)),
+        ]);
+    }
+
+    if let Some(p) = &s.pulse_rate {
+        rows.push(("Pulse rate min / mean / max", format!("{:.0} / {:.0} / {:.0} bpm", p.min, p.mean, p.max)));
+    }
+
+    rows.extend([
+        ("Desaturations", s.desaturations.events.len().to_string()),
+        ("ODI3", format!("{:.1} /h", s.desaturations.odi3)),
+        ("ODI4", format!("{:.1} /h", s.desaturations.odi4)),
+        ("Hypoxic burden (ensemble)", format!("{:.1} %·min/h", s.hypoxic_burden.ensemble_averaged.burden)),
+        ("Hypoxic burden (baseline area)", format!("{:.1} %·min/h", s.hypoxic_burden.baseline_area.burden)),
+        ("Disconnects", s.disconnects.to_string()),
+        ("Probe off", s.probe_off.to_string()),
+    ]);
+
+    let mut h = String::from("<h2>Summary</h2>\n<table>\n");
+    for (k, v) in rows {
+        let _ = writeln!(h, "<tr><th>{}</th><td>{}</td></tr>", k, v);
+    }
+    h += "</table>\n";
+
+    h
+}
+
+/// SpO2 histogram as a horizontal bar chart
+fn histogram(s: &Summary) -> String {
+    let v = match &s.spo2 {
+        Some(v) if !v.histogram.is_empty() => v,
+        _ => return String::new(),
+    };
+
+    let total = s.valid_time.as_secs_f64().max(1.0);
+    let row = 14.0;
+    let height = row * v.histogram.len() as f64;
+
+    let mut h = String::from("<h2>Time at SpO2</h2>\n");
+    let _ = writeln!(h, "<svg viewBox=\"0 0 {} {}\">", WIDTH, height + row);
+
+    for (i, b) in v.histogram.iter().rev().enumerate() {
+        let pct = b.time.as_secs_f64() * 100.0 / total;
+        let y = i as f64 * row;
+        let _ = writeln!(h, "<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">{} %</text>", MARGIN - 5.0, y + row - 3.0, b.spo2);
+        let _ = writeln!(h, "<rect x=\"{}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#1f77b4\"/>", MARGIN, y + 1.0, pct / 100.0 * (WIDTH - 2.0 * MARGIN - 80.0), row - 2.0);
+        let _ = writeln!(h, "<text x=\"{:.1}\" y=\"{:.1}\">{:.1} % ({})</text>", MARGIN + 5.0 + pct / 100.0 * (WIDTH - 2.0 * MARGIN - 80.0), y + row - 3.0, pct, duration(b.time));
+    }
+
+    h += "</svg>\n";
+    h
+}
+
+/// Desaturation event table
+fn events(s: &Summary) -> String {
+    let events = &s.desaturations.events;
+    if events.is_empty() {
+        return String::new();
+    }
+
+    let mut h = String::from("<h2>Desaturation events</h2>\n<table>\n<tr><th>Start</th><th>Duration</th><th>Baseline</th><th>Nadir</th><th>Drop</th></tr>\n");
+    for e in events {
+        let _ = writeln!(h, "<tr><td>{}</td><td>{}</td><td>{:.0} %</td><td>{:.0} %</td><td>{:.1} %</td></tr>",
+            local(e.start, "%H:%M:%S"), duration(e.duration()), e.baseline, e.nadir, e.depth());
+    }
+    h += "</table>\n";
+
+    h
+}
+
+/// Hypoxic burden event windows
+fn burden(s: &Summary) -> String {
+    let b = &s.hypoxic_burden.ensemble_averaged;
+    let (from, to) = match b.search_window {
+        Some(w) if !b.windows.is_empty() => w,
+        _ => return String::new(),
+    };
+
+    let mut h = String::from("<h2>Hypoxic burden windows</h2>\n");
+    let _ = writeln!(h, "<p>Ensemble search window {:+} s to {:+} s from each desaturation nadir</p>", from, to);
+    h += "<table>\n<tr><th>Start</th><th>End</th><th>Baseline</th><th>Area</th></tr>\n";
+    for w in &b.windows {
+        let _ = writeln!(h, "<tr><td>{}</td><td>{}</td><td>{:.0} %</td><td>{:.2} %·min</td></tr>",
+            local(w.start, "%H:%M:%S"), local(w.end, "%H:%M:%S"), w.baseline, w.area);
+    }
+    h += "</table>\n";
+
+    h
+}
+
+/// Trend chart with shaded desaturations and disconnection gaps
+fn chart(s: &Summary, points: &[(SystemTime, Option<f64>)], range: (f64, f64), shaded: &[(SystemTime, SystemTime)], gaps: &[(SystemTime, SystemTime)], colour: &str) -> String {
+    let span = s.end.duration_since(s.start).unwrap_or_default().as_secs_f64().max(1.0);
+    let x = |t: SystemTime| MARGIN + t.duration_since(s.start).unwrap_or_default().as_secs_f64() / span * (WIDTH - 2.0 * MARGIN);
+    let y = |v: f64| HEIGHT - MARGIN / 2.0 - (v.clamp(range.0, range.1) - range.0) / (range.1 - range.0) * (HEIGHT - MARGIN);
+
+    let mut h = String::new();
+    let _ = writeln!(h, "<svg viewBox=\"0 0 {} {}\">", WIDTH, HEIGHT + MARGIN / 2.0);
+
+    // Shaded regions behind the trace
+    for (fill, regions) in [("#ddd", gaps), ("#f4cccc", shaded)] {
+        for (a, b) in regions {
+            let _ = writeln!(h, "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\"/>",
+                x(*a), y(range.1), (x(*b) - x(*a)).max(1.0), y(range.0) - y(range.1), fill);
+        }
+    }
+
+    // Horizontal grid, five divisions
+    for i in 0..=5 {
+        let v = range.0 + (range.1 - range.0) * i as f64 / 5.0;
+        let _ = writeln!(h, "<line x1=\"{}\" x2=\"{}\" y1=\"{:.1}\" y2=\"{:.1}\" stroke=\"#eee\"/>", MARGIN, WIDTH - MARGIN, y(v), y(v));
+        let _ = writeln!(h, "<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">{:.0}</text>", MARGIN - 5.0, y(v) + 4.0, v);
+    }
+
+    // Hourly time axis labels
+    let since_epoch = s.start.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut t = SystemTime::UNIX_EPOCH + Duration::from_secs((since_epoch / 3600 + 1) * 3600);
+    while t < s.end {
+        let _ = writeln!(h, "<line x1=\"{:.1}\" x2=\"{:.1}\" y1=\"{:.1}\" y2=\"{:.1}\" stroke=\"#eee\"/>", x(t), x(t), y(range.1), y(range.0));
+        let _ = writeln!(h, "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>", x(t), HEIGHT + 5.0, local(t, "%H:%M"));
+        t += Duration::from_secs(3600);
+    }
+
+    // Trace, broken where there is no valid data
+    let mut segment: Vec<String> = vec![];
+    for (t, v) in points.iter().chain(std::iter::once(&(s.end, None))) {
+        match v {
+            Some(v) => segment.push(format!("{:.1},{:.1}", x(*t), y(*v))),
+            None if !segment.is_empty() => {
+                let _ = writeln!(h, "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"1\"/>", segment.join(" "), colour);
+                segment.clear();
+            },
+            None => (),
+        }
+    }
+
+    h += "</svg>\n";
+    h
+}
+
+enum Bucket {
+    Min,
+    Mean,
+}
+
+/// Reduce a series to one point per chart unit, so long recordings remain compact.
+/// SpO2 uses the bucket minimum so desaturation nadirs are preserved.
+fn bucket(values: &[(SystemTime, Option<f32>)], s: &Summary, mode: Bucket) -> Vec<(SystemTime, Option<f64>)> {
+    let span = s.end.duration_since(s.start).unwrap_or_default().as_secs_f64();
+    let size = (span / (WIDTH - 2.0 * MARGIN)).ceil().max(1.0) as usize;
+
+    values.chunks(size).map(|c| {
+        let valid: Vec<_> = c.iter().filter_map(|(_, v)| v.map(|v| v as f64)).collect();
+
+        let v = match (valid.is_empty(), &mode) {
+            (true, _) => None,
+            (false, Bucket::Min) => Some(valid.iter().cloned().fold(f64::INFINITY, f64::min)),
+            (false, Bucket::Mean) => Some(valid.iter().sum::<f64>() / valid.len() as f64),
+        };
+
+        (c[0].0, v)
+    }).collect()
+}
+
+/// Periods the sensor was disconnected, from each disconnection to the next reading
+fn gaps(records: &[Record], end: SystemTime) -> Vec<(SystemTime, SystemTime)> {
+    let mut gaps = vec![];
+    let mut from = None;
+
+    for r in records {
+        match r {
+            Record::Disconnected{ timestamp, .. } => { from.get_or_insert(*timestamp); },
+            Record::Reading(v) => if let Some(f) = from.take() {
+                gaps.push((f, v.timestamp));
+            },
+            _ => (),
+        }
+    }
+
+    if let Some(f) = from {
+        gaps.push((f, end));
+    }
+
+    gaps
+}
+
+fn local(t: SystemTime, format: &str) -> String {
+    let t: DateTime<Local> = t.into();
+    t.format(format).to_string()
+}
+
+fn duration(d: Duration) -> String {
+    let s = d.as_secs();
+    format!("{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Address, DeviceInfo, Reading, Status};
+    use crate::analysis::DesaturationOptions;
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_760_745_600 + secs)
+    }
+
+    fn reading(t: u64, spo2: f32) -> Record {
+        Record::Reading(Reading{ timestamp: at(t), spo2: Some(spo2), pulse_rate: Some(72.0), perfusion_index: None, status: Status::default() })
+    }
+
+    /// Five minutes at 96 % with a 20 s desaturation to 90 % and a 30 s disconnection
+    fn records() -> Vec<Record> {
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let info = DeviceInfo{ firmware_revision: Some("1.0 & \"beta\"".to_string()), ..Default::default() };
+
+        let mut records = vec![
+            Record::Device{ timestamp: at(0), address, name: Some("<script>alert(1)</script>".to_string()), address_type: None, protocol: None, info },
+        ];
+        records.extend((0..200).map(|t| reading(t, if (130..150).contains(&t) { 90.0 } else { 96.0 })));
+        records.push(Record::Disconnected{ timestamp: at(200), address });
+        records.extend((230..300).map(|t| reading(t, 96.0)));
+
+        records
+    }
+
+    #[test]
+    fn html_report() {
+        let records = records();
+        let summary = Summary::from_records(&records, &DesaturationOptions::default());
+        assert_eq!(summary.desaturations.events.len(), 1);
+
+        let h = html(&records, &summary);
+
+        // Self-contained, with SpO2, pulse rate and histogram charts
+        assert!(!h.contains("http"));
+        assert_eq!(h.matches("<svg").count(), 3);
+
+        // Desaturations and gaps shaded on both trend charts
+        assert_eq!(h.matches("fill=\"#f4cccc\"").count(), 2);
+        assert_eq!(h.matches("fill=\"#ddd\"").count(), 2);
+
+        // Device details are escaped
+        assert!(!h.contains("<script"));
+        assert!(h.contains("<td>&lt;script&gt;alert(1)&lt;/script&gt;</td>"));
+        assert!(h.contains("<td>1.0 &amp; &quot;beta&quot;</td>"));
+    }
+}
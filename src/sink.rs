@@ -0,0 +1,558 @@
+//! CSV and JSON Lines reading logs, for use with external tools.
+//!
+//! Unlike session [recordings](crate::recording) these contain only readings, with
+//! a configurable set of fields and timestamp formats.
+//! Logs can be read back with [`read`], for replay or analysis.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use structopt::StructOpt;
+
+use crate::{Error, Address, Reading, Status};
+use crate::lines::{self, LineWriter};
+use crate::reading::unix_time;
+
+
+/// Reading log format
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SinkFormat {
+    /// Comma separated values, with a header row
+    Csv,
+    /// Newline delimited JSON objects
+    Jsonl,
+}
+
+impl fmt::Display for SinkFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkFormat::Csv => write!(f, "csv"),
+            SinkFormat::Jsonl => write!(f, "jsonl"),
+        }
+    }
+}
+
+impl FromStr for SinkFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(SinkFormat::Csv),
+            "jsonl" | "ndjson" => Ok(SinkFormat::Jsonl),
+            _ => Err(format!("Unrecognised format '{}' (expected csv or jsonl)", s)),
+        }
+    }
+}
+
+/// Reading field written to a log
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Field {
+    /// Oxygen saturation
+    Spo2,
+    /// Pulse rate
+    PulseRate,
+    /// Perfusion index
+    PerfusionIndex,
+    /// Status flags, one column per flag
+    Status,
+    /// Device address
+    Address,
+}
+
+impl Field {
+    /// All fields, in output order
+    pub const ALL: &'static [Field] = &[Field::Address, Field::Spo2, Field::PulseRate, Field::PerfusionIndex, Field::Status];
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spo2" => Ok(Field::Spo2),
+            "pulse" | "pulse_rate" => Ok(Field::PulseRate),
+            "pi" | "perfusion_index" => Ok(Field::PerfusionIndex),
+            "status" => Ok(Field::Status),
+            "address" => Ok(Field::Address),
+            _ => Err(format!("Unrecognised field '{}' (expected spo2, pulse, pi, status or address)", s)),
+        }
+    }
+}
+
+/// Set of fields written to a log, parsed from a comma separated list
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Fields(pub Vec<Field>);
+
+impl Fields {
+    fn contains(&self, f: Field) -> bool {
+        self.0.contains(&f)
+    }
+}
+
+impl Default for Fields {
+    fn default() -> Self {
+        Fields(Field::ALL.to_vec())
+    }
+}
+
+impl FromStr for Fields {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split(',')
+            .map(|f| f.trim())
+            .filter(|f| !f.is_empty())
+            .map(Field::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Normalise to output order
+        Ok(Fields(Field::ALL.iter().filter(|f| fields.contains(f)).cloned().collect()))
+    }
+}
+
+/// Timestamp format written to a log
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TimestampMode {
+    /// Wall-clock time (RFC 3339, UTC)
+    Wall,
+    /// Monotonic seconds since the log was created, unaffected by clock changes
+    Monotonic,
+    /// Both wall-clock and monotonic timestamps
+    Both,
+}
+
+impl FromStr for TimestampMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wall" => Ok(TimestampMode::Wall),
+            "monotonic" => Ok(TimestampMode::Monotonic),
+            "both" => Ok(TimestampMode::Both),
+            _ => Err(format!("Unrecognised timestamp mode '{}' (expected wall, monotonic or both)", s)),
+        }
+    }
+}
+
+/// Reading log options
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct SinkOptions {
+    /// Fields to write (comma separated: spo2, pulse, pi, status, address)
+    #[structopt(long, default_value="spo2,pulse,pi,status,address")]
+    pub fields: Fields,
+
+    /// Timestamps to write (wall, monotonic or both)
+    #[structopt(long, default_value="wall")]
+    pub timestamps: TimestampMode,
+
+    /// Interval at which buffered readings are flushed to disk
+    #[structopt(long, default_value="1s")]
+    pub flush_interval: humantime::Duration,
+}
+
+impl Default for SinkOptions {
+    fn default() -> Self {
+        Self{
+            fields: Fields::default(),
+            timestamps: TimestampMode::Wall,
+            flush_interval: Duration::from_secs(1).into(),
+        }
+    }
+}
+
+/// Writes readings to a CSV or JSON Lines log.
+///
+/// Output is line buffered and flushed on drop, and when a reading is written once the
+/// configured flush interval has elapsed. A killed process never leaves more than a
+/// single partial line.
+///
+/// As flushing is only checked on write, readings buffered before the sensor stalls
+/// remain buffered until the next reading. Callers should also call
+/// [`ReadingSink::flush`] each flush interval (eg. from a `tokio::time::interval`) to
+/// bound the readings lost if the process is killed.
+#[derive(Debug)]
+pub struct ReadingSink {
+    w: LineWriter,
+    format: SinkFormat,
+    opts: SinkOptions,
+    start: Instant,
+    last_flush: Instant,
+}
+
+/// Status flag column names, in output order
+const STATUS_COLUMNS: &[&str] = &["probe_off", "searching", "low_signal", "motion", "sensor_fault", "low_battery", "low_quality"];
+
+impl ReadingSink {
+    /// Create a new reading log, writing a header row for CSV output
+    pub fn create(path: impl AsRef<Path>, format: SinkFormat, opts: SinkOptions) -> Result<Self, Error> {
+        let w = LineWriter::create(path.as_ref())?;
+
+        debug!("Logging readings to {} ({})", path.as_ref().display(), format);
+
+        let now = Instant::now();
+        let mut s = Self{ w, format, opts, start: now, last_flush: now };
+
+        if format == SinkFormat::Csv {
+            let header = s.columns().join(",");
+            s.w.write_line(&header)?;
+            s.flush()?;
+        }
+
+        Ok(s)
+    }
+
+    /// Column (or JSON key) names for the configured fields
+    pub fn columns(&self) -> Vec<&'static str> {
+        let mut c = vec![];
+
+        match self.opts.timestamps {
+            TimestampMode::Wall => c.push("timestamp"),
+            TimestampMode::Monotonic => c.push("elapsed"),
+            TimestampMode::Both => c.extend(["timestamp", "elapsed"]),
+        }
+
+        for f in &self.opts.fields.0 {
+            match f {
+                Field::Address => c.push("address"),
+                Field::Spo2 => c.push("spo2"),
+                Field::PulseRate => c.push("pulse_rate"),
+                Field::PerfusionIndex => c.push("perfusion_index"),
+                Field::Status => c.extend(STATUS_COLUMNS),
+            }
+        }
+
+        c
+    }
+
+    /// Write a reading, flushing if the flush interval has elapsed
+    pub fn write(&mut self, address: Option<Address>, r: &Reading) -> Result<(), Error> {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let fields = &self.opts.fields;
+
+        let mut values: Vec<Value> = vec![];
+
+        if self.opts.timestamps != TimestampMode::Monotonic {
+            values.push(Value::Str(humantime::format_rfc3339_millis(r.timestamp).to_string()));
+        }
+        if self.opts.timestamps != TimestampMode::Wall {
+            values.push(Value::Num(Some((elapsed * 1000.0).round() / 1000.0)));
+        }
+        if fields.contains(Field::Address) {
+            values.push(address.map(|a| Value::Str(a.to_string())).unwrap_or(Value::Num(None)));
+        }
+        if fields.contains(Field::Spo2) {
+            values.push(Value::num(r.spo2));
+        }
+        if fields.contains(Field::PulseRate) {
+            values.push(Value::num(r.pulse_rate));
+        }
+        if fields.contains(Field::PerfusionIndex) {
+            values.push(Value::num(r.perfusion_index));
+        }
+        if fields.contains(Field::Status) {
+            let s = &r.status;
+            values.extend([s.probe_off, s.searching, s.low_signal, s.motion, s.sensor_fault, s.low_battery, s.low_quality].map(Value::Bool));
+        }
+
+        let line = match self.format {
+            SinkFormat::Csv => values.iter().map(|v| v.csv()).collect::<Vec<_>>().join(","),
+            SinkFormat::Jsonl => {
+                let o: serde_json::Map<_, _> = self.columns().iter()
+                    .zip(values.iter())
+                    .map(|(k, v)| (k.to_string(), v.json()))
+                    .collect();
+                serde_json::Value::Object(o).to_string()
+            },
+        };
+
+        self.w.write_line(&line)?;
+
+        if self.last_flush.elapsed() >= *self.opts.flush_interval {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush buffered readings to disk
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.w.flush()?;
+        self.last_flush = Instant::now();
+
+        Ok(())
+    }
+}
+
+/// Output value, formatted per log format
+enum Value {
+    Str(String),
+    Num(Option<f64>),
+    Bool(bool),
+}
+
+impl Value {
+    /// Convert a reading value, rounding to avoid f32 artefacts (eg. 1.2999999523)
+    fn num(v: Option<f32>) -> Self {
+        Value::Num(v.map(|v| (v as f64 * 1000.0).round() / 1000.0))
+    }
+
+    fn csv(&self) -> String {
+        match self {
+            Value::Str(s) => s.clone(),
+            Value::Num(Some(v)) => v.to_string(),
+            Value::Num(None) => String::new(),
+            Value::Bool(b) => (*b as u8).to_string(),
+        }
+    }
+
+    fn json(&self) -> serde_json::Value {
+        match self {
+            Value::Str(s) => serde_json::Value::from(s.as_str()),
+            Value::Num(v) => serde_json::json!(v),
+            Value::Bool(b) => serde_json::Value::from(*b),
+        }
+    }
+}
+
+/// Read readings back from a CSV or JSON Lines reading log, detecting the format from
+/// the first line.
+///
+/// Readings are timestamped from the wall-clock column where present, otherwise from
+/// the monotonic column (relative to the unix epoch, so these logs can be replayed and
+/// analysed but not exported). Fields not included in the log are left empty.
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Reading>, Error> {
+    let lines = lines::read(path.as_ref())?;
+
+    // JSON Lines logs start with an object, CSV logs with a header row
+    let (header, rows): (Option<Vec<&str>>, _) = match lines.first() {
+        Some((_, l)) if l.trim_start().starts_with('{') => (None, &lines[..]),
+        Some((_, l)) => (Some(l.split(',').map(|c| c.trim()).collect()), &lines[1..]),
+        None => return Ok(vec![]),
+    };
+
+    lines::parse(rows, |line| {
+        let values = match &header {
+            Some(h) => {
+                let cells: Vec<_> = line.split(',').collect();
+                if cells.len() != h.len() {
+                    return Err(format!("expected {} columns, found {}", h.len(), cells.len()));
+                }
+                h.iter().zip(cells).map(|(k, v)| (k.to_string(), v.trim().to_string())).collect()
+            },
+            None => parse_json(line)?,
+        };
+        parse_reading(&values)
+    })
+}
+
+/// Parse a JSON Lines log entry to string values, null values are omitted
+fn parse_json(line: &str) -> Result<HashMap<String, String>, String> {
+    let o: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line)
+        .map_err(|e| e.to_string())?;
+
+    let values = o.into_iter().filter_map(|(k, v)| {
+        let v = match v {
+            serde_json::Value::String(s) => s,
+            serde_json::Value::Bool(b) => (b as u8).to_string(),
+            serde_json::Value::Number(n) => n.to_string(),
+            _ => return None,
+        };
+        Some((k, v))
+    }).collect();
+
+    Ok(values)
+}
+
+/// Parse a reading from log column values
+fn parse_reading(values: &HashMap<String, String>) -> Result<Reading, String> {
+    let get = |k: &str| values.get(k).map(|v| v.as_str()).filter(|v| !v.is_empty());
+
+    let num = |k: &str| match get(k) {
+        Some(v) => v.parse::<f32>().map(Some).map_err(|_| format!("invalid {} '{}'", k, v)),
+        None => Ok(None),
+    };
+    let flag = |k: &str| matches!(get(k), Some("1") | Some("true"));
+
+    let timestamp = match (get("timestamp"), get("elapsed")) {
+        (Some(t), _) => humantime::parse_rfc3339_weak(t).map_err(|e| format!("invalid timestamp '{}': {}", t, e))?,
+        (None, Some(e)) => {
+            e.parse::<f64>().ok()
+                .filter(|s| *s >= 0.0)
+                .and_then(unix_time::from_secs)
+                .ok_or_else(|| format!("invalid elapsed '{}'", e))?
+        },
+        (None, None) => return Err("missing timestamp".to_string()),
+    };
+
+    Ok(Reading{
+        timestamp,
+        spo2: num("spo2")?,
+        pulse_rate: num("pulse_rate")?,
+        perfusion_index: num("perfusion_index")?,
+        status: Status{
+            probe_off: flag("probe_off"),
+            searching: flag("searching"),
+            low_signal: flag("low_signal"),
+            motion: flag("motion"),
+            sensor_fault: flag("sensor_fault"),
+            low_battery: flag("low_battery"),
+            low_quality: flag("low_quality"),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::SystemTime;
+
+    use crate::tests::temp_path;
+    use super::*;
+
+    fn reading(millis: u64, spo2: Option<f32>) -> Reading {
+        Reading{
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(1_760_745_600_000 + millis),
+            spo2,
+            pulse_rate: Some(72.0),
+            perfusion_index: Some(1.3),
+            status: Status{ low_quality: spo2.is_none(), ..Default::default() },
+        }
+    }
+
+    fn options(fields: &str, timestamps: TimestampMode) -> SinkOptions {
+        SinkOptions{ fields: fields.parse().unwrap(), timestamps, ..Default::default() }
+    }
+
+    /// Write readings to a log, returning the log lines
+    fn write(name: &str, format: SinkFormat, opts: SinkOptions, readings: &[Reading]) -> Vec<String> {
+        let path = temp_path(name);
+
+        let mut s = ReadingSink::create(&path, format, opts).unwrap();
+        for r in readings {
+            s.write(Some(Address([1, 2, 3, 4, 5, 6])), r).unwrap();
+        }
+        drop(s);
+
+        let data = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        data.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn csv_fields() {
+        let lines = write("fields.csv", SinkFormat::Csv, options("spo2,address", TimestampMode::Wall), &[reading(0, Some(97.0)), reading(1000, None)]);
+
+        // Fields are written in a fixed order
+        assert_eq!(lines, vec![
+            "timestamp,address,spo2",
+            "2025-10-18T00:00:00.000Z,01:02:03:04:05:06,97",
+            "2025-10-18T00:00:01.000Z,01:02:03:04:05:06,",
+        ]);
+
+        let lines = write("default.csv", SinkFormat::Csv, SinkOptions::default(), &[reading(0, Some(97.0))]);
+        assert_eq!(lines, vec![
+            "timestamp,address,spo2,pulse_rate,perfusion_index,probe_off,searching,low_signal,motion,sensor_fault,low_battery,low_quality",
+            "2025-10-18T00:00:00.000Z,01:02:03:04:05:06,97,72,1.3,0,0,0,0,0,0,0",
+        ]);
+
+        assert!("spo2,bpm".parse::<Fields>().is_err());
+    }
+
+    #[test]
+    fn jsonl() {
+        let lines = write("log.jsonl", SinkFormat::Jsonl, options("spo2,pulse,status", TimestampMode::Wall), &[reading(0, None)]);
+        assert_eq!(lines.len(), 1);
+
+        let v: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(v["timestamp"], "2025-10-18T00:00:00.000Z");
+        assert_eq!(v["spo2"], serde_json::Value::Null);
+        assert_eq!(v["pulse_rate"], 72.0);
+        assert_eq!(v["low_quality"], true);
+        assert_eq!(v["probe_off"], false);
+        assert!(v.get("address").is_none() && v.get("perfusion_index").is_none());
+    }
+
+    #[test]
+    fn timestamp_modes() {
+        let lines = write("monotonic.csv", SinkFormat::Csv, options("spo2", TimestampMode::Monotonic), &[reading(0, Some(97.0))]);
+        assert_eq!(lines[0], "elapsed,spo2");
+
+        // Seconds since the log was created, independent of the reading timestamp
+        let (elapsed, spo2) = lines[1].split_once(',').unwrap();
+        assert!((0.0..1.0).contains(&elapsed.parse::<f64>().unwrap()));
+        assert_eq!(spo2, "97");
+
+        let lines = write("both.csv", SinkFormat::Csv, options("spo2", TimestampMode::Both), &[reading(0, Some(97.0))]);
+        assert_eq!(lines[0], "timestamp,elapsed,spo2");
+        assert!(lines[1].starts_with("2025-10-18T00:00:00.000Z,"));
+    }
+
+    #[test]
+    fn flush() {
+        let path = temp_path("flush.csv");
+        let mut s = ReadingSink::create(&path, SinkFormat::Csv, options("spo2", TimestampMode::Wall)).unwrap();
+
+        // Buffered until the flush interval elapses on a later write, or flushed explicitly
+        s.write(None, &reading(0, Some(97.0))).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        s.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        drop(s);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_back() {
+        let readings = vec![reading(0, Some(97.0)), reading(1000, None), reading(2500, Some(95.0))];
+
+        for (name, format, truncated) in [("back.csv", SinkFormat::Csv, "2025-10-18T00:00:03.000Z,01:02:03:04:05:06,9"), ("back.jsonl", SinkFormat::Jsonl, "{\"timestamp\":\"2025-10-18T00:00:03")] {
+            let path = temp_path(name);
+
+            let mut s = ReadingSink::create(&path, format, SinkOptions::default()).unwrap();
+            for r in &readings {
+                s.write(None, r).unwrap();
+            }
+            drop(s);
+
+            // A truncated final line, as left by a killed process, is ignored
+            let mut data = fs::read_to_string(&path).unwrap();
+            data.push_str(truncated);
+            fs::write(&path, &data).unwrap();
+
+            assert_eq!(read(&path).unwrap(), readings, "{}", name);
+
+            // Corrupt lines elsewhere are errors
+            let n = data.lines().count();
+            let last = data.lines().nth(n - 2).unwrap().to_string();
+            data.push_str(&format!("\n{}\n", last));
+            fs::write(&path, &data).unwrap();
+            assert!(matches!(read(&path), Err(Error::InvalidRecording(e)) if e.starts_with(&format!("line {}:", n))), "{}", name);
+
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn elapsed() {
+        let path = temp_path("elapsed.csv");
+
+        // Elapsed only logs are timestamped from the unix epoch, out of range values are errors
+        fs::write(&path, "elapsed,spo2\n0.5,97\n1e30,96\n2.5,95\n").unwrap();
+        let e = read(&path).unwrap_err();
+        assert!(matches!(&e, Error::InvalidRecording(m) if m == "line 3: invalid elapsed '1e30'"), "{:?}", e);
+
+        fs::write(&path, "elapsed,spo2\n0.5,97\n2.5,95\n").unwrap();
+        let readings = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let timestamps: Vec<_> = readings.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, [SystemTime::UNIX_EPOCH + Duration::from_millis(500), SystemTime::UNIX_EPOCH + Duration::from_millis(2500)]);
+    }
+}
@@ -0,0 +1,328 @@
+//! Connection supervisor, reconnects to a sensor with backoff when the connection drops
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use log::{debug, info, warn, error};
+use structopt::StructOpt;
+use tokio::sync::mpsc;
+
+use crate::{Sensor, Options, Transport, Address, DiscoveredDevice, DeviceInfo, Measurement};
+
+
+/// Reconnection options
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct ReconnectOptions {
+    /// Delay before the first reconnection attempt
+    #[structopt(long, default_value="1s")]
+    pub reconnect_initial: humantime::Duration,
+
+    /// Maximum delay between reconnection attempts
+    #[structopt(long, default_value="60s")]
+    pub reconnect_max: humantime::Duration,
+
+    /// Multiplier applied to the reconnection delay after each failed attempt (at least 1.0)
+    #[structopt(long, default_value="2.0", parse(try_from_str = parse_factor))]
+    pub reconnect_factor: f32,
+
+    /// Maximum number of consecutive failed attempts before giving up (0 for unlimited)
+    #[structopt(long, default_value="0")]
+    pub reconnect_attempts: usize,
+
+    /// Treat the connection as lost if no measurements are received for this period
+    #[structopt(long, default_value="30s")]
+    pub stall_timeout: humantime::Duration,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        Self {
+            reconnect_initial: Duration::from_secs(1).into(),
+            reconnect_max: Duration::from_secs(60).into(),
+            reconnect_factor: 2.0,
+            reconnect_attempts: 0,
+            stall_timeout: Duration::from_secs(30).into(),
+        }
+    }
+}
+
+/// Events emitted by a [`Supervisor`]
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    /// Initial connection to a sensor
+    Connected {
+        address: Address,
+        timestamp: SystemTime,
+    },
+    /// Device details, emitted following each connection
+    Device(Box<DeviceDetails>),
+    /// Measurement received from the connected sensor
+    Measurement(Measurement),
+    /// Connection to the sensor lost
+    Disconnected {
+        address: Address,
+        timestamp: SystemTime,
+    },
+    /// Connection to the same device re-established following a disconnection
+    Reconnected {
+        address: Address,
+        timestamp: SystemTime,
+        /// Number of attempts made before reconnecting
+        attempts: usize,
+    },
+}
+
+/// Details of a connected device
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeviceDetails {
+    pub device: DiscoveredDevice,
+    pub info: DeviceInfo,
+    /// Detected protocol name
+    pub protocol: Option<String>,
+}
+
+/// Supervises a [`Sensor`] connection, reconnecting to the same device with exponential
+/// backoff when the connection drops so a single measurement stream survives for the
+/// length of a session.
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    transport: Arc<dyn Transport>,
+    opts: Options,
+    reconnect: ReconnectOptions,
+    device: Option<DiscoveredDevice>,
+}
+
+impl Supervisor {
+    /// Create a new supervisor using the provided transport, scanning for the first
+    /// matching device and reconnecting to that device by address
+    pub fn new(transport: Arc<dyn Transport>, opts: Options, reconnect: ReconnectOptions) -> Self {
+        Self{ transport, opts, reconnect, device: None }
+    }
+
+    /// Create a supervisor for a specific device, reconnecting by address rather
+    /// than rescanning
+    pub fn for_device(transport: Arc<dyn Transport>, device: DiscoveredDevice, opts: Options, reconnect: ReconnectOptions) -> Self {
+        Self{ transport, opts, reconnect, device: Some(device) }
+    }
+
+    /// Open the transport selected by [`Options::backend`] and create a supervisor
+    pub async fn open(opts: Options, reconnect: ReconnectOptions) -> Result<Self, crate::Error> {
+        let transport = crate::transport::open(&opts).await?;
+
+        Ok(Self::new(Arc::from(transport), opts, reconnect))
+    }
+
+    /// Start the supervisor, returning a stream of events.
+    ///
+    /// The stream ends only if the configured reconnection attempts are exhausted,
+    /// dropping the stream stops the supervisor.
+    pub fn events(self) -> BoxStream<'static, Event> {
+        let (tx, rx) = mpsc::channel(256);
+
+        tokio::spawn(async move {
+            self.run(tx).await;
+        });
+
+        Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|e| (e, rx))
+        }))
+    }
+
+    async fn run(self, tx: mpsc::Sender<Event>) {
+        let mut connected_before = false;
+        let mut attempts = 0;
+        let mut delay = *self.reconnect.reconnect_initial;
+        let mut device = self.device.clone();
+
+        loop {
+            let sensor = match &device {
+                Some(d) => Sensor::connect_device(self.transport.as_ref(), d.clone()).await,
+                None => Sensor::connect_with(self.transport.as_ref(), &self.opts).await,
+            };
+
+            match sensor {
+                Ok(sensor) => {
+                    let address = sensor.connection().address();
+
+                    // Pin reconnections to this device, rescanning could select another match
+                    if device.is_none() {
+                        device = Some(sensor.device().clone());
+                    }
+
+                    let evt = match connected_before {
+                        false => Event::Connected{ address, timestamp: SystemTime::now() },
+                        true => Event::Reconnected{ address, timestamp: SystemTime::now(), attempts },
+                    };
+                    if tx.send(evt).await.is_err() {
+                        return;
+                    }
+
+                    let evt = Event::Device(Box::new(DeviceDetails{
+                        device: sensor.device().clone(),
+                        info: sensor.info().clone(),
+                        protocol: sensor.protocol().map(|p| p.name().to_string()),
+                    }));
+                    if tx.send(evt).await.is_err() {
+                        return;
+                    }
+
+                    connected_before = true;
+                    attempts = 0;
+                    delay = *self.reconnect.reconnect_initial;
+
+                    // Forward measurements until the connection drops or stalls
+                    match self.forward(&sensor, &tx).await {
+                        Ok(()) => (),
+                        Err(Closed) => {
+                            let _ = sensor.connection().disconnect().await;
+                            return;
+                        }
+                    }
+
+                    info!("Sensor {} disconnected", address);
+                    if let Err(e) = sensor.connection().disconnect().await {
+                        debug!("Disconnect failed: {:?}", e);
+                    }
+
+                    if tx.send(Event::Disconnected{ address, timestamp: SystemTime::now() }).await.is_err() {
+                        return;
+                    }
+                },
+                Err(e) => {
+                    warn!("Failed to connect to sensor: {}", e);
+                },
+            }
+
+            attempts += 1;
+            if self.reconnect.reconnect_attempts > 0 && attempts > self.reconnect.reconnect_attempts {
+                error!("Giving up after {} reconnection attempts", attempts - 1);
+                return;
+            }
+
+            debug!("Reconnecting in {:?} (attempt {})", delay, attempts);
+
+            tokio::select!{
+                _ = tokio::time::sleep(delay) => (),
+                _ = tx.closed() => return,
+            }
+
+            delay = backoff(delay, self.reconnect.reconnect_factor, *self.reconnect.reconnect_max);
+        }
+    }
+
+    async fn forward(&self, sensor: &Sensor, tx: &mpsc::Sender<Event>) -> Result<(), Closed> {
+        let mut measurements = match sensor.measurements().await {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Failed to subscribe to measurements: {}", e);
+                return Ok(());
+            }
+        };
+
+        loop {
+            match tokio::time::timeout(*self.reconnect.stall_timeout, measurements.next()).await {
+                Ok(Some(m)) => {
+                    tx.send(Event::Measurement(m)).await.map_err(|_| Closed)?
+                },
+                Ok(None) => return Ok(()),
+                Err(_) => {
+                    warn!("No measurements received for {}, assuming connection lost", self.reconnect.stall_timeout);
+                    return Ok(());
+                },
+            }
+        }
+    }
+}
+
+/// Event receiver has been dropped
+struct Closed;
+
+/// Parse a reconnection delay multiplier, which must be finite and at least 1.0
+fn parse_factor(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(f) if f.is_finite() && f >= 1.0 => Ok(f),
+        Ok(f) => Err(format!("reconnect factor must be finite and at least 1.0 (got {})", f)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Calculate the next reconnection delay, clamped to the maximum.
+///
+/// Factors set directly on [`ReconnectOptions`] bypass [`parse_factor`], so invalid
+/// factors leave the delay unchanged and overflows saturate at the maximum.
+fn backoff(delay: Duration, factor: f32, max: Duration) -> Duration {
+    let factor = if factor.is_finite() { factor.max(1.0) } else { 1.0 };
+
+    Duration::try_from_secs_f32(delay.as_secs_f32() * factor).unwrap_or(max).min(max)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::mock::{MockDevice, MockTransport, Step};
+    use super::*;
+
+    fn device(address: u8, advertise_after: Duration) -> MockDevice {
+        let mut d = MockDevice::berrymed(Address([address; 6]));
+        d.advertise_after = advertise_after;
+        d.sessions = vec![
+            vec![Step::berrymed_notify(Duration::from_millis(10)), Step::Disconnect{ delay: Duration::from_secs(1) }],
+            vec![Step::berrymed_notify(Duration::from_millis(10))],
+        ];
+        d
+    }
+
+    #[test]
+    fn reconnect_factor() {
+        let parse = |f: &str| ReconnectOptions::from_iter_safe(&["spo2", "--reconnect-factor", f]).map(|o| o.reconnect_factor);
+
+        assert_eq!(parse("1.5").ok(), Some(1.5));
+        assert_eq!(parse("1").ok(), Some(1.0));
+        for f in ["0.5", "-2", "NaN", "inf", "x"] {
+            assert!(parse(f).is_err(), "factor {}", f);
+        }
+    }
+
+    #[test]
+    fn backoff_clamped() {
+        let max = Duration::from_secs(60);
+
+        assert_eq!(backoff(Duration::from_secs(1), 2.0, max), Duration::from_secs(2));
+        assert_eq!(backoff(Duration::from_secs(40), 2.0, max), max);
+        assert_eq!(backoff(Duration::from_secs(40), f32::MAX, max), max);
+
+        // Invalid factors leave the delay unchanged
+        for f in [-1.0, 0.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(backoff(Duration::from_secs(1), f, max), Duration::from_secs(1), "factor {}", f);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_to_same_device() {
+        let (a, b) = (Address([1; 6]), Address([2; 6]));
+        let t = MockTransport::new(vec![device(1, Duration::from_secs(1))]);
+
+        let s = Supervisor::new(Arc::new(t.clone()), Options::from_iter(&["spo2"]), ReconnectOptions::default());
+        let mut events = s.events();
+
+        let mut addresses = vec![];
+        while let Some(e) = events.next().await {
+            match e {
+                Event::Connected{ address, .. } => addresses.push(address),
+                // Another matching device, advertising before the original on a rescan
+                Event::Disconnected{ .. } => t.add(device(2, Duration::from_secs(0))),
+                Event::Reconnected{ address, attempts, .. } => {
+                    addresses.push(address);
+                    assert_eq!(attempts, 1);
+                    break;
+                },
+                _ => (),
+            }
+        }
+
+        assert_eq!(addresses, vec![a, a]);
+        assert_eq!(t.connections(a), 2);
+        assert_eq!(t.connections(b), 0);
+    }
+}
+
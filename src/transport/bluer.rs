@@ -0,0 +1,274 @@
+//! BlueZ transport using [bluer](https://docs.rs/bluer)
+//!
+//! bluer 0.13 does not expose BlueZ discovery filters, and sets an unfiltered discovery
+//! filter itself when starting discovery (replacing any set by this client). Scans
+//! therefore discover all nearby devices and [`ScanFilter`]s are applied to discovered
+//! devices in userspace.
+
+use async_trait::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use log::{trace, debug, info, warn, error};
+
+use bluer::AdapterEvent;
+
+use crate::{Error, Options};
+use super::{Transport, Connection, Adaptor, Address, AddressType, DiscoveredDevice, ScanFilter, Service, Characteristic, Properties};
+
+
+/// Transport using a BlueZ adapter via bluer
+#[derive(Debug)]
+pub struct BluerTransport {
+    _session: bluer::Session,
+    adapter: bluer::Adapter,
+}
+
+/// Connection to a device via bluer
+#[derive(Debug)]
+pub struct BluerConnection {
+    device: bluer::Device,
+    address_type: AddressType,
+}
+
+impl BluerTransport {
+    pub async fn new(opts: &Options) -> Result<Self, Error> {
+        // Create bluez session
+        let session = bluer::Session::new().await?;
+
+        // Select adaptor
+        let adapter = select_adapter(&session, &opts.adaptor).await?;
+
+        debug!("Using adapter: {} ({})", adapter.name(), adapter.address().await?);
+
+        // Check adaptor is powered, enabling if requested
+        if !adapter.is_powered().await? {
+            if !opts.power_on {
+                return Err(Error::AdaptorPoweredOff(adapter.name().to_string()));
+            }
+
+            info!("Powering on adapter {}", adapter.name());
+            adapter.set_powered(true).await?;
+        }
+
+        Ok(Self{ _session: session, adapter })
+    }
+}
+
+#[async_trait]
+impl Transport for BluerTransport {
+    async fn scan(&self, filter: &ScanFilter) -> Result<BoxStream<'static, DiscoveredDevice>, Error> {
+        // Start device discovery, this stops when the event stream is dropped.
+        // bluer 0.13 sets its own discovery filter (see module docs), so filtering is applied here instead.
+        let events = self.adapter.discover_devices().await?;
+
+        let adapter = self.adapter.clone();
+        let filter = filter.clone();
+        let devices = events.filter_map(move |evt| {
+            let adapter = adapter.clone();
+            let filter = filter.clone();
+            async move {
+                match evt {
+                    AdapterEvent::DeviceAdded(addr) => {
+                        trace!("Device added: {:?}", addr);
+
+                        match describe(&adapter, addr).await {
+                            Ok(d) if filter.matches(&d) => Some(d),
+                            Ok(d) => {
+                                trace!("Filtered device: {:?}", d);
+                                None
+                            },
+                            Err(e) => {
+                                warn!("Failed to fetch properties for device {}: {:?}", addr, e);
+                                None
+                            }
+                        }
+                    },
+                    _ => {
+                        debug!("Event: {:?}", evt);
+                        None
+                    },
+                }
+            }
+        });
+
+        Ok(Box::pin(devices))
+    }
+
+    async fn connect(&self, device: &DiscoveredDevice) -> Result<Box<dyn Connection>, Error> {
+        let addr = bluer::Address(device.address.0);
+
+        // Use the discovered address type, falling back to random where unknown
+        let address_type = device.address_type.unwrap_or(AddressType::Random);
+
+        debug!("Connecting to {} ({} address)", device.address, address_type);
+
+        let (device, address_type) = match self.adapter.connect_device(addr, address_type.into()).await {
+            Ok(d) => (d, address_type),
+            Err(e) => {
+                // Retry with the alternate address type
+                let alternate = address_type.alternate();
+                warn!("Connection with {} address failed ({}), retrying with {} address", address_type, e, alternate);
+
+                let d = self.adapter.connect_device(addr, alternate.into()).await?;
+                info!("Connected using {} address", alternate);
+                (d, alternate)
+            }
+        };
+
+        if !device.is_connected().await? {
+            error!("Failed to connect to device");
+            return Err(Error::ConnectFailed)
+        }
+
+        Ok(Box::new(BluerConnection{ device, address_type }))
+    }
+}
+
+impl From<AddressType> for bluer::AddressType {
+    fn from(t: AddressType) -> Self {
+        match t {
+            AddressType::Public => bluer::AddressType::LePublic,
+            AddressType::Random => bluer::AddressType::LeRandom,
+        }
+    }
+}
+
+/// Locate the adaptor matching the provided selector
+async fn select_adapter(session: &bluer::Session, adaptor: &Adaptor) -> Result<bluer::Adapter, Error> {
+    let mut names = session.adapter_names().await?;
+    names.sort();
+
+    trace!("Available adapters: {:?}", names);
+
+    let name = match adaptor {
+        Adaptor::Index(i) => names.get(*i).cloned(),
+        Adaptor::Name(n) => names.iter().find(|v| *v == n).cloned(),
+        Adaptor::Address(a) => {
+            let mut found = None;
+            for n in &names {
+                if session.adapter(n)?.address().await?.0 == a.0 {
+                    found = Some(n.clone());
+                    break;
+                }
+            }
+            found
+        },
+    };
+
+    match name {
+        Some(n) => Ok(session.adapter(&n)?),
+        None => Err(Error::NoMatchingAdaptor(adaptor.clone())),
+    }
+}
+
+/// Read out discovery information for a device
+async fn describe(adapter: &bluer::Adapter, addr: bluer::Address) -> Result<DiscoveredDevice, Error> {
+    let device = adapter.device(addr)?;
+
+    let mut service_uuids: Vec<_> = device.uuids().await?.unwrap_or_default().into_iter().collect();
+    service_uuids.sort();
+
+    Ok(DiscoveredDevice{
+        address: Address(addr.0),
+        name: device.name().await?,
+        rssi: device.rssi().await?,
+        address_type: match device.address_type().await? {
+            bluer::AddressType::LePublic => Some(AddressType::Public),
+            bluer::AddressType::LeRandom => Some(AddressType::Random),
+            bluer::AddressType::BrEdr => None,
+        },
+        service_uuids,
+        manufacturer_data: device.manufacturer_data().await?.unwrap_or_default(),
+    })
+}
+
+impl BluerConnection {
+    /// Fetch the bluer characteristic matching a discovered [`Characteristic`]
+    async fn characteristic(&self, characteristic: &Characteristic) -> Result<bluer::gatt::remote::Characteristic, Error> {
+        for s in self.device.services().await? {
+            if s.uuid().await? != characteristic.service {
+                continue;
+            }
+
+            for c in s.characteristics().await? {
+                if c.uuid().await? == characteristic.uuid {
+                    return Ok(c);
+                }
+            }
+        }
+
+        Err(Error::NoCharacteristic(characteristic.uuid))
+    }
+}
+
+#[async_trait]
+impl Connection for BluerConnection {
+    fn address(&self) -> Address {
+        Address(self.device.address().0)
+    }
+
+    fn address_type(&self) -> Option<AddressType> {
+        Some(self.address_type)
+    }
+
+    async fn discover(&self) -> Result<Vec<Service>, Error> {
+        let mut services = vec![];
+
+        for s in self.device.services().await? {
+            let uuid = s.uuid().await?;
+
+            let mut characteristics = vec![];
+            for c in s.characteristics().await? {
+                let flags = c.flags().await?;
+
+                characteristics.push(Characteristic{
+                    service: uuid,
+                    uuid: c.uuid().await?,
+                    properties: Properties{
+                        read: flags.read,
+                        write: flags.write,
+                        write_without_response: flags.write_without_response,
+                        notify: flags.notify,
+                        indicate: flags.indicate,
+                    },
+                });
+            }
+
+            services.push(Service{
+                uuid,
+                primary: s.primary().await?,
+                characteristics,
+            });
+        }
+
+        Ok(services)
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<BoxStream<'static, Vec<u8>>, Error> {
+        let c = self.characteristic(characteristic).await?;
+
+        let notifications = c.notify().await?;
+
+        Ok(Box::pin(notifications))
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>, Error> {
+        let c = self.characteristic(characteristic).await?;
+
+        let data = c.read().await?;
+
+        Ok(data)
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8]) -> Result<(), Error> {
+        let c = self.characteristic(characteristic).await?;
+
+        c.write(data).await?;
+
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<(), Error> {
+        self.device.disconnect().await?;
+        Ok(())
+    }
+}
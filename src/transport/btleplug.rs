@@ -0,0 +1,271 @@
+//! Cross-platform transport using [btleplug](https://docs.rs/btleplug)
+
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use log::{trace, debug, warn, error};
+
+use btleplug::platform::{Adapter, Peripheral, Manager};
+use btleplug::api::{WriteType, Manager as _, Central as _, Peripheral as _, CentralEvent};
+
+use crate::{Error, Options};
+use super::{Transport, Connection, Adaptor, Address, AddressType, DiscoveredDevice, ScanFilter, Service, Characteristic, Properties};
+
+
+/// Transport using a btleplug central adapter
+#[derive(Debug)]
+pub struct BtleplugTransport {
+    central: Adapter,
+}
+
+/// Connection to a btleplug peripheral
+#[derive(Debug)]
+pub struct BtleplugConnection {
+    central: Adapter,
+    periph: Peripheral,
+}
+
+impl BtleplugTransport {
+    pub async fn new(opts: &Options) -> Result<Self, Error> {
+        // Connect to BLE manager
+        let manager = Manager::new().await?;
+
+        // Fetch adapter for central role
+        let adapters = manager.adapters().await?;
+        let central = match &opts.adaptor {
+            Adaptor::Index(i) => adapters.into_iter().nth(*i),
+            // btleplug only exposes adapter info strings, on linux of the form `hci0 (usb:...)`
+            Adaptor::Name(n) => {
+                let mut found = None;
+                for a in adapters {
+                    if a.adapter_info().await?.split_whitespace().next() == Some(n.as_str()) {
+                        found = Some(a);
+                        break;
+                    }
+                }
+                found
+            },
+            Adaptor::Address(_) => {
+                warn!("Selecting adaptors by address is not supported by the btleplug backend");
+                None
+            },
+        };
+
+        let central = match central {
+            Some(c) => c,
+            None => {
+                return Err(Error::NoMatchingAdaptor(opts.adaptor.clone()));
+            }
+        };
+
+        if opts.power_on {
+            warn!("Adaptor power control is not supported by the btleplug backend");
+        }
+
+        Ok(Self{ central })
+    }
+}
+
+/// Stops scanning when the scan stream is dropped
+struct ScanGuard(Adapter);
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        let central = self.0.clone();
+        tokio::spawn(async move {
+            if let Err(e) = central.stop_scan().await {
+                error!("Failed to stop scan: {:?}", e);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Transport for BtleplugTransport {
+    async fn scan(&self, filter: &ScanFilter) -> Result<BoxStream<'static, DiscoveredDevice>, Error> {
+        // Setup event channel
+        let events = self.central.events().await?;
+
+        // Start scanning
+        debug!("Starting scan for BLE devices");
+        // Service filters are passed to the platform (BlueZ discovery filters on linux)
+        self.central.start_scan(btleplug::api::ScanFilter{ services: filter.services.clone() }).await?;
+
+        let central = self.central.clone();
+        let guard = ScanGuard(self.central.clone());
+
+        let devices = stream::unfold((events, central, guard), |(mut events, central, guard)| async move {
+            while let Some(evt) = events.next().await {
+                let id = match &evt {
+                    CentralEvent::DeviceDiscovered(id) => id,
+                    _ => {
+                        trace!("Unhandled event: {:?}", evt);
+                        continue;
+                    }
+                };
+
+                // Fetch peripheral information
+                let periph = match central.peripheral(id).await {
+                    Ok(p) => p,
+                    Err(e) => {
+                        error!("Failed to fetch peripheral {:?}: {:?}", id, e);
+                        continue;
+                    }
+                };
+                trace!("Discovered peripheral {:?}: {:?}", id, periph);
+
+                // Read out properties
+                let props = match periph.properties().await {
+                    Ok(Some(p)) => p,
+                    Ok(None) => {
+                        error!("Failed to fetch properties for peripheral {:?}", id);
+                        continue;
+                    },
+                    Err(e) => {
+                        error!("Failed to fetch properties for peripheral {:?}: {:?}", periph, e);
+                        continue;
+                    },
+                };
+                trace!("Properties: {:?}", props);
+
+                let d = DiscoveredDevice{
+                    address: Address(props.address.into_inner()),
+                    name: props.local_name,
+                    rssi: props.rssi,
+                    address_type: props.address_type.map(|t| match t {
+                        btleplug::api::AddressType::Public => AddressType::Public,
+                        btleplug::api::AddressType::Random => AddressType::Random,
+                    }),
+                    service_uuids: props.services,
+                    manufacturer_data: props.manufacturer_data,
+                };
+
+                return Some((d, (events, central, guard)));
+            }
+
+            None
+        });
+
+        Ok(Box::pin(devices))
+    }
+
+    async fn connect(&self, device: &DiscoveredDevice) -> Result<Box<dyn Connection>, Error> {
+        // Locate peripheral by address
+        let periph = self.central.peripherals().await?
+            .into_iter()
+            .find(|p| p.address().into_inner() == device.address.0)
+            .ok_or(Error::NoDeviceFound)?;
+
+        // Ensure we're connected
+        if !periph.is_connected().await? {
+            debug!("Connecting to device");
+            periph.connect().await?;
+
+            if !periph.is_connected().await? {
+                error!("Failed to connect to device");
+                return Err(Error::ConnectFailed)
+            }
+        }
+
+        Ok(Box::new(BtleplugConnection{ central: self.central.clone(), periph }))
+    }
+}
+
+impl BtleplugConnection {
+    /// Fetch the btleplug characteristic matching a discovered [`Characteristic`]
+    fn characteristic(&self, characteristic: &Characteristic) -> Result<btleplug::api::Characteristic, Error> {
+        self.periph.characteristics()
+            .into_iter()
+            .find(|c| c.service_uuid == characteristic.service && c.uuid == characteristic.uuid)
+            .ok_or(Error::NoCharacteristic(characteristic.uuid))
+    }
+}
+
+#[async_trait]
+impl Connection for BtleplugConnection {
+    fn address(&self) -> Address {
+        Address(self.periph.address().into_inner())
+    }
+
+    async fn discover(&self) -> Result<Vec<Service>, Error> {
+        use btleplug::api::CharPropFlags;
+
+        self.periph.discover_services().await?;
+
+        let services = self.periph.services().into_iter().map(|s| {
+            let characteristics = s.characteristics.iter().map(|c| Characteristic{
+                service: s.uuid,
+                uuid: c.uuid,
+                properties: Properties{
+                    read: c.properties.contains(CharPropFlags::READ),
+                    write: c.properties.contains(CharPropFlags::WRITE),
+                    write_without_response: c.properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE),
+                    notify: c.properties.contains(CharPropFlags::NOTIFY),
+                    indicate: c.properties.contains(CharPropFlags::INDICATE),
+                },
+            }).collect();
+
+            Service{
+                uuid: s.uuid,
+                primary: s.primary,
+                characteristics,
+            }
+        }).collect();
+
+        Ok(services)
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<BoxStream<'static, Vec<u8>>, Error> {
+        let c = self.characteristic(characteristic)?;
+
+        // Notifications are shared between characteristics, so filter by UUID
+        let notifications = self.periph.notifications().await?;
+        self.periph.subscribe(&c).await?;
+
+        // The notification stream persists across connections, so end it on disconnect
+        let id = self.periph.id();
+        let mut events = self.central.events().await?;
+        let disconnected = async move {
+            while let Some(evt) = events.next().await {
+                if matches!(evt, CentralEvent::DeviceDisconnected(d) if d == id) {
+                    debug!("Disconnected event for {:?}", id);
+                    break;
+                }
+            }
+        };
+
+        let uuid = c.uuid;
+        let values = notifications
+            .filter_map(move |n| async move {
+                if n.uuid == uuid { Some(n.value) } else { None }
+            })
+            .take_until(disconnected);
+
+        Ok(Box::pin(values))
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>, Error> {
+        let c = self.characteristic(characteristic)?;
+
+        let data = self.periph.read(&c).await?;
+
+        Ok(data)
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8]) -> Result<(), Error> {
+        let c = self.characteristic(characteristic)?;
+
+        let write_type = match characteristic.properties.write {
+            true => WriteType::WithResponse,
+            false => WriteType::WithoutResponse,
+        };
+
+        self.periph.write(&c, data, write_type).await?;
+
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<(), Error> {
+        self.periph.disconnect().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,488 @@
+//! In-memory mock transport for testing without BLE hardware.
+//!
+//! Devices are scripted with advertisement and connection delays, connection failures,
+//! GATT services and per-connection notification sequences. All timing uses `tokio::time`
+//! so scripts run deterministically under a paused runtime (`tokio::time::pause`).
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use log::debug;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::Error;
+use super::{Transport, Connection, Address, DiscoveredDevice, ScanFilter, Service, Characteristic};
+
+
+/// Scripted step played back on each subscription to a connection
+#[derive(Debug, PartialEq, Clone)]
+pub enum Step {
+    /// Send a notification on a characteristic after a delay
+    Notify {
+        delay: Duration,
+        characteristic: Uuid,
+        data: Vec<u8>,
+    },
+    /// Drop the connection after a delay
+    Disconnect {
+        delay: Duration,
+    },
+}
+
+/// Scripted mock device
+#[derive(Debug, PartialEq, Clone)]
+pub struct MockDevice {
+    /// Advertised device information
+    pub info: DiscoveredDevice,
+    /// Delay from scan start to advertisement
+    pub advertise_after: Duration,
+    /// Delay before a connection attempt completes
+    pub connect_delay: Duration,
+    /// Number of connection attempts that fail before a connection succeeds
+    pub connect_failures: usize,
+    /// GATT services exposed by the device
+    pub services: Vec<Service>,
+    /// Characteristic values returned by reads
+    pub values: HashMap<Uuid, Vec<u8>>,
+    /// Notification scripts for successive connections, the last script is
+    /// reused for any further connections
+    pub sessions: Vec<Vec<Step>>,
+}
+
+impl MockDevice {
+    /// Create a new mock device advertising with the provided address and name
+    pub fn new(address: Address, name: &str) -> Self {
+        Self {
+            info: DiscoveredDevice {
+                address,
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            advertise_after: Duration::from_millis(0),
+            connect_delay: Duration::from_millis(0),
+            connect_failures: 0,
+            services: vec![],
+            values: HashMap::new(),
+            sessions: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+impl MockDevice {
+    /// BerryMed (J1) device exposing the vendor notification characteristic
+    pub(crate) fn berrymed(address: Address) -> Self {
+        use crate::protocol::berrymed;
+
+        let mut d = Self::new(address, "J1 test");
+        d.services = vec![Service{
+            uuid: berrymed::SERVICE,
+            primary: true,
+            characteristics: vec![Characteristic{
+                service: berrymed::SERVICE,
+                uuid: berrymed::NOTIFY,
+                properties: super::Properties{ notify: true, ..Default::default() },
+            }],
+        }];
+        d
+    }
+}
+
+#[cfg(test)]
+impl Step {
+    /// BerryMed packet notification after a delay, SpO2 97 %, pulse rate 72 bpm
+    pub(crate) fn berrymed_notify(delay: Duration) -> Self {
+        use crate::protocol::berrymed;
+
+        Step::Notify{ delay, characteristic: berrymed::NOTIFY, data: vec![0x85, 0x32, 0x00, 0x48, 0x61] }
+    }
+}
+
+/// Live events pushed to active connections
+#[derive(Debug, Clone)]
+enum Live {
+    Notify(Uuid, Vec<u8>),
+    Disconnected,
+}
+
+#[derive(Debug)]
+struct DeviceState {
+    device: MockDevice,
+    failures: usize,
+    connections: usize,
+    connected: bool,
+    tx: Option<broadcast::Sender<Live>>,
+    writes: Vec<(Uuid, Vec<u8>)>,
+}
+
+type Shared = Arc<Mutex<HashMap<Address, DeviceState>>>;
+
+/// In-memory [`Transport`] serving scripted [`MockDevice`]s
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    devices: Shared,
+}
+
+/// Connection to a [`MockDevice`]
+#[derive(Debug)]
+pub struct MockConnection {
+    address: Address,
+    devices: Shared,
+    connection: usize,
+    services: Vec<Service>,
+    script: Vec<Step>,
+    tx: broadcast::Sender<Live>,
+}
+
+impl MockTransport {
+    /// Create a new mock transport serving the provided devices
+    pub fn new(devices: Vec<MockDevice>) -> Self {
+        let t = Self::default();
+        for d in devices {
+            t.add(d);
+        }
+        t
+    }
+
+    /// Add a device, replacing any existing device with the same address
+    pub fn add(&self, device: MockDevice) {
+        let state = DeviceState {
+            failures: device.connect_failures,
+            device,
+            connections: 0,
+            connected: false,
+            tx: None,
+            writes: vec![],
+        };
+
+        self.devices.lock().unwrap().insert(state.device.info.address, state);
+    }
+
+    /// Push a notification to subscribers of a connected device
+    pub fn notify(&self, address: Address, characteristic: Uuid, data: &[u8]) {
+        if let Some(tx) = self.devices.lock().unwrap().get(&address).and_then(|d| d.tx.as_ref()) {
+            let _ = tx.send(Live::Notify(characteristic, data.to_vec()));
+        }
+    }
+
+    /// Drop the connection to a device, ending active subscriptions
+    pub fn disconnect(&self, address: Address) {
+        disconnect(&self.devices, address);
+    }
+
+    /// Check whether a device is currently connected
+    pub fn is_connected(&self, address: Address) -> bool {
+        self.devices.lock().unwrap().get(&address).map(|d| d.connected).unwrap_or(false)
+    }
+
+    /// Fetch the number of successful connections made to a device
+    pub fn connections(&self, address: Address) -> usize {
+        self.devices.lock().unwrap().get(&address).map(|d| d.connections).unwrap_or(0)
+    }
+
+    /// Fetch writes made to a device, in order
+    pub fn writes(&self, address: Address) -> Vec<(Uuid, Vec<u8>)> {
+        self.devices.lock().unwrap().get(&address).map(|d| d.writes.clone()).unwrap_or_default()
+    }
+}
+
+/// Check whether the numbered connection to a device is still current
+fn is_current(devices: &Shared, address: Address, connection: usize) -> bool {
+    devices.lock().unwrap().get(&address)
+        .map(|d| d.connected && d.connections == connection)
+        .unwrap_or(false)
+}
+
+fn disconnect(devices: &Shared, address: Address) {
+    if let Some(d) = devices.lock().unwrap().get_mut(&address) {
+        debug!("Mock device {} disconnected", address);
+
+        d.connected = false;
+        if let Some(tx) = d.tx.take() {
+            let _ = tx.send(Live::Disconnected);
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for MockTransport {
+    async fn scan(&self, filter: &ScanFilter) -> Result<BoxStream<'static, DiscoveredDevice>, Error> {
+        let mut adverts: Vec<_> = self.devices.lock().unwrap().values()
+            .filter(|d| filter.matches(&d.device.info))
+            .map(|d| (d.device.advertise_after, d.device.info.clone()))
+            .collect();
+        adverts.sort_by_key(|(t, _)| *t);
+
+        // Emit advertisements at their scheduled times, then scan indefinitely
+        let start = tokio::time::Instant::now();
+        let devices = stream::iter(adverts)
+            .then(move |(t, d)| async move {
+                tokio::time::sleep_until(start + t).await;
+                d
+            })
+            .chain(stream::pending());
+
+        Ok(Box::pin(devices))
+    }
+
+    async fn connect(&self, device: &DiscoveredDevice) -> Result<Box<dyn Connection>, Error> {
+        let delay = match self.devices.lock().unwrap().get(&device.address) {
+            Some(d) => d.device.connect_delay,
+            None => return Err(Error::NoDeviceFound),
+        };
+
+        tokio::time::sleep(delay).await;
+
+        let mut devices = self.devices.lock().unwrap();
+        let d = devices.get_mut(&device.address).ok_or(Error::NoDeviceFound)?;
+
+        if d.failures > 0 {
+            d.failures -= 1;
+            debug!("Mock device {} connection failed ({} failures remaining)", device.address, d.failures);
+            return Err(Error::ConnectFailed);
+        }
+
+        // Select the script for this connection
+        let script = d.device.sessions.get(d.connections)
+            .or_else(|| d.device.sessions.last())
+            .cloned()
+            .unwrap_or_default();
+
+        let (tx, _) = broadcast::channel(64);
+        d.tx = Some(tx.clone());
+        d.connected = true;
+        d.connections += 1;
+
+        Ok(Box::new(MockConnection{
+            address: device.address,
+            devices: self.devices.clone(),
+            connection: d.connections,
+            services: d.device.services.clone(),
+            script,
+            tx,
+        }))
+    }
+}
+
+impl MockConnection {
+    fn is_connected(&self) -> bool {
+        is_current(&self.devices, self.address, self.connection)
+    }
+
+    fn characteristic(&self, characteristic: &Characteristic) -> Result<&Characteristic, Error> {
+        super::find_characteristic(&self.services, characteristic.service, characteristic.uuid)
+            .ok_or(Error::NoCharacteristic(characteristic.uuid))
+    }
+}
+
+#[async_trait]
+impl Connection for MockConnection {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    async fn discover(&self) -> Result<Vec<Service>, Error> {
+        if !self.is_connected() {
+            return Err(Error::ConnectFailed);
+        }
+
+        Ok(self.services.clone())
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<BoxStream<'static, Vec<u8>>, Error> {
+        let uuid = self.characteristic(characteristic)?.uuid;
+
+        if !self.is_connected() {
+            return Err(Error::ConnectFailed);
+        }
+
+        let rx = self.tx.subscribe();
+        let connection = self.connection;
+        let address = self.address;
+        let devices = self.devices.clone();
+
+        // Play back the connection script
+        let scripted = stream::iter(self.script.clone())
+            .then(move |step| {
+                let devices = devices.clone();
+                async move {
+                    match step {
+                        Step::Notify{ delay, characteristic, data } => {
+                            tokio::time::sleep(delay).await;
+                            Some((characteristic, data))
+                        },
+                        Step::Disconnect{ delay } => {
+                            tokio::time::sleep(delay).await;
+
+                            // Only drop the connection this script belongs to
+                            if is_current(&devices, address, connection) {
+                                disconnect(&devices, address);
+                            }
+                            None
+                        },
+                    }
+                }
+            })
+            .take_while(|n| futures::future::ready(n.is_some()))
+            .filter_map(move |n| futures::future::ready(match n {
+                Some((c, d)) if c == uuid => Some(d),
+                _ => None,
+            }));
+
+        // Then forward live notifications until disconnected
+        let live = stream::unfold(rx, move |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(Live::Notify(c, d)) if c == uuid => return Some((d, rx)),
+                    Ok(Live::Notify(..)) => (),
+                    Ok(Live::Disconnected) => return None,
+                    Err(broadcast::error::RecvError::Lagged(_)) => (),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        // Drop scripted notifications once the connection is lost
+        let devices = self.devices.clone();
+        let scripted = scripted.take_while(move |_| futures::future::ready(is_current(&devices, address, connection)));
+
+        Ok(Box::pin(scripted.chain(live)))
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>, Error> {
+        let uuid = self.characteristic(characteristic)?.uuid;
+
+        if !self.is_connected() {
+            return Err(Error::ConnectFailed);
+        }
+
+        let devices = self.devices.lock().unwrap();
+        let value = devices.get(&self.address).and_then(|d| d.device.values.get(&uuid));
+
+        Ok(value.cloned().unwrap_or_default())
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8]) -> Result<(), Error> {
+        let uuid = self.characteristic(characteristic)?.uuid;
+
+        if !self.is_connected() {
+            return Err(Error::ConnectFailed);
+        }
+
+        if let Some(d) = self.devices.lock().unwrap().get_mut(&self.address) {
+            d.writes.push((uuid, data.to_vec()));
+        }
+
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<(), Error> {
+        if self.is_connected() {
+            disconnect(&self.devices, self.address);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use structopt::StructOpt;
+
+    use crate::{Sensor, Options, Measurement};
+    use crate::protocol::berrymed;
+    use super::*;
+
+    fn options() -> Options {
+        Options::from_iter(&["spo2"])
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_failures() {
+        let a = Address([1; 6]);
+        let mut d = MockDevice::berrymed(a);
+        d.connect_failures = 2;
+        let t = MockTransport::new(vec![d]);
+
+        for _ in 0..2 {
+            assert!(matches!(Sensor::connect_with(&t, &options()).await, Err(Error::ConnectFailed)));
+            assert!(!t.is_connected(a));
+        }
+
+        Sensor::connect_with(&t, &options()).await.unwrap();
+        assert!(t.is_connected(a));
+        assert_eq!(t.connections(a), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_delay() {
+        let mut d = MockDevice::berrymed(Address([1; 6]));
+        d.advertise_after = Duration::from_secs(2);
+        d.connect_delay = Duration::from_secs(3);
+        let t = MockTransport::new(vec![d]);
+
+        let start = tokio::time::Instant::now();
+        Sensor::connect_with(&t, &options()).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scripted_disconnect_ends_measurements() {
+        let a = Address([1; 6]);
+        let mut d = MockDevice::berrymed(a);
+        d.sessions = vec![vec![Step::berrymed_notify(Duration::from_millis(10)), Step::berrymed_notify(Duration::from_millis(10)), Step::Disconnect{ delay: Duration::from_secs(1) }, Step::berrymed_notify(Duration::from_millis(10))]];
+        let t = MockTransport::new(vec![d]);
+
+        let s = Sensor::connect_with(&t, &options()).await.unwrap();
+        let m: Vec<_> = s.measurements().await.unwrap().collect().await;
+
+        // Each packet decodes to a pleth sample, the unchanged second reading is suppressed
+        assert_eq!(m.len(), 3);
+        assert!(matches!(&m[1], Measurement::Reading(r) if r.spo2 == Some(97.0) && r.pulse_rate == Some(72.0)));
+        assert!(!t.is_connected(a));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_per_connection() {
+        let a = Address([1; 6]);
+        let mut d = MockDevice::berrymed(a);
+        d.sessions = vec![
+            vec![Step::berrymed_notify(Duration::from_millis(10)), Step::Disconnect{ delay: Duration::from_secs(1) }],
+            vec![Step::berrymed_notify(Duration::from_millis(10)), Step::berrymed_notify(Duration::from_millis(10)), Step::Disconnect{ delay: Duration::from_secs(1) }],
+        ];
+        let t = MockTransport::new(vec![d]);
+
+        let mut counts = vec![];
+        for _ in 0..3 {
+            let s = Sensor::connect_with(&t, &options()).await.unwrap();
+            let c = s.services()[0].characteristics[0].clone();
+            counts.push(s.connection().subscribe(&c).await.unwrap().count().await);
+        }
+
+        // The last script is reused for further connections
+        assert_eq!(counts, vec![1, 2, 2]);
+        assert_eq!(t.connections(a), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn live_notifications() {
+        let a = Address([1; 6]);
+        let t = MockTransport::new(vec![MockDevice::berrymed(a)]);
+
+        let s = Sensor::connect_with(&t, &options()).await.unwrap();
+        let c = s.services()[0].characteristics[0].clone();
+        let mut n = s.connection().subscribe(&c).await.unwrap();
+
+        t.notify(a, berrymed::NOTIFY, &[1, 2]);
+        t.notify(a, berrymed::WRITE, &[3]);
+        assert_eq!(n.next().await, Some(vec![1, 2]));
+
+        t.disconnect(a);
+        assert_eq!(n.next().await, None);
+        assert!(s.connection().read(&c).await.is_err());
+    }
+}
@@ -0,0 +1,313 @@
+//! BLE transport abstraction, allowing sensor logic to be shared between
+//! bluetooth backends.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use uuid::Uuid;
+
+use crate::{Error, Options};
+
+#[cfg(feature = "bluer")]
+pub mod bluer;
+
+#[cfg(feature = "btleplug")]
+pub mod btleplug;
+
+pub mod mock;
+
+
+/// BLE transport, provides device discovery and connection
+#[async_trait]
+pub trait Transport: Send + Sync + Debug {
+    /// Start scanning for devices, the filter may be applied by the backend to
+    /// reduce scan traffic but callers should not rely on it excluding devices.
+    ///
+    /// Discovery continues until the returned stream is dropped.
+    async fn scan(&self, filter: &ScanFilter) -> Result<BoxStream<'static, DiscoveredDevice>, Error>;
+
+    /// Connect to a previously discovered device
+    async fn connect(&self, device: &DiscoveredDevice) -> Result<Box<dyn Connection>, Error>;
+}
+
+/// Connection to a BLE device, provides GATT service discovery, notifications and writes
+#[async_trait]
+pub trait Connection: Send + Sync + Debug {
+    /// Fetch the address of the connected device
+    fn address(&self) -> Address;
+
+    /// Fetch the LE address type used for the connection, where known
+    fn address_type(&self) -> Option<AddressType> {
+        None
+    }
+
+    /// Discover services and characteristics exposed by the device
+    async fn discover(&self) -> Result<Vec<Service>, Error>;
+
+    /// Subscribe to notifications or indications from a characteristic.
+    ///
+    /// The returned stream ends when the device disconnects.
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<BoxStream<'static, Vec<u8>>, Error>;
+
+    /// Read the value of a characteristic
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>, Error>;
+
+    /// Write a value to a characteristic
+    async fn write(&self, characteristic: &Characteristic, data: &[u8]) -> Result<(), Error>;
+
+    /// Disconnect from the device
+    async fn disconnect(&self) -> Result<(), Error>;
+}
+
+/// Open the transport selected by [`Options::backend`]
+pub async fn open(opts: &Options) -> Result<Box<dyn Transport>, Error> {
+    match opts.backend {
+        #[cfg(feature = "bluer")]
+        Backend::Bluer => Ok(Box::new(self::bluer::BluerTransport::new(opts).await?)),
+
+        #[cfg(feature = "btleplug")]
+        Backend::Btleplug => Ok(Box::new(self::btleplug::BtleplugTransport::new(opts).await?)),
+
+        #[allow(unreachable_patterns)]
+        b => Err(Error::BackendUnavailable(b)),
+    }
+}
+
+/// BLE backend, selects the [`Transport`] implementation used
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Backend {
+    /// BlueZ via bluer (linux only)
+    Bluer,
+    /// Cross-platform btleplug
+    Btleplug,
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Bluer => write!(f, "bluer"),
+            Backend::Btleplug => write!(f, "btleplug"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bluer" => Ok(Backend::Bluer),
+            "btleplug" => Ok(Backend::Btleplug),
+            _ => Err(format!("Unrecognised backend '{}' (expected bluer or btleplug)", s)),
+        }
+    }
+}
+
+/// BLE adaptor selector, by index, name or controller address
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Adaptor {
+    /// Adaptor index, in name order
+    Index(usize),
+    /// Adaptor name (eg. `hci1`)
+    Name(String),
+    /// Controller address
+    Address(Address),
+}
+
+impl Display for Adaptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Adaptor::Index(i) => write!(f, "index {}", i),
+            Adaptor::Name(n) => write!(f, "name {}", n),
+            Adaptor::Address(a) => write!(f, "address {}", a),
+        }
+    }
+}
+
+impl FromStr for Adaptor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(i) = s.parse::<usize>() {
+            return Ok(Adaptor::Index(i));
+        }
+
+        if let Ok(a) = s.parse::<Address>() {
+            return Ok(Adaptor::Address(a));
+        }
+
+        match s.is_empty() {
+            true => Err("Empty adaptor name".to_string()),
+            false => Ok(Adaptor::Name(s.to_string())),
+        }
+    }
+}
+
+/// Bluetooth device address
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Default)]
+pub struct Address(pub [u8; 6]);
+
+impl Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let a = &self.0;
+        write!(f, "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", a[0], a[1], a[2], a[3], a[4], a[5])
+    }
+}
+
+impl Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl <'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut a = [0u8; 6];
+        let mut parts = s.split(':');
+
+        for b in a.iter_mut() {
+            *b = parts.next()
+                .and_then(|p| u8::from_str_radix(p, 16).ok())
+                .ok_or_else(|| format!("Invalid address '{}'", s))?;
+        }
+
+        if parts.next().is_some() {
+            return Err(format!("Invalid address '{}'", s));
+        }
+
+        Ok(Address(a))
+    }
+}
+
+/// LE address type
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressType {
+    /// Public (IEEE assigned) address
+    Public,
+    /// Random (static or private) address
+    Random,
+}
+
+impl AddressType {
+    /// Fetch the alternate address type, used to retry failed connections
+    pub fn alternate(&self) -> Self {
+        match self {
+            AddressType::Public => AddressType::Random,
+            AddressType::Random => AddressType::Public,
+        }
+    }
+}
+
+impl Display for AddressType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressType::Public => write!(f, "public"),
+            AddressType::Random => write!(f, "random"),
+        }
+    }
+}
+
+/// Device found during a scan
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DiscoveredDevice {
+    /// Device address
+    pub address: Address,
+    /// Advertised local name
+    pub name: Option<String>,
+    /// Received signal strength (dBm), if known
+    pub rssi: Option<i16>,
+    /// LE address type, if reported by the backend
+    pub address_type: Option<AddressType>,
+    /// Advertised service UUIDs
+    pub service_uuids: Vec<Uuid>,
+    /// Advertised manufacturer specific data, by company identifier
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+/// Scan filter, passed to backend discovery where supported.
+///
+/// The btleplug backend passes service UUIDs to the platform (BlueZ discovery filters
+/// on linux), the minimum RSSI is left to device matching. The bluer backend is limited
+/// to bluer 0.13, which sets its own discovery filter, so all devices are discovered and
+/// the filter is applied in userspace.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ScanFilter {
+    /// Advertised service UUIDs, any of which must be present (empty for any device)
+    pub services: Vec<Uuid>,
+    /// Minimum signal strength (dBm)
+    pub min_rssi: Option<i16>,
+}
+
+impl ScanFilter {
+    /// Check whether a discovered device passes the filter
+    pub fn matches(&self, d: &DiscoveredDevice) -> bool {
+        let service = self.services.is_empty() || self.services.iter().any(|s| d.service_uuids.contains(s));
+        let rssi = match (self.min_rssi, d.rssi) {
+            (Some(min), Some(rssi)) => rssi >= min,
+            _ => true,
+        };
+
+        service && rssi
+    }
+}
+
+/// GATT service
+#[derive(Debug, PartialEq, Clone)]
+pub struct Service {
+    pub uuid: Uuid,
+    pub primary: bool,
+    pub characteristics: Vec<Characteristic>,
+}
+
+/// GATT characteristic
+#[derive(Debug, PartialEq, Clone)]
+pub struct Characteristic {
+    /// UUID of the service containing this characteristic
+    pub service: Uuid,
+    pub uuid: Uuid,
+    pub properties: Properties,
+}
+
+/// GATT characteristic properties
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Properties {
+    pub read: bool,
+    pub write: bool,
+    pub write_without_response: bool,
+    pub notify: bool,
+    pub indicate: bool,
+}
+
+impl Service {
+    /// Find a characteristic by UUID
+    pub fn characteristic(&self, uuid: Uuid) -> Option<&Characteristic> {
+        self.characteristics.iter().find(|c| c.uuid == uuid)
+    }
+}
+
+/// Find a characteristic by service and characteristic UUID
+pub fn find_characteristic(services: &[Service], service: Uuid, characteristic: Uuid) -> Option<&Characteristic> {
+    services.iter()
+        .filter(|s| s.uuid == service)
+        .find_map(|s| s.characteristic(characteristic))
+}